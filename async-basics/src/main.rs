@@ -1,14 +1,25 @@
-use minimio;
+mod minimio;
+
 use std::{
-    collections::{BTreeMap, HashMap},
+    any::Any,
+    cell::Cell,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt, fs,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
     sync::{
-        mpsc::{channel, Receiver, Sender},
-        Arc, Mutex,
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        OnceLock,
     },
     thread,
+    time::{Duration, Instant},
 };
 
-static mut RUNTIME: *mut Runtime = std::ptr::null_mut();
+thread_local! {
+    // the runtime running on this thread, so the javascript API can reach it
+    static RUNTIME: Cell<*mut Runtime> = const { Cell::new(std::ptr::null_mut()) };
+}
 
 pub struct Runtime {
     // available threads
@@ -16,21 +27,27 @@ pub struct Runtime {
     // callbacks scheduled to run
     callbacks_to_run: Vec<(usize, Js)>,
     // all registered callbacks
-    callback_queue: HashMap<usize, Box<dyn FnOnce()>>,
+    callback_queue: HashMap<usize, Box<dyn FnOnce(Js)>>,
+    // Resources to release in the close phase, and the callbacks to run once they are
+    closing: Vec<(Box<dyn Any>, usize)>,
     // number of pending poll events, only used to print
     epoll_pending_events: usize,
     // register for events of interet with the OS
     epoll_registrator: minimio::Registrator,
     // The handle to our epoll thread
     epoll_thread: thread::JoinHandle<()>,
-    // None = infinite, Some(n) = timeout in n ms, Some(0) = immediate
-    epoll_timeout: Arc<Mutex<Option<i32>>>,
     // Channel used by both our threadpool and our epoll thread to send events to the main loop
     event_reciever: Receiver<PollEvent>,
     // Creates an unique identity for our callbacks
     identity_token: usize,
+    // Callbacks waiting for the check phase
+    immediates: Vec<usize>,
+    // Callbacks to run as soon as the current one returns
+    next_ticks: VecDeque<usize>,
     // The number of events pending. When this is zero, we're done
     pending_events: usize,
+    // Tasks waiting for a thread when all of them are busy
+    queued_tasks: VecDeque<Task>,
     // Handles to our threads in the threadpool
     thread_pool: Vec<NodeThread>,
     // Holds all our timers, and an Id for the callback to run once they expire
@@ -47,78 +64,96 @@ impl Runtime {
 
         for i in 0..4 {
             let (evt_sender, evt_receiver) = channel::<Task>();
-            let event_sernder = event_sender.clone();
+            let event_sender = event_sender.clone();
 
             let handle = thread::Builder::new()
-            .name(format!("Pool{}", i))
-            .spawn(move || {
+                .name(format!("pool{}", i))
+                .spawn(move || {
+                    while let Ok(task) = evt_receiver.recv() {
+                        print(format!("received a task of type: {}", task.kind));
 
-                while Ok(task) = evt_receiver.recv() {
-                    print(format!("received a task of type: {}", task.kind));
+                        if let ThreadPoolTaskKind::Close = task.kind {
+                            break;
+                        };
 
-                    if let ThreadPoolTaskKind::Close == task.kind {
-                        break;
-                    };
+                        let res = (task.task)();
+                        print(format!("finished running a task of type: {}.", task.kind));
 
-                    let res = (task.task)();
-                    print(format!("finished running a task of type: {}.", task.kind));
+                        let event = PollEvent::Threadpool((i, task.callback_id, res));
+                        event_sender.send(event).expect("threadpool");
+                    }
+                })
+                .expect("Couldn't initialize thread pool.");
 
-                    let event = PollEvent::Threadpool((i, task.callback_id, res));
-                    event_sender.send(event).expect("threadpool");
-                }
-            })
-            .expect("Couldn't initialize thread pool.");
-            
             let node_thread = NodeThread {
                 handle,
                 sender: evt_sender,
-            }
+            };
             threads.push(node_thread);
         }
 
         // ===== EPOLL THREAD =====
         let mut poll = minimio::Poll::new().expect("Error creating epoll queue");
         let registrator = poll.registrator();
-        let epoll_timeout = Arc::new(Mutex::new(None));
-        let epoll_timeout_clone = epoll_timeout.clone();
 
+        // the epoll thread only waits for sockets, the timers are handled by the main loop
+        // since a poll that is already waiting can't pick up a new timeout
         let epoll_thread = thread::Builder::new()
-        .name("epoll".to_string())
-        .spawn(move || {
-            let mut events = minimio::Events::with_capacity(1024);
-
-            loop {
-                let epoll_timeout_handle = epoll_timeout_clone.lock().unwrap();
-                let timeout = *epoll_timeout_handle;
-                drop(epoll_timeout_handle);
-
-                match poll.poll(&mut events, timeout) {
-                    Ok(v) if v > 0 => {
-                        for i in 0..v {
-                            let event = events.get_mut(i).expect("No events in event list.");
-                            println!(format!("epoll event {} is ready", event.id().value()));
-
-                            let event = PollEvent::Epoll(event.id().value() as usize);
-                            event_sender.send(event).expect("epoll event");
+            .name("epoll".to_string())
+            .spawn(move || {
+                let mut events = minimio::Events::with_capacity(1024);
+
+                loop {
+                    match poll.poll(&mut events, None) {
+                        Ok(v) => {
+                            for i in 0..v {
+                                let event = events.get_mut(i).expect("No events in event list.");
+                                print(format!("epoll event {} is ready", event.id().value()));
+
+                                let event = PollEvent::Epoll(event.id().value());
+                                event_sender.send(event).expect("epoll event");
+                            }
                         }
-                    }
-                    Ok(v) if v == 0 {
-                        println!("epoll event timeout is ready");
-                        
+                        // close_loop was called
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                            print("received event of type: Close");
+                            break;
+                        }
+                        Err(e) => panic!("{:?}", e),
                     }
                 }
-            }
-        })
+            })
+            .expect("Couldn't initialize the epoll thread.");
+
+        Runtime {
+            available_threads: (0..4).collect(),
+            callbacks_to_run: vec![],
+            callback_queue: HashMap::new(),
+            closing: vec![],
+            epoll_pending_events: 0,
+            epoll_registrator: registrator,
+            epoll_thread,
+            event_reciever: event_receiver,
+            identity_token: 0,
+            immediates: vec![],
+            next_ticks: VecDeque::new(),
+            pending_events: 0,
+            queued_tasks: VecDeque::new(),
+            thread_pool: threads,
+            timers: BTreeMap::new(),
+            timers_to_remove: vec![],
+        }
     }
 
     // The run function on our Runtime will consume self so it's the last thing that we'll be able to call on this instance of our Runtime
     pub fn run(mut self, f: impl Fn()) {
         let rt_ptr: *mut Runtime = &mut self;
-        unsafe { RUNTIME = rt_ptr };
+        RUNTIME.with(|rt| rt.set(rt_ptr));
         let mut ticks = 0;
 
         // run the main function
         f();
+        self.run_next_ticks();
 
         // ====== Event Loop ======
         while self.pending_events > 0 {
@@ -134,7 +169,13 @@ impl Runtime {
             self.run_callbacks();
 
             // ====== 4. IDLE/PREPARE ======
-            // we won't use this
+            // with immediates or resources to close waiting, the poll mustn't block
+            // (libuv keeps an idle handle running for that)
+            let timeout = if self.immediates.is_empty() && self.closing.is_empty() {
+                self.get_next_timer()
+            } else {
+                Some(Duration::ZERO)
+            };
 
             // ====== 5. POLL ======
             // if we don't have any outstanding events then we are finished
@@ -142,38 +183,40 @@ impl Runtime {
                 break;
             }
 
-            // We want to get the time to the next timeout (if any) and we
-            // set the timeout of our epoll wait to the same as the timeout
-            // for the next timer. If there is none, we set it to infinite (None)
-            let next_timeout = self.get_next_timer();
-            let mut epoll_timeout_lock = self.epoll_timeout.lock().unwrap();
-            *epoll_timeout_lock = next_timeout;
-            drop(epoll_timeout_lock); // release the lock
+            // We wait for the next event, but no longer than until the next timer
+            // expires. If there is none, we wait for as long as it takes
+            // both threadpool threads and the epoll thread hold a sending part of the channel
+            let event = match timeout {
+                Some(timeout) => match self.event_reciever.recv_timeout(timeout) {
+                    Ok(event) => Some(event),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => panic!("event channel closed"),
+                },
+                None => Some(self.event_reciever.recv().expect("event channel closed")),
+            };
 
             // we handle one event but multiple events could be returned
             // on the same poll. We won't cover that here though but there are
             // several ways of handling this.
-            // both threadpool threads and the epoll thread hold a sending part of the channel
-            if let Ok(event) = self.event_reciever.recv() {
-                match event {
-                    PollEvent::Timeout => (),
-                    PollEvent::Threadpool(thread_id, callback_id, data) => {
-                        self.process_threadpool_events(thread_id, callback_id, data);
-                    }
-                    PollEvent::Epoll(event_id) => {
-                        self.process_epoll_events(event_id);
-                    }
+            match event {
+                // a timer expired, it's processed on the next tick
+                None => print("timeout is ready"),
+                Some(PollEvent::Threadpool((thread_id, callback_id, data))) => {
+                    self.process_threadpool_events(thread_id, callback_id, data);
+                }
+                Some(PollEvent::Epoll(event_id)) => {
+                    self.process_epoll_events(event_id);
                 }
             }
             self.run_callbacks();
 
             // ====== 6. CHECK ======
-            // an set immidiate function could be added pretty easily but we
-            // won't do that here
+            // run the immediates, the ones they set run on the next tick
+            self.run_immediates();
 
             // ====== 7. CLOSE CALLBACKS ======
-            // Release resources, we won't do that here, but this is typically
-            // where sockets etc are closed.
+            // release the resources that were closed and let their owners know
+            self.run_close_callbacks();
         }
 
         // clean up resources, make sure all destructors run
@@ -187,6 +230,193 @@ impl Runtime {
 
         self.epoll_registrator.close_loop().unwrap();
         self.epoll_thread.join().unwrap();
+        RUNTIME.with(|rt| rt.set(std::ptr::null_mut()));
+
+        print("FINISHED");
+    }
+
+    // moves the callbacks of the expired timers to the ones that are to run
+    fn process_expired_timers(&mut self) {
+        // Need an intermediate variable to please the borrowchecker
+        let timers_to_remove = &mut self.timers_to_remove;
+
+        self.timers
+            .range(..=Instant::now())
+            .for_each(|(k, _)| timers_to_remove.push(*k));
+
+        for key in self.timers_to_remove.drain(..) {
+            let callback_id = self.timers.remove(&key).unwrap();
+            self.callbacks_to_run.push((callback_id, Js::Undefined));
+        }
+    }
+
+    // the time left until the next timer expires, if there is one
+    fn get_next_timer(&self) -> Option<Duration> {
+        self.timers
+            .keys()
+            .next()
+            .map(|&instant| instant.saturating_duration_since(Instant::now()))
+    }
+
+    fn run_callbacks(&mut self) {
+        // the callbacks can register new events, but not new callbacks to run right away
+        let callbacks = std::mem::take(&mut self.callbacks_to_run);
+        for (callback_id, data) in callbacks {
+            self.run_callback(callback_id, data);
+        }
+    }
+
+    fn run_immediates(&mut self) {
+        let immediates = std::mem::take(&mut self.immediates);
+        for callback_id in immediates {
+            self.run_callback(callback_id, Js::Undefined);
+        }
+    }
+
+    fn run_close_callbacks(&mut self) {
+        let closing = std::mem::take(&mut self.closing);
+        for (resource, callback_id) in closing {
+            drop(resource);
+            self.run_callback(callback_id, Js::Undefined);
+        }
+    }
+
+    // every callback is followed by the next ticks it queued, before anything else runs
+    fn run_callback(&mut self, callback_id: usize, data: Js) {
+        let cb = self.callback_queue.remove(&callback_id).unwrap();
+        cb(data);
+        self.pending_events -= 1;
+        self.run_next_ticks();
+    }
+
+    // next ticks queued by a next tick run in the same go
+    fn run_next_ticks(&mut self) {
+        while let Some(callback_id) = self.next_ticks.pop_front() {
+            let cb = self.callback_queue.remove(&callback_id).unwrap();
+            cb(Js::Undefined);
+            self.pending_events -= 1;
+        }
+    }
+
+    fn process_epoll_events(&mut self, event_id: usize) {
+        self.callbacks_to_run.push((event_id, Js::Undefined));
+        self.epoll_pending_events -= 1;
+    }
+
+    fn process_threadpool_events(&mut self, thread_id: usize, callback_id: usize, data: Js) {
+        self.callbacks_to_run.push((callback_id, data));
+        // the thread is free again, hand it the next queued task if there is one
+        match self.queued_tasks.pop_front() {
+            Some(task) => self.send_task(thread_id, task),
+            None => self.available_threads.push(thread_id),
+        }
+    }
+
+    fn send_task(&self, thread_id: usize, task: Task) {
+        self.thread_pool[thread_id]
+            .sender
+            .send(task)
+            .expect("register work");
+    }
+
+    // the callback runs once the event with this token has been reported by epoll
+    fn register_event_epoll(&mut self, token: usize, cb: impl FnOnce(Js) + 'static) {
+        self.add_callback(token, cb);
+
+        print(format!("Event with id: {} registered.", token));
+        self.pending_events += 1;
+        self.epoll_pending_events += 1;
+    }
+
+    // the task runs on the threadpool, the callback gets what it returns
+    fn register_event_threadpool(
+        &mut self,
+        task: impl Fn() -> Js + Send + 'static,
+        kind: ThreadPoolTaskKind,
+        cb: impl FnOnce(Js) + 'static,
+    ) {
+        let callback_id = self.generate_cb_identity();
+        self.add_callback(callback_id, cb);
+
+        let event = Task {
+            task: Box::new(task),
+            callback_id,
+            kind,
+        };
+
+        // we are not going to implement a real scheduler here, just a LIFO queue
+        match self.available_threads.pop() {
+            Some(thread_id) => self.send_task(thread_id, event),
+            None => self.queued_tasks.push_back(event),
+        }
+        self.pending_events += 1;
+    }
+
+    // the callback runs once `ms` milliseconds have passed
+    fn set_timeout(&mut self, ms: u64, cb: impl FnOnce(Js) + 'static) {
+        let cb_id = self.generate_cb_identity();
+        self.add_callback(cb_id, cb);
+
+        // timers that expire at the same instant run in the order they were set
+        let mut timeout = Instant::now() + Duration::from_millis(ms);
+        while self.timers.contains_key(&timeout) {
+            timeout += Duration::from_nanos(1);
+        }
+        self.timers.insert(timeout, cb_id);
+
+        self.pending_events += 1;
+        print(format!("Registered timer event id: {}", cb_id));
+    }
+
+    // the callback runs in the check phase, right after the poll
+    fn set_immediate(&mut self, cb: impl FnOnce(Js) + 'static) {
+        let cb_id = self.generate_cb_identity();
+        self.add_callback(cb_id, cb);
+        self.immediates.push(cb_id);
+        self.pending_events += 1;
+    }
+
+    // the callback runs as soon as the one running now returns
+    fn next_tick(&mut self, cb: impl FnOnce(Js) + 'static) {
+        let cb_id = self.generate_cb_identity();
+        self.add_callback(cb_id, cb);
+        self.next_ticks.push_back(cb_id);
+        self.pending_events += 1;
+    }
+
+    // the resource is dropped in the close phase, then the callback runs
+    fn close(&mut self, resource: impl Any, cb: impl FnOnce(Js) + 'static) {
+        let cb_id = self.generate_cb_identity();
+        self.add_callback(cb_id, cb);
+        self.closing.push((Box::new(resource), cb_id));
+        self.pending_events += 1;
+    }
+
+    fn generate_identity(&mut self) -> usize {
+        self.identity_token = self.identity_token.wrapping_add(1);
+        self.identity_token
+    }
+
+    // an identity that isn't used by any of the callbacks in the queue
+    fn generate_cb_identity(&mut self) -> usize {
+        loop {
+            let ident = self.generate_identity();
+            if !self.callback_queue.contains_key(&ident) {
+                break ident;
+            }
+        }
+    }
+
+    // Adds a callback to the queue
+    fn add_callback(&mut self, ident: usize, cb: impl FnOnce(Js) + 'static) {
+        let boxed_cb = Box::new(cb);
+        self.callback_queue.insert(ident, boxed_cb);
+    }
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Runtime::new()
     }
 }
 
@@ -223,6 +453,17 @@ pub enum ThreadPoolTaskKind {
     Close,
 }
 
+impl fmt::Display for ThreadPoolTaskKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ThreadPoolTaskKind::*;
+        match self {
+            FileRead => write!(f, "File read"),
+            Encrypt => write!(f, "Encrypt"),
+            Close => write!(f, "Close"),
+        }
+    }
+}
+
 // The Js object is simply there to make our code look more JavaScripty
 // and to abstract over the return type of closures
 #[derive(Debug)]
@@ -230,6 +471,8 @@ pub enum Js {
     Undefined,
     String(String),
     Int(usize),
+    // the work failed, what node passes as the callback's `err`
+    Error(String),
 }
 
 impl Js {
@@ -250,7 +493,7 @@ impl Js {
 }
 
 // events that we can accept from the threadpool and the event queue
-// describes the three main events our epoll-eventloop handles
+// describes the two main events our epoll-eventloop handles
 enum PollEvent {
     // An event from the `threadpool` with a tuple containing the `thread id`,
     // the `callback_id` and the data which the we expect to process in our
@@ -259,9 +502,394 @@ enum PollEvent {
     // An event from the epoll-based eventloop holding the `event_id` for the
     // event
     Epoll(usize),
-    Timeout,
+}
+
+// the runtime the javascript code is running on
+fn rt() -> &'static mut Runtime {
+    let rt = RUNTIME.with(|rt| rt.get());
+    assert!(!rt.is_null(), "no runtime running on this thread");
+    unsafe { &mut *rt }
+}
+
+// prints which thread it's printed from
+fn print(t: impl fmt::Display) {
+    println!("Thread: {}\t {}", current(), t);
+}
+
+// prints the first lines of a longer text
+fn print_content(t: impl fmt::Display, descr: &str) {
+    println!(
+        "\n===== THREAD {} START CONTENT - {} =====",
+        current(),
+        descr.to_uppercase()
+    );
+
+    let content = format!("{}", t);
+    let lines = content.lines().take(2);
+    let main_cont: String = lines.map(|l| format!("{}\n", l)).collect();
+    println!("{}... [Note: Abbreviated for display] ...", main_cont);
+
+    println!("===== END CONTENT =====\n");
+}
+
+fn current() -> String {
+    thread::current().name().unwrap_or("unnamed").to_string()
+}
+
+// a local server that answers `GET /delay/<ms>/url/<url>` after <ms> milliseconds
+fn test_server() -> SocketAddr {
+    static ADDR: OnceLock<SocketAddr> = OnceLock::new();
+    *ADDR.get_or_init(|| {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("binding the test server");
+        let addr = listener.local_addr().unwrap();
+        thread::Builder::new()
+            .name("server".to_string())
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    thread::spawn(move || serve_slowly(stream));
+                }
+            })
+            .expect("Couldn't start the test server.");
+        addr
+    })
+}
+
+fn serve_slowly(mut stream: std::net::TcpStream) {
+    let mut request = Vec::new();
+    let mut buffer = [0_u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&buffer[..n]),
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+    let mut parts = path.trim_start_matches('/').splitn(4, '/');
+    let (delay, url) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("delay"), Some(ms), Some("url"), Some(url)) => (ms.parse().unwrap_or(0), url),
+        _ => (0, path),
+    };
+    thread::sleep(Duration::from_millis(delay));
+
+    let body = format!("Hello from {} after {}ms\n", url, delay);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
+
+// ===== THE JAVASCRIPT API =====
+// each of these registers its callback with the runtime, which runs it once the work is done
+
+// runs the callback once `ms` milliseconds have passed
+fn set_timeout(ms: u64, cb: impl FnOnce(Js) + 'static) {
+    rt().set_timeout(ms, cb);
+}
+
+// runs the callback once the loop has polled for events
+fn set_immediate(cb: impl FnOnce(Js) + 'static) {
+    rt().set_immediate(cb);
+}
+
+// runs the callback as soon as the running code is done, before the loop goes on
+fn next_tick(cb: impl FnOnce(Js) + 'static) {
+    rt().next_tick(cb);
+}
+
+struct Fs;
+
+impl Fs {
+    // reads the whole file on the threadpool, the callback gets its text (or the error)
+    fn read(path: impl Into<PathBuf>, cb: impl FnOnce(Js) + 'static) {
+        let path = path.into();
+        let work = move || {
+            // Let's simulate that there is a very large file we're reading allowing us to actually
+            // observe how the code is executed
+            thread::sleep(Duration::from_secs(1));
+            // a panic here would take the worker down and leave the loop waiting for it
+            match fs::read_to_string(&path) {
+                Ok(text) => Js::String(text),
+                Err(e) => Js::Error(format!("reading {}: {}", path.display(), e)),
+            }
+        };
+        rt().register_event_threadpool(work, ThreadPoolTaskKind::FileRead, cb);
+    }
+}
+
+struct Crypto;
+
+impl Crypto {
+    // a "magic" number worked out on the threadpool, slow on purpose
+    fn encrypt(n: usize, cb: impl FnOnce(Js) + 'static) {
+        let work = move || {
+            fn fibonacci(n: usize) -> usize {
+                match n {
+                    0 => 0,
+                    1 => 1,
+                    _ => fibonacci(n - 1).wrapping_add(fibonacci(n - 2)),
+                }
+            }
+            Js::Int(fibonacci(n))
+        };
+        rt().register_event_threadpool(work, ThreadPoolTaskKind::Encrypt, cb);
+    }
+}
+
+struct Http;
+
+impl Http {
+    // a GET that the local test server answers after `delay_ms`, the callback gets the response
+    fn http_get_slow(url: &str, delay_ms: u32, cb: impl FnOnce(Js) + 'static) {
+        let rt = rt();
+        let mut stream = minimio::TcpStream::connect(test_server()).expect("connecting");
+        let request = format!(
+            "GET /delay/{}/url/http://{} HTTP/1.1\r\n\
+             Host: localhost\r\n\
+             Connection: close\r\n\
+             \r\n",
+            delay_ms, url
+        );
+        stream
+            .write_all(request.as_bytes())
+            .expect("Error writing to stream");
+
+        let token = rt.generate_cb_identity();
+        rt.epoll_registrator
+            .register(&mut stream, token, minimio::Interests::READABLE)
+            .unwrap();
+
+        // the socket is readable, the server closes it once the whole response is sent
+        let wrapped = move |_| {
+            let mut buffer = String::new();
+            stream
+                .read_to_string(&mut buffer)
+                .expect("Stream read error");
+            crate::rt().close(stream, move |_| {
+                print(format!("Connection for event {} closed", token));
+            });
+            cb(Js::String(buffer));
+        };
+        rt.register_event_epoll(token, wrapped);
+    }
+}
+
+// Think of this function as the javascript program you have written
+fn javascript() {
+    let test_file = concat!(env!("CARGO_MANIFEST_DIR"), "/test.txt");
+
+    print("First call to read test.txt");
+    Fs::read(test_file, |result| {
+        let text = result.into_string().unwrap();
+        let len = text.len();
+        print(format!("First count: {} characters.", len));
+
+        print(r#"I want to create a "magic" number based on the text."#);
+        Crypto::encrypt(text.len(), |result| {
+            let n = result.into_int().unwrap();
+            print(format!(r#""Encrypted" number is: {}"#, n));
+        })
+    });
+
+    print("Registering immediate timeout 1");
+    set_timeout(0, |_res| {
+        print("Immediate1 timed out");
+    });
+    print("Registering immediate timeout 2");
+    set_timeout(0, |_res| {
+        print("Immediate2 timed out");
+    });
+
+    // let's read the file again and display the text
+    print("Second call to read test.txt");
+    Fs::read(test_file, move |result| {
+        let text = result.into_string().unwrap();
+        let len = text.len();
+        print(format!("Second count: {} characters.", len));
+
+        // aaand one more time but not in parallel.
+        print("Third call to read test.txt");
+        Fs::read(test_file, |result| {
+            let text = result.into_string().unwrap();
+            print_content(&text, "file read");
+        });
+    });
+
+    print("Registering a 3000 and a 500 ms timeout");
+    set_timeout(3000, |_res| {
+        print("3000ms timer timed out");
+        set_timeout(500, |_res| {
+            print("500ms timer(nested) timed out");
+        });
+    });
+
+    print("Registering a 1000 ms timeout");
+    set_timeout(1000, |_res| {
+        print("SETTIMEOUT");
+    });
+
+    print("Registering an immediate and a next tick");
+    set_immediate(|_res| {
+        print("Immediate ran");
+    });
+    next_tick(|_res| {
+        print("Next tick ran");
+    });
+
+    // `http_get_slow` lets us define a latency we want to simulate
+    print("Registering http get request to google.com");
+    Http::http_get_slow("www.google.com", 2000, |result| {
+        let result = result.into_string().unwrap();
+        print_content(result.trim(), "web call");
+    });
 }
 
 fn main() {
-    println!("Hello, world!");
+    let rt = Runtime::new();
+    rt.run(javascript);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    // what the callbacks ran, in order
+    #[derive(Clone, Default)]
+    struct Log(Rc<RefCell<Vec<&'static str>>>);
+
+    impl Log {
+        fn push(&self, entry: &'static str) {
+            self.0.borrow_mut().push(entry);
+        }
+
+        // a callback that only logs
+        fn cb(&self, entry: &'static str) -> impl FnOnce(Js) + 'static {
+            let log = self.clone();
+            move |_| log.push(entry)
+        }
+    }
+
+    fn run_logged(program: impl Fn(&Log)) -> Vec<&'static str> {
+        let log = Log::default();
+        Runtime::new().run(|| program(&log));
+        let entries = log.0.borrow().clone();
+        entries
+    }
+
+    #[test]
+    fn next_ticks_run_before_the_loop() {
+        let order = run_logged(|log| {
+            set_timeout(0, log.cb("timeout"));
+            set_immediate(log.cb("immediate"));
+            let inner = log.clone();
+            next_tick(move |_| {
+                inner.push("tick 1");
+                next_tick(inner.cb("nested tick"));
+            });
+            next_tick(log.cb("tick 2"));
+            log.push("main");
+        });
+        // node runs either the timer or the immediate first here, depending on the clock,
+        // a 0ms timer set by the main script has always expired by our first tick
+        assert_eq!(
+            order,
+            [
+                "main",
+                "tick 1",
+                "tick 2",
+                "nested tick",
+                "timeout",
+                "immediate"
+            ]
+        );
+    }
+
+    #[test]
+    fn immediates_run_before_timers_after_io() {
+        let order = run_logged(|log| {
+            let log = log.clone();
+            Crypto::encrypt(10, move |_| {
+                set_timeout(0, log.cb("timeout"));
+                set_immediate(log.cb("immediate"));
+                next_tick(log.cb("tick"));
+                log.push("io");
+            });
+        });
+        assert_eq!(order, ["io", "tick", "immediate", "timeout"]);
+    }
+
+    #[test]
+    fn next_ticks_run_between_callbacks_of_a_phase() {
+        let order = run_logged(|log| {
+            let first = log.clone();
+            set_timeout(0, move |_| {
+                first.push("timeout 1");
+                next_tick(first.cb("tick"));
+            });
+            set_timeout(0, log.cb("timeout 2"));
+        });
+        assert_eq!(order, ["timeout 1", "tick", "timeout 2"]);
+    }
+
+    #[test]
+    fn immediates_set_by_immediates_wait_for_the_next_tick() {
+        let order = run_logged(|log| {
+            let first = log.clone();
+            Crypto::encrypt(10, move |_| {
+                let inner = first.clone();
+                set_immediate(move |_| {
+                    inner.push("immediate 1");
+                    set_immediate(inner.cb("immediate 3"));
+                    rt().close((), inner.cb("close"));
+                });
+                set_immediate(first.cb("immediate 2"));
+            });
+        });
+        assert_eq!(
+            order,
+            ["immediate 1", "immediate 2", "close", "immediate 3"]
+        );
+    }
+
+    #[test]
+    fn close_callbacks_run_after_the_resource_is_dropped() {
+        struct Resource(Log);
+
+        impl Drop for Resource {
+            fn drop(&mut self) {
+                self.0.push("dropped");
+            }
+        }
+
+        let order = run_logged(|log| {
+            let log = log.clone();
+            Crypto::encrypt(10, move |_| {
+                set_timeout(0, log.cb("timeout"));
+                rt().close(Resource(log.clone()), log.cb("close"));
+                set_immediate(log.cb("immediate"));
+            });
+        });
+        assert_eq!(order, ["immediate", "dropped", "close", "timeout"]);
+    }
+
+    #[test]
+    fn read_errors_go_to_the_callback() {
+        let result = Rc::new(RefCell::new(None));
+        let got = Rc::clone(&result);
+        Runtime::new().run(move || {
+            let got = Rc::clone(&got);
+            Fs::read("/nonexistent/test.txt", move |js| {
+                *got.borrow_mut() = Some(js)
+            });
+        });
+        let result = result.borrow_mut().take();
+        match result {
+            Some(Js::Error(e)) => assert!(e.starts_with("reading /nonexistent/test.txt"), "{}", e),
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,331 @@
+// A small epoll based reactor, in place of the minimio example crate and with the same interface
+// `Poll` waits for events on the epoll thread, the `Registrator` is used from the main thread to
+// register interest in a socket and to wake the poll up for good once the runtime is done.
+
+use std::{
+    io::{self, Read, Write},
+    net::{self, ToSocketAddrs},
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+// the token of the eventfd that wakes the poll up, never handed out to the user
+const WAKE_TOKEN: u64 = u64::MAX;
+
+pub type Events = Vec<Event>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token(usize);
+
+impl Token {
+    pub fn value(&self) -> usize {
+        self.0
+    }
+}
+
+// what's ready is always the socket registered with the token, so that's all we keep
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    token: u64,
+}
+
+impl Event {
+    pub fn id(&self) -> Token {
+        Token(self.token as usize)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interests(u32);
+
+impl Interests {
+    pub const READABLE: Interests = Interests(libc::EPOLLIN as u32);
+}
+
+// the epoll and eventfd descriptors, closed when the last of Poll and its Registrators is gone
+#[derive(Debug)]
+struct Selector {
+    epoll: RawFd,
+    wake: RawFd,
+}
+
+impl Drop for Selector {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.wake);
+            libc::close(self.epoll);
+        }
+    }
+}
+
+fn check(res: libc::c_int) -> io::Result<libc::c_int> {
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+fn ctl(epoll: RawFd, op: libc::c_int, fd: RawFd, flags: u32, token: u64) -> io::Result<()> {
+    let mut event = libc::epoll_event {
+        events: flags,
+        u64: token,
+    };
+    check(unsafe { libc::epoll_ctl(epoll, op, fd, &mut event) })?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct Poll {
+    selector: Arc<Selector>,
+    is_poll_dead: Arc<AtomicBool>,
+    // reused for every call to epoll_wait
+    buffer: Vec<libc::epoll_event>,
+}
+
+impl Poll {
+    pub fn new() -> io::Result<Poll> {
+        let epoll = check(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        let wake = match check(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })
+        {
+            Ok(fd) => fd,
+            Err(e) => {
+                unsafe { libc::close(epoll) };
+                return Err(e);
+            }
+        };
+        let selector = Arc::new(Selector { epoll, wake });
+        ctl(
+            epoll,
+            libc::EPOLL_CTL_ADD,
+            wake,
+            libc::EPOLLIN as u32,
+            WAKE_TOKEN,
+        )?;
+
+        Ok(Poll {
+            selector,
+            is_poll_dead: Arc::new(AtomicBool::new(false)),
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn registrator(&self) -> Registrator {
+        Registrator {
+            selector: self.selector.clone(),
+            is_poll_dead: self.is_poll_dead.clone(),
+        }
+    }
+
+    // waits for at most `timeout_ms` (forever with None) and returns the number of events,
+    // an `Interrupted` error once the loop has been closed
+    pub fn poll(&mut self, events: &mut Events, timeout_ms: Option<i32>) -> io::Result<usize> {
+        events.clear();
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(closed());
+        }
+
+        let capacity = events.capacity().max(1);
+        self.buffer.clear();
+        self.buffer.reserve(capacity);
+        let n = loop {
+            let res = unsafe {
+                libc::epoll_wait(
+                    self.selector.epoll,
+                    self.buffer.as_mut_ptr(),
+                    capacity as libc::c_int,
+                    timeout_ms.unwrap_or(-1),
+                )
+            };
+            match check(res) {
+                Ok(n) => break n as usize,
+                // a signal, not a close_loop
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        // epoll_wait initialized the first n entries
+        unsafe { self.buffer.set_len(n) };
+
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(closed());
+        }
+        events.extend(
+            self.buffer
+                .iter()
+                .map(|e| e.u64)
+                .filter(|&token| token != WAKE_TOKEN)
+                .map(|token| Event { token }),
+        );
+        Ok(events.len())
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "poll closed")
+}
+
+#[derive(Debug, Clone)]
+pub struct Registrator {
+    selector: Arc<Selector>,
+    is_poll_dead: Arc<AtomicBool>,
+}
+
+impl Registrator {
+    // one event for the socket, it has to be registered again for the next one
+    pub fn register(
+        &self,
+        stream: &mut TcpStream,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::other("poll instance closed"));
+        }
+        assert!(token as u64 != WAKE_TOKEN, "token is reserved");
+        let flags = interests.0 | libc::EPOLLONESHOT as u32;
+        ctl(
+            self.selector.epoll,
+            libc::EPOLL_CTL_ADD,
+            stream.as_raw_fd(),
+            flags,
+            token as u64,
+        )
+        .or_else(|e| match e.raw_os_error() {
+            // registered before, the one shot has been used up
+            Some(libc::EEXIST) => ctl(
+                self.selector.epoll,
+                libc::EPOLL_CTL_MOD,
+                stream.as_raw_fd(),
+                flags,
+                token as u64,
+            ),
+            _ => Err(e),
+        })
+    }
+
+    // makes the poll return an `Interrupted` error, now and on every later call
+    pub fn close_loop(&self) -> io::Result<()> {
+        if self.is_poll_dead.swap(true, Ordering::SeqCst) {
+            return Err(io::Error::other("poll instance already closed"));
+        }
+        let one = 1_u64.to_ne_bytes();
+        check(unsafe {
+            libc::write(self.selector.wake, one.as_ptr() as *const libc::c_void, 8) as libc::c_int
+        })?;
+        Ok(())
+    }
+}
+
+// a blocking TCP stream that can be registered with the poll
+#[derive(Debug)]
+pub struct TcpStream {
+    inner: net::TcpStream,
+}
+
+impl TcpStream {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<TcpStream> {
+        let inner = net::TcpStream::connect(addr)?;
+        Ok(TcpStream { inner })
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread, time::Duration, time::Instant};
+
+    // a connected stream and the server's end of it
+    fn connected() -> (TcpStream, net::TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (stream, server)
+    }
+
+    #[test]
+    fn timeout_without_events() {
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(8);
+        let start = Instant::now();
+        assert_eq!(poll.poll(&mut events, Some(20)).unwrap(), 0);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn readable_socket_is_reported_once() {
+        let mut poll = Poll::new().unwrap();
+        let registrator = poll.registrator();
+        let mut events = Events::with_capacity(8);
+        let (mut stream, mut server) = connected();
+
+        registrator
+            .register(&mut stream, 7, Interests::READABLE)
+            .unwrap();
+        assert_eq!(poll.poll(&mut events, Some(20)).unwrap(), 0);
+
+        server.write_all(b"ready").unwrap();
+        assert_eq!(poll.poll(&mut events, Some(1000)).unwrap(), 1);
+        assert_eq!(events[0].id(), Token(7));
+
+        // one shot, until it's registered again
+        assert_eq!(poll.poll(&mut events, Some(20)).unwrap(), 0);
+        registrator
+            .register(&mut stream, 8, Interests::READABLE)
+            .unwrap();
+        assert_eq!(poll.poll(&mut events, Some(1000)).unwrap(), 1);
+        assert_eq!(events[0].id().value(), 8);
+
+        let mut buffer = [0_u8; 5];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ready");
+    }
+
+    #[test]
+    fn close_loop_wakes_the_poll() {
+        let mut poll = Poll::new().unwrap();
+        let registrator = poll.registrator();
+        let waiting = thread::spawn(move || {
+            let mut events = Events::with_capacity(8);
+            poll.poll(&mut events, None)
+        });
+
+        thread::sleep(Duration::from_millis(20));
+        registrator.close_loop().unwrap();
+        let err = waiting.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+
+        // nothing more to do with a closed poll
+        assert!(registrator.close_loop().is_err());
+        let (mut stream, _server) = connected();
+        assert!(registrator
+            .register(&mut stream, 1, Interests::READABLE)
+            .is_err());
+    }
+}
@@ -0,0 +1,478 @@
+// A small wrk-like load generator built on the same mio primitives as the server
+// cargo run --release -p mio-http --bin mio-http-bench -- --connections 128 --rate 50000 --duration 10
+//
+// Every connection is keep-alive and has at most one request in flight.
+// With a target rate, requests are scheduled on a fixed timetable and latency is measured from
+// the time a request *should* have been sent, so a stalled server can't hide its queueing delay
+// by slowing the client down (the "coordinated omission" problem). Without a rate every connection
+// sends its next request as soon as the previous response arrives.
+
+use mio::{net::TcpStream, Events, Interest, Poll, Token};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    process,
+    time::{Duration, Instant},
+};
+
+struct Options {
+    addr: SocketAddr,
+    path: String,
+    connections: usize,
+    rate: u64, // requests per second across all connections, 0 = as fast as possible
+    duration: Duration,
+}
+
+impl Options {
+    fn from_args() -> Options {
+        let mut options = Options {
+            addr: "127.0.0.1:8080".parse().unwrap(),
+            path: "/".to_string(),
+            connections: 64,
+            rate: 0,
+            duration: Duration::from_secs(10),
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let value = args.next().unwrap_or_else(|| usage(&arg));
+            match arg.as_str() {
+                "--addr" => options.addr = value.parse().unwrap_or_else(|_| usage(&arg)),
+                "--path" => options.path = value,
+                "--connections" => {
+                    options.connections = value.parse().unwrap_or_else(|_| usage(&arg))
+                }
+                "--rate" => options.rate = value.parse().unwrap_or_else(|_| usage(&arg)),
+                "--duration" => {
+                    options.duration =
+                        Duration::from_secs(value.parse().unwrap_or_else(|_| usage(&arg)))
+                }
+                _ => usage(&arg),
+            }
+        }
+        if options.connections == 0 {
+            usage("--connections");
+        }
+        options
+    }
+}
+
+fn usage(arg: &str) -> ! {
+    eprintln!("unexpected argument: {}", arg);
+    eprintln!(
+        "usage: mio-http-bench [--addr ADDR] [--path PATH] [--connections N] [--rate REQ_PER_SEC] [--duration SECS]"
+    );
+    process::exit(2);
+}
+
+struct Client {
+    socket: TcpStream,
+    connected: bool,
+    // request bytes not yet written
+    out: Vec<u8>,
+    // response bytes read so far
+    buf: Vec<u8>,
+    // when the request in flight was due, None when idle
+    started: Option<Instant>,
+}
+
+impl Client {
+    fn send(&mut self, request: &[u8], due: Instant) {
+        self.out.extend_from_slice(request);
+        self.started = Some(due);
+    }
+
+    // write as much of the pending request as the socket takes
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.out.is_empty() && self.connected {
+            match self.socket.write(&self.out) {
+                Ok(n) => {
+                    self.out.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // read until the socket would block, Err once the server hangs up
+    fn fill(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        loop {
+            match self.socket.read(buffer) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.buf.extend_from_slice(&buffer[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// Length of the first complete response in the buffer, with its status code
+// The canned mio-http response uses bare \n line endings, so both are accepted.
+// Bodies are framed by Content-Length or chunked, an Err means the framing is broken.
+fn response(buf: &[u8]) -> io::Result<Option<(usize, u16)>> {
+    let (head_end, sep) = match find(buf, b"\r\n\r\n") {
+        Some(pos) => (pos, 4),
+        None => match find(buf, b"\n\n") {
+            Some(pos) => (pos, 2),
+            None => return Ok(None),
+        },
+    };
+    let head = String::from_utf8_lossy(&buf[..head_end]);
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid("no status line"))?;
+
+    let mut content_length = 0;
+    let mut chunked = false;
+    for line in lines {
+        let colon = match line.find(':') {
+            Some(colon) => colon,
+            None => continue,
+        };
+        let (name, value) = (line[..colon].trim(), line[colon + 1..].trim());
+        if name.eq_ignore_ascii_case("Content-Length") {
+            content_length = value
+                .parse::<usize>()
+                .map_err(|_| invalid("bad Content-Length"))?;
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            chunked = value
+                .rsplit(',')
+                .next()
+                .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        }
+    }
+
+    let start = head_end + sep;
+    if chunked {
+        return Ok(chunked_len(&buf[start..])?.map(|body| (start + body, status)));
+    }
+    let len = start + content_length;
+    if buf.len() >= len {
+        Ok(Some((len, status)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+// Length of a complete chunked body (up to and including the last chunk and its trailers),
+// None while more of it is still to come
+fn chunked_len(body: &[u8]) -> io::Result<Option<usize>> {
+    let mut pos = 0;
+    loop {
+        let line = match find(&body[pos..], b"\r\n") {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        // chunk extensions after a ';' are ignored
+        let size = std::str::from_utf8(&body[pos..pos + line])
+            .ok()
+            .and_then(|line| line.split(';').next())
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .ok_or_else(|| invalid("bad chunk size"))?;
+        pos += line + 2;
+
+        if size == 0 {
+            // trailer fields, up to an empty line
+            loop {
+                match find(&body[pos..], b"\r\n") {
+                    Some(0) => return Ok(Some(pos + 2)),
+                    Some(line) => pos += line + 2,
+                    None => return Ok(None),
+                }
+            }
+        }
+
+        let end = pos
+            .checked_add(size)
+            .and_then(|end| end.checked_add(2))
+            .ok_or_else(|| invalid("bad chunk size"))?;
+        if body.len() < end {
+            return Ok(None);
+        }
+        if &body[end - 2..end] != b"\r\n" {
+            return Err(invalid("chunk not terminated"));
+        }
+        pos = end;
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[derive(Default)]
+struct Stats {
+    latencies: Vec<u64>, // microseconds
+    non_2xx: u64,
+    errors: u64,
+    bytes: u64,
+}
+
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn fmt_us(us: u64) -> String {
+    if us >= 1_000_000 {
+        format!("{:.2}s", us as f64 / 1_000_000.0)
+    } else if us >= 1_000 {
+        format!("{:.2}ms", us as f64 / 1_000.0)
+    } else {
+        format!("{}us", us)
+    }
+}
+
+fn main() {
+    let options = Options::from_args();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\n\r\n",
+        options.path, options.addr
+    )
+    .into_bytes();
+
+    let mut poll = Poll::new().unwrap();
+    let mut clients = Vec::with_capacity(options.connections);
+    for i in 0..options.connections {
+        let mut socket = TcpStream::connect(options.addr).unwrap();
+        poll.registry()
+            .register(
+                &mut socket,
+                Token(i),
+                Interest::READABLE | Interest::WRITABLE,
+            )
+            .unwrap();
+        clients.push(Client {
+            socket,
+            connected: false,
+            out: Vec::new(),
+            buf: Vec::new(),
+            started: None,
+        });
+    }
+
+    // connections without a request in flight
+    let mut idle: VecDeque<usize> = VecDeque::new();
+    let interval = match options.rate {
+        0 => None,
+        rate => Some(Duration::from_nanos(1_000_000_000 / rate)),
+    };
+
+    let mut stats = Stats::default();
+    let mut buffer = [0_u8; 16 * 1024];
+    let mut events = Events::with_capacity(1024);
+
+    // Connect everything before the clock starts, so connection setup doesn't count as latency
+    // The first writable event tells us how each non-blocking connect went.
+    let mut connecting = options.connections;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while connecting > 0 && Instant::now() < deadline {
+        poll.poll(&mut events, Some(Duration::from_millis(100)))
+            .unwrap();
+        for event in &events {
+            let i = event.token().0;
+            let client = &mut clients[i];
+            if client.connected || !event.is_writable() {
+                continue;
+            }
+            connecting -= 1;
+            match client.socket.take_error() {
+                Ok(None) if client.socket.peer_addr().is_ok() => {
+                    client.connected = true;
+                    idle.push_back(i);
+                }
+                _ => {
+                    eprintln!("connection {} failed to connect", i);
+                    stats.errors += 1;
+                }
+            }
+        }
+    }
+    if idle.is_empty() {
+        eprintln!("could not connect to {}", options.addr);
+        process::exit(1);
+    }
+
+    let start = Instant::now();
+    let end = start + options.duration;
+    let mut next_due = start;
+
+    println!(
+        "Running {:?} test @ http://{}{}",
+        options.duration, options.addr, options.path
+    );
+    println!(
+        "  {} connections, {}",
+        options.connections,
+        match options.rate {
+            0 => "unthrottled".to_string(),
+            rate => format!("{} requests/sec", rate),
+        }
+    );
+
+    loop {
+        let now = Instant::now();
+        if now >= end {
+            break;
+        }
+
+        // hand the requests that are due to idle connections
+        match interval {
+            Some(interval) => {
+                while next_due <= now {
+                    let i = match idle.pop_front() {
+                        Some(i) => i,
+                        // every connection is busy, the request stays due and its latency keeps growing
+                        None => break,
+                    };
+                    clients[i].send(&request, next_due);
+                    if clients[i].flush().is_err() {
+                        stats.errors += 1;
+                    }
+                    next_due += interval;
+                }
+            }
+            None => {
+                while let Some(i) = idle.pop_front() {
+                    clients[i].send(&request, now);
+                    if clients[i].flush().is_err() {
+                        stats.errors += 1;
+                    }
+                }
+            }
+        }
+
+        let timeout = match interval {
+            Some(_) if !idle.is_empty() => next_due.saturating_duration_since(now),
+            _ => end - now,
+        };
+        poll.poll(&mut events, Some(timeout.min(end - now)))
+            .unwrap();
+
+        for event in &events {
+            let i = event.token().0;
+            let client = &mut clients[i];
+
+            if !client.connected {
+                continue;
+            }
+            if event.is_writable() && client.flush().is_err() {
+                stats.errors += 1;
+            }
+
+            if event.is_readable() {
+                let response = client.fill(&mut buffer).and_then(|_| response(&client.buf));
+                let (len, status) = match response {
+                    Ok(Some(response)) => response,
+                    Ok(None) => continue,
+                    // the server closed the connection (or broke the framing), it won't be used again
+                    Err(_) => {
+                        stats.errors += 1;
+                        client.started = None;
+                        client.connected = false;
+                        idle.retain(|&c| c != i);
+                        continue;
+                    }
+                };
+
+                let now = Instant::now();
+                if let Some(started) = client.started.take() {
+                    stats.latencies.push((now - started).as_micros() as u64);
+                }
+                if !(200..300).contains(&status) {
+                    stats.non_2xx += 1;
+                }
+                stats.bytes += len as u64;
+                client.buf.drain(..len);
+                idle.push_back(i);
+            }
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let mut latencies = stats.latencies;
+    latencies.sort_unstable();
+    let count = latencies.len();
+    let mean = if count > 0 {
+        latencies.iter().sum::<u64>() / count as u64
+    } else {
+        0
+    };
+
+    println!("  Latency");
+    println!("    mean   {:>10}", fmt_us(mean));
+    for &(name, p) in &[
+        ("p50", 50.0),
+        ("p90", 90.0),
+        ("p99", 99.0),
+        ("p999", 99.9),
+        ("max", 100.0),
+    ] {
+        println!("    {:<6} {:>10}", name, fmt_us(percentile(&latencies, p)));
+    }
+    println!(
+        "  {} requests in {:.2}s, {:.2}MB read",
+        count,
+        elapsed,
+        stats.bytes as f64 / (1024.0 * 1024.0)
+    );
+    if stats.non_2xx > 0 {
+        println!("  Non-2xx responses: {}", stats.non_2xx);
+    }
+    if stats.errors > 0 {
+        println!("  Socket errors: {}", stats.errors);
+    }
+    println!("Requests/sec: {:.2}", count as f64 / elapsed);
+    println!(
+        "Transfer/sec: {:.2}MB",
+        stats.bytes as f64 / (1024.0 * 1024.0) / elapsed
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_length_responses() {
+        let res = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloHTTP/1.1";
+        assert_eq!(response(res).unwrap(), Some((43, 200)));
+        let canned = b"HTTP/1.1 404 Not Found\nContent-Length: 2\n\nhi";
+        assert_eq!(response(canned).unwrap(), Some((44, 404)));
+        let partial = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhel";
+        assert_eq!(response(partial).unwrap(), None);
+    }
+
+    #[test]
+    fn chunked_bodies() {
+        assert_eq!(chunked_len(b"5\r\nhello\r\n0\r\n\r\n").unwrap(), Some(15));
+        assert_eq!(
+            chunked_len(b"3;ext=1\r\nabc\r\n0\r\nTrailer: x\r\n\r\nnext").unwrap(),
+            Some(31)
+        );
+        assert_eq!(chunked_len(b"5\r\nhel").unwrap(), None);
+        assert_eq!(chunked_len(b"5\r\nhello\r\n0\r\n").unwrap(), None);
+        assert!(chunked_len(b"zz\r\n").is_err());
+        assert!(chunked_len(b"3\r\nabcde\r\n").is_err());
+        assert!(chunked_len(b"ffffffffffffffff\r\n").is_err());
+
+        let res = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhi\r\n0\r\n\r\n";
+        assert_eq!(response(res).unwrap(), Some((res.len(), 200)));
+    }
+}
@@ -0,0 +1,310 @@
+// Response compression negotiated with Accept-Encoding
+// Small bodies are compressed in one go. File bodies are compressed a chunk at a time as they're
+// written out (see `Outgoing`), and sent with chunked transfer encoding since the compressed
+// length isn't known up front.
+
+use crate::{
+    request::Request,
+    response::{Body, Response},
+};
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use std::io::{self, Write};
+
+// only these content types are worth compressing, everything else is usually compressed already
+const COMPRESSIBLE: [&str; 6] = [
+    "text/",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+// Pick an encoding the client accepts, gzip over deflate when both are equally welcome
+// `identity` and anything unknown are ignored, `q=0` rules an encoding out.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    let mut star = None;
+    let mut refused = Vec::new();
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .filter_map(|q| q.trim().parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+
+        let encoding = match name.as_str() {
+            "gzip" | "x-gzip" => Encoding::Gzip,
+            "deflate" => Encoding::Deflate,
+            "*" => {
+                star = Some(q);
+                continue;
+            }
+            _ => continue,
+        };
+        if q <= 0.0 {
+            refused.push(encoding);
+            continue;
+        }
+        match best {
+            Some((_, best_q)) if best_q > q => {}
+            Some((Encoding::Gzip, best_q)) if best_q == q => {}
+            _ => best = Some((encoding, q)),
+        }
+    }
+
+    if let Some((encoding, _)) = best {
+        return Some(encoding);
+    }
+    // `*` stands for anything not mentioned explicitly
+    match star {
+        Some(q) if q > 0.0 => [Encoding::Gzip, Encoding::Deflate]
+            .iter()
+            .copied()
+            .find(|e| !refused.contains(e)),
+        _ => None,
+    }
+}
+
+fn compressible(content_type: &str) -> bool {
+    COMPRESSIBLE.iter().any(|t| content_type.starts_with(t))
+}
+
+pub struct Compress {
+    // bodies smaller than this aren't worth the trouble
+    pub min_size: u64,
+}
+
+impl Compress {
+    // Compress the response if its type and size qualify and the client accepts it
+    pub fn apply(&self, req: &Request, res: Response) -> Response {
+        // ranges refer to the uncompressed representation, and the rest have no body
+        if res.status != 200 || res.encoding.is_some() {
+            return res;
+        }
+        let len = match &res.body {
+            // streamed bodies are sent as the handler produces them
+            Body::Empty | Body::Stream(_) => return res,
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File(_, len) => *len,
+        };
+        match res.header_value("Content-Type") {
+            Some(content_type) if compressible(content_type) => {}
+            _ => return res,
+        }
+
+        // the response differs with Accept-Encoding whether or not this client gets it compressed
+        let res = res.header("Vary", "Accept-Encoding");
+        if len < self.min_size {
+            return res;
+        }
+        // HTTP/1.0 clients can't take a chunked body
+        let chunked_ok = req.version != "HTTP/1.0";
+        match req.header("Accept-Encoding").and_then(negotiate) {
+            Some(encoding) => res.encode(encoding, chunked_ok),
+            None => res,
+        }
+    }
+}
+
+// A streaming compressor whose output is collected in memory
+pub enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    pub fn new(encoding: Encoding) -> Encoder {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            // HTTP's "deflate" is the zlib format
+            Encoding::Deflate => {
+                Encoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::default()))
+            }
+        }
+    }
+
+    // compress some input and hand back whatever compressed output is ready
+    pub fn encode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let out = match self {
+            Encoder::Gzip(e) => {
+                e.write_all(data)?;
+                e.get_mut()
+            }
+            Encoder::Deflate(e) => {
+                e.write_all(data)?;
+                e.get_mut()
+            }
+        };
+        Ok(std::mem::take(out))
+    }
+
+    // the rest of the compressed stream, including the trailer
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Deflate(e) => e.finish(),
+        }
+    }
+}
+
+pub fn encode_all(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+    let mut encoder = Encoder::new(encoding);
+    // writing into a Vec can't fail
+    let mut out = encoder.encode(data).unwrap();
+    out.extend(encoder.finish().unwrap());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use std::io::Read;
+
+    fn get(accept_encoding: Option<&str>, version: &str) -> Request {
+        Request {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            version: version.to_string(),
+            headers: accept_encoding
+                .map(|value| ("Accept-Encoding".to_string(), value.to_string()))
+                .into_iter()
+                .collect(),
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn preference() {
+        assert_eq!(negotiate("gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("x-gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("GZip"), Some(Encoding::Gzip));
+        // gzip wins a tie, whichever order they're listed in
+        assert_eq!(negotiate("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br, deflate"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("br"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn q_values() {
+        assert_eq!(negotiate("gzip;q=0.5, deflate"), Some(Encoding::Deflate));
+        assert_eq!(
+            negotiate("gzip; q=0.9, deflate;q=0.8"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            negotiate("gzip;q=0, deflate;q=0.1"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(negotiate("gzip;q=0"), None);
+        assert_eq!(negotiate("gzip;q=0.000, deflate;q=0"), None);
+        // an unparsable q counts as 1
+        assert_eq!(
+            negotiate("deflate;q=abc, gzip;q=0.5"),
+            Some(Encoding::Deflate)
+        );
+    }
+
+    #[test]
+    fn identity() {
+        // identity is never picked as an encoding, the response just goes out as it is
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("identity;q=0"), None);
+        assert_eq!(negotiate("identity;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate("identity, deflate;q=0.5"),
+            Some(Encoding::Deflate)
+        );
+    }
+
+    #[test]
+    fn star() {
+        assert_eq!(negotiate("*"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0, *"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("gzip;q=0, deflate;q=0, *"), None);
+        assert_eq!(negotiate("*;q=0"), None);
+        // explicit entries come before `*`
+        assert_eq!(negotiate("deflate;q=0.2, *"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("gzip, *;q=0"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn apply() {
+        let compress = Compress { min_size: 16 };
+        let text = "hello compression ".repeat(20);
+        let res = || {
+            Response::new(200)
+                .header("Content-Type", "text/plain")
+                .body(Body::Bytes(text.clone().into_bytes()))
+        };
+
+        let out = compress.apply(&get(Some("gzip"), "HTTP/1.1"), res());
+        assert_eq!(out.header_value("Content-Encoding"), Some("gzip"));
+        assert_eq!(out.header_value("Vary"), Some("Accept-Encoding"));
+        let body = match out.body {
+            Body::Bytes(body) => body,
+            _ => panic!("expected a byte body"),
+        };
+        let mut plain = String::new();
+        GzDecoder::new(&body[..])
+            .read_to_string(&mut plain)
+            .unwrap();
+        assert_eq!(plain, text);
+
+        let out = compress.apply(&get(Some("deflate"), "HTTP/1.1"), res());
+        let body = match out.body {
+            Body::Bytes(body) => body,
+            _ => panic!("expected a byte body"),
+        };
+        let mut plain = String::new();
+        ZlibDecoder::new(&body[..])
+            .read_to_string(&mut plain)
+            .unwrap();
+        assert_eq!(plain, text);
+
+        // not accepted, but it still varies
+        let out = compress.apply(&get(None, "HTTP/1.1"), res());
+        assert_eq!(out.header_value("Content-Encoding"), None);
+        assert_eq!(out.header_value("Vary"), Some("Accept-Encoding"));
+
+        // too small, not compressible, or not a full response
+        let small = Response::text(200, "hi");
+        let out = compress.apply(&get(Some("gzip"), "HTTP/1.1"), small);
+        assert_eq!(out.header_value("Content-Encoding"), None);
+        let png = Response::new(200)
+            .header("Content-Type", "image/png")
+            .body(Body::Bytes(vec![0; 100]));
+        let out = compress.apply(&get(Some("gzip"), "HTTP/1.1"), png);
+        assert_eq!(out.header_value("Content-Encoding"), None);
+        let mut partial = res();
+        partial.status = 206;
+        let out = compress.apply(&get(Some("gzip"), "HTTP/1.1"), partial);
+        assert_eq!(out.header_value("Content-Encoding"), None);
+    }
+}
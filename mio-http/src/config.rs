@@ -0,0 +1,70 @@
+// Command line options
+// mio-http [--addr 0.0.0.0:8080] [--root ./public] [--max-connections 1024] [--access-log]
+//          [--upstream 127.0.0.1:9001,127.0.0.1:9002] [--compress] [--compress-min-size 1024]
+
+use crate::compress::Compress;
+use std::{net::SocketAddr, process};
+
+pub struct Config {
+    pub addr: String,
+    // serve files from this directory instead of the canned response
+    pub root: Option<String>,
+    // connections beyond this are answered with a 503 and closed
+    pub max_connections: usize,
+    // print a line per answered request to stdout
+    pub access_log: bool,
+    // proxy every request to these backends
+    pub upstreams: Vec<SocketAddr>,
+    // gzip/deflate responses for clients that accept it
+    pub compress: Option<Compress>,
+}
+
+impl Config {
+    pub fn from_args() -> Config {
+        let mut config = Config {
+            addr: "0.0.0.0:8080".to_string(),
+            root: None,
+            max_connections: 1024,
+            access_log: false,
+            upstreams: Vec::new(),
+            compress: None,
+        };
+        let mut min_size = 1024;
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().unwrap_or_else(|| usage(&arg));
+            match arg.as_str() {
+                "--addr" => config.addr = value(),
+                "--root" => config.root = Some(value()),
+                "--max-connections" => {
+                    config.max_connections = value().parse().unwrap_or_else(|_| usage(&arg))
+                }
+                "--access-log" => config.access_log = true,
+                "--compress" => config.compress = Some(Compress { min_size }),
+                "--compress-min-size" => min_size = value().parse().unwrap_or_else(|_| usage(&arg)),
+                "--upstream" => {
+                    for addr in value().split(',') {
+                        let addr = addr.trim().parse().unwrap_or_else(|_| usage(&arg));
+                        config.upstreams.push(addr);
+                    }
+                }
+                _ => usage(&arg),
+            }
+        }
+        // the size may come before or after --compress
+        if let Some(compress) = config.compress.as_mut() {
+            compress.min_size = min_size;
+        }
+        config
+    }
+}
+
+fn usage(arg: &str) -> ! {
+    eprintln!("unexpected argument: {}", arg);
+    eprintln!("usage: mio-http [--addr ADDR] [--root DIR] [--max-connections N] [--access-log]");
+    eprintln!(
+        "                [--upstream ADDR[,ADDR...]] [--compress] [--compress-min-size BYTES]"
+    );
+    process::exit(2);
+}
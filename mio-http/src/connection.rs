@@ -0,0 +1,68 @@
+use crate::response::Outgoing;
+use mio::{net::TcpStream, Token};
+use std::{
+    io::{self, Read},
+    time::Instant,
+};
+
+// The listener is always registered at Token(0), so connection tokens are shifted by one
+// from their slab keys. Freed keys are handed out again by the slab, and so are their tokens.
+pub const LISTENER: Token = Token(0);
+
+pub fn token(key: usize) -> Token {
+    Token(key + 1)
+}
+
+pub fn key(token: Token) -> usize {
+    token.0 - 1
+}
+
+// Everything the event loop knows about one client
+pub struct Connection {
+    pub socket: TcpStream,
+    // bytes read off the socket that haven't been answered yet
+    pub request: Vec<u8>,
+    // the request being answered, while its response is written out
+    pub exchange: Option<Exchange>,
+    // in proxy mode, the upstream connection the response is coming from
+    pub upstream: Option<usize>,
+}
+
+// A request that has been parsed and the response that is still being written out
+// A response larger than the socket send buffer (e.g. a big file) takes several writable events.
+pub struct Exchange {
+    pub method: String,
+    pub path: String,
+    pub started: Instant,
+    pub response: Outgoing,
+}
+
+impl Connection {
+    pub fn new(socket: TcpStream) -> Connection {
+        Connection {
+            socket,
+            request: Vec::with_capacity(192),
+            exchange: None,
+            upstream: None,
+        }
+    }
+
+    // Read until the socket would block and return how many bytes came in,
+    // None once the peer has gone away
+    pub fn fill(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        let mut read = 0;
+        loop {
+            match self.socket.read(buffer) {
+                // successful read of zero bytes means connected is closed
+                Ok(0) => return None,
+                Ok(n) => {
+                    self.request.extend_from_slice(&buffer[..n]);
+                    read += n;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Some(read),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,425 @@
+// Static files served from a document root
+// Conditional requests (ETag / If-Modified-Since) and single byte ranges are supported,
+// the file body itself is streamed by `Outgoing` so nothing here reads file contents.
+
+use crate::{
+    request::Request,
+    response::{Body, Response},
+};
+use std::{
+    fs::{self, File},
+    io::{self, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub struct DocRoot {
+    root: PathBuf, // canonical, so that resolved paths can be checked against it
+}
+
+impl DocRoot {
+    pub fn new(root: &str) -> io::Result<DocRoot> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(DocRoot { root })
+    }
+
+    pub fn serve(&self, req: &Request) -> Response {
+        if req.method != "GET" && req.method != "HEAD" {
+            return Response::text(405, "Method Not Allowed").header("Allow", "GET, HEAD");
+        }
+
+        let path = match self.resolve(&req.path) {
+            Some(path) => path,
+            None => return Response::text(404, "Not Found"),
+        };
+
+        match self.open(req, &path) {
+            Ok(res) => res,
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                Response::text(403, "Forbidden")
+            }
+            Err(_) => Response::text(500, "Internal Server Error"),
+        }
+    }
+
+    // map a request target onto a file under the root
+    // None for anything that doesn't exist or would escape the root
+    fn resolve(&self, target: &str) -> Option<PathBuf> {
+        let path = target.split(['?', '#']).next()?;
+        let path = percent_decode(path)?;
+        if !path.starts_with('/') {
+            return None;
+        }
+
+        let mut resolved = self.root.clone();
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return None,
+                s if s.contains('\\') || s.contains('\0') => return None,
+                s => resolved.push(s),
+            }
+        }
+
+        if resolved.is_dir() {
+            resolved.push("index.html");
+        }
+
+        // a symlink inside the root could still point outside of it
+        let resolved = fs::canonicalize(resolved).ok()?;
+        if resolved.starts_with(&self.root) && resolved.is_file() {
+            Some(resolved)
+        } else {
+            None
+        }
+    }
+
+    fn open(&self, req: &Request, path: &Path) -> io::Result<Response> {
+        let mut file = File::open(path)?;
+        let meta = file.metadata()?;
+        let len = meta.len();
+        // HTTP dates only have second precision
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| UNIX_EPOCH + Duration::from_secs(d.as_secs()));
+
+        let etag = format!(
+            "\"{:x}-{:x}\"",
+            len,
+            modified
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        );
+        let last_modified = modified.map(httpdate::fmt_http_date);
+
+        let mut res = Response::new(200)
+            .header("Content-Type", content_type(path))
+            .header("ETag", &etag)
+            .header("Accept-Ranges", "bytes");
+        if let Some(last_modified) = &last_modified {
+            res = res.header("Last-Modified", last_modified);
+        }
+
+        if not_modified(req, &etag, modified) {
+            res.status = 304;
+            return Ok(res);
+        }
+
+        let range = match req.header("Range") {
+            Some(range) if if_range_matches(req, &etag, last_modified.as_deref()) => {
+                parse_range(range, len)
+            }
+            _ => None,
+        };
+
+        match range {
+            Some(Ok((start, end))) => {
+                file.seek(SeekFrom::Start(start))?;
+                res.status = 206;
+                Ok(res
+                    .header("Content-Range", format!("bytes {}-{}/{}", start, end, len))
+                    .body(Body::File(file, end - start + 1)))
+            }
+            Some(Err(())) => Ok(Response::text(416, "Range Not Satisfiable")
+                .header("Content-Range", format!("bytes */{}", len))),
+            None => Ok(res.body(Body::File(file, len))),
+        }
+    }
+}
+
+// If-None-Match takes precedence over If-Modified-Since (RFC 7232, section 6)
+fn not_modified(req: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(tags) = req.header("If-None-Match") {
+        return tags.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || same_etag(tag.trim_start_matches("W/"), etag)
+        });
+    }
+
+    match (req.header("If-Modified-Since"), modified) {
+        (Some(since), Some(modified)) => match httpdate::parse_http_date(since) {
+            Ok(since) => modified <= since,
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+// the compressed representations carry the same tag with the encoding appended
+fn same_etag(tag: &str, etag: &str) -> bool {
+    if tag == etag {
+        return true;
+    }
+    let base = &etag[..etag.len() - 1];
+    ["gzip", "deflate"]
+        .iter()
+        .any(|encoding| tag == format!("{}-{}\"", base, encoding))
+}
+
+// a Range is only honoured if the If-Range validator (when present) still matches
+fn if_range_matches(req: &Request, etag: &str, last_modified: Option<&str>) -> bool {
+    match req.header("If-Range") {
+        None => true,
+        Some(validator) if validator.starts_with('"') => validator == etag,
+        Some(validator) => Some(validator) == last_modified,
+    }
+}
+
+// Parse a single `bytes=` range into inclusive offsets
+// None means the header is ignored (unknown unit, multiple ranges or garbage) and the
+// whole file is sent, Some(Err) means the range can't be satisfied for this file.
+fn parse_range(header: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let dash = spec.find('-')?;
+    let (start, end) = (spec[..dash].trim(), spec[dash + 1..].trim());
+
+    let range = if start.is_empty() {
+        // suffix range: the last n bytes
+        let n: u64 = end.parse().ok()?;
+        if n == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        (len.saturating_sub(n), len - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end: u64 = if end.is_empty() {
+            u64::MAX
+        } else {
+            end.parse().ok()?
+        };
+        if end < start {
+            return None;
+        }
+        if start >= len {
+            return Some(Err(()));
+        }
+        (start, end.min(len - 1))
+    };
+    Some(Ok(range))
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            // from_str_radix would also take a sign, as in "%+1"
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            out.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "application/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") | Some("md") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(path: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            body: Vec::new(),
+        }
+    }
+
+    // a fresh document root with `dir/hello.txt` in it, and a file next to the root
+    fn doc_root(name: &str) -> (DocRoot, PathBuf) {
+        let base = std::env::temp_dir().join(format!("mio-http-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("root/dir")).unwrap();
+        fs::write(base.join("root/dir/hello.txt"), "hello world").unwrap();
+        fs::write(base.join("secret.txt"), "secret").unwrap();
+        let root = DocRoot::new(base.join("root").to_str().unwrap()).unwrap();
+        (root, base)
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-3", 10), Some(Ok((0, 3))));
+        // open-ended and past the end are clamped to the last byte
+        assert_eq!(parse_range("bytes=4-", 10), Some(Ok((4, 9))));
+        assert_eq!(parse_range("bytes=4-100", 10), Some(Ok((4, 9))));
+        // suffix ranges
+        assert_eq!(parse_range("bytes=-3", 10), Some(Ok((7, 9))));
+        assert_eq!(parse_range("bytes=-30", 10), Some(Ok((0, 9))));
+        // unsatisfiable
+        assert_eq!(parse_range("bytes=10-", 10), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 10), Some(Err(())));
+        assert_eq!(parse_range("bytes=-5", 0), Some(Err(())));
+        // ignored: multiple ranges, other units and garbage
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), None);
+        assert_eq!(parse_range("items=0-1", 10), None);
+        assert_eq!(parse_range("bytes=5-2", 10), None);
+        assert_eq!(parse_range("bytes=a-b", 10), None);
+    }
+
+    #[test]
+    fn range_responses() {
+        let (root, base) = doc_root("ranges");
+
+        let res = root.serve(&get("/dir/hello.txt", &[("Range", "bytes=6-")]));
+        assert_eq!(res.status, 206);
+        assert_eq!(res.header_value("Content-Range"), Some("bytes 6-10/11"));
+        assert!(matches!(res.body, Body::File(_, 5)));
+
+        let res = root.serve(&get("/dir/hello.txt", &[("Range", "bytes=20-")]));
+        assert_eq!(res.status, 416);
+        assert_eq!(res.header_value("Content-Range"), Some("bytes */11"));
+
+        // more than one range gets the whole file
+        let res = root.serve(&get("/dir/hello.txt", &[("Range", "bytes=0-1,3-4")]));
+        assert_eq!(res.status, 200);
+        assert!(matches!(res.body, Body::File(_, 11)));
+
+        // a stale If-Range gets the whole file too
+        let res = root.serve(&get(
+            "/dir/hello.txt",
+            &[("Range", "bytes=0-1"), ("If-Range", "\"stale\"")],
+        ));
+        assert_eq!(res.status, 200);
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn traversal() {
+        let (root, base) = doc_root("traversal");
+
+        assert_eq!(root.serve(&get("/dir/hello.txt", &[])).status, 200);
+        assert_eq!(root.serve(&get("/dir/./hello.txt?x=1", &[])).status, 200);
+        for path in &[
+            "/../secret.txt",
+            "/dir/../../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/dir/..%2f..%2fsecret.txt",
+            "/dir\\..\\..\\secret.txt",
+            "/dir/hello.txt%00",
+            "dir/hello.txt",
+            "/missing.txt",
+        ] {
+            assert_eq!(root.serve(&get(path, &[])).status, 404, "{}", path);
+        }
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let (root, base) = doc_root("conditional");
+
+        let res = root.serve(&get("/dir/hello.txt", &[]));
+        let etag = res.header_value("ETag").unwrap().to_string();
+        let last_modified = res.header_value("Last-Modified").unwrap().to_string();
+
+        let res = root.serve(&get("/dir/hello.txt", &[("If-None-Match", &etag)]));
+        assert_eq!(res.status, 304);
+        let weak = format!("\"other\", W/{}", etag);
+        let res = root.serve(&get("/dir/hello.txt", &[("If-None-Match", &weak)]));
+        assert_eq!(res.status, 304);
+        let res = root.serve(&get(
+            "/dir/hello.txt",
+            &[("If-Modified-Since", &last_modified)],
+        ));
+        assert_eq!(res.status, 304);
+
+        // If-None-Match wins over a matching If-Modified-Since
+        let res = root.serve(&get(
+            "/dir/hello.txt",
+            &[
+                ("If-None-Match", "\"other\""),
+                ("If-Modified-Since", &last_modified),
+            ],
+        ));
+        assert_eq!(res.status, 200);
+        // and over a stale one
+        let res = root.serve(&get(
+            "/dir/hello.txt",
+            &[
+                ("If-None-Match", &etag),
+                ("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT"),
+            ],
+        ));
+        assert_eq!(res.status, 304);
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn compressed_etags() {
+        assert!(same_etag("\"b-5f\"", "\"b-5f\""));
+        assert!(same_etag("\"b-5f-gzip\"", "\"b-5f\""));
+        assert!(same_etag("\"b-5f-deflate\"", "\"b-5f\""));
+        assert!(!same_etag("\"b-5f-br\"", "\"b-5f\""));
+        assert!(!same_etag("\"b-60\"", "\"b-5f\""));
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("/a%20b").as_deref(), Some("/a b"));
+        assert_eq!(percent_decode("/%C3%A9t%c3%a9").as_deref(), Some("/été"));
+        assert_eq!(percent_decode("/plain").as_deref(), Some("/plain"));
+        // truncated or non-hex escapes, and bytes that aren't UTF-8
+        assert_eq!(percent_decode("/a%2"), None);
+        assert_eq!(percent_decode("/a%zz"), None);
+        assert_eq!(percent_decode("/a%+1"), None);
+        assert_eq!(percent_decode("/a%-1"), None);
+        assert_eq!(percent_decode("/%ff"), None);
+    }
+}
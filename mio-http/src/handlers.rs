@@ -0,0 +1,55 @@
+// Handlers whose bodies are streamed while they're being produced
+// A handler gets the sending half of its body and returns the response head, the event loop
+// attaches the body and writes chunks out as they're sent.
+
+use crate::{response::Response, stream::BodySender};
+use std::{thread, time::Duration};
+
+pub type StreamHandler = fn(BodySender) -> Response;
+
+pub fn route(path: &str) -> Option<StreamHandler> {
+    match path {
+        "/stream/report" => Some(report),
+        "/stream/events" => Some(events),
+        _ => None,
+    }
+}
+
+// A CSV report that takes a while to put together, sent a row at a time as rows are ready
+fn report(body: BodySender) -> Response {
+    thread::spawn(move || {
+        if body.send("id,square\n").is_err() {
+            return;
+        }
+        for id in 0..100_u64 {
+            // pretend every row is an expensive query
+            thread::sleep(Duration::from_millis(20));
+            if body.send(format!("{},{}\n", id, id * id)).is_err() {
+                return; // the client went away
+            }
+        }
+    });
+
+    Response::new(200)
+        .header("Content-Type", "text/csv")
+        .header("Content-Disposition", "attachment; filename=\"report.csv\"")
+}
+
+// Server-sent events: a tick every second until the client goes away
+fn events(body: BodySender) -> Response {
+    thread::spawn(move || {
+        for tick in 0_u64.. {
+            if body
+                .send(format!("event: tick\ndata: {}\n\n", tick))
+                .is_err()
+            {
+                return;
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    });
+
+    Response::new(200)
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+}
@@ -1,13 +1,34 @@
-// Testing: wrk -d 60s -t 8 -c 128 --rate 150k http://127.0.0.1:8080/
+// Testing: cargo run --release --bin mio-http-bench -- --duration 60 --connections 128 --rate 150000
+// (or with wrk: wrk -d 60s -t 8 -c 128 --rate 150k http://127.0.0.1:8080/)
+// Serving files: cargo run -p mio-http -- --root ./public
+// Counters for Prometheus are at /metrics, --access-log prints a line per request
+// Reverse proxy: cargo run -p mio-http -- --upstream 127.0.0.1:9001,127.0.0.1:9002
+// Streamed bodies: curl -N http://127.0.0.1:8080/stream/events (or /stream/report)
 
-use mio::{
-    net::{TcpListener, TcpStream},
-    Events, Interest, Poll, Token,
-};
-use std::{
-    collections::HashMap,
-    io::{self, Read, Write},
-};
+mod compress;
+mod config;
+mod connection;
+mod files;
+mod handlers;
+mod metrics;
+mod proxy;
+mod reject;
+mod request;
+mod response;
+mod stream;
+
+use config::Config;
+use connection::{Connection, Exchange, LISTENER};
+use files::DocRoot;
+use metrics::Metrics;
+use mio::{net::TcpListener, Events, Interest, Poll, Registry, Token};
+use proxy::Proxy;
+use reject::Rejected;
+use request::Parsed;
+use response::{Body, Outgoing, Response};
+use slab::Slab;
+use std::{io, time::Instant};
+use stream::{Streams, WAKER};
 
 // predefined HTTP response
 static RESPONSE: &str = "HTTP/1.1 200 OK
@@ -18,40 +39,164 @@ Content-Length: 6
 hello
 ";
 
-fn is_double_crnl(window: &[u8]) -> bool {
-    window.len() >= 4
-        && (window[0] == '\r' as u8)
-        && (window[1] == '\n' as u8)
-        && (window[2] == '\r' as u8)
-        && (window[3] == '\n' as u8)
+// What the server does with requests (other than /metrics)
+enum Mode {
+    Canned,
+    Files(DocRoot),
+    Proxy(Proxy),
+}
+
+// Build the response for the first complete request in the buffer, if there is one
+// The request bytes are removed from the buffer so pipelined requests are answered in order.
+fn respond(
+    mode: &mut Mode,
+    config: &Config,
+    metrics: &Metrics,
+    registry: &Registry,
+    streams: &Streams,
+    token: Token,
+    conn: &mut Connection,
+) -> Option<Exchange> {
+    let started = Instant::now();
+    let buf = &mut conn.request;
+    match request::parse(buf) {
+        Parsed::Partial => None,
+        Parsed::Invalid => {
+            buf.clear();
+            Some(Exchange {
+                method: "-".to_string(),
+                path: "-".to_string(),
+                started,
+                response: Response::text(400, "Bad Request").into_outgoing(false, false),
+            })
+        }
+        // the body isn't read, the connection is closed after the answer
+        Parsed::TooLarge => {
+            buf.clear();
+            Some(Exchange {
+                method: "-".to_string(),
+                path: "-".to_string(),
+                started,
+                response: Response::text(413, "Payload Too Large").into_outgoing(false, false),
+            })
+        }
+        Parsed::Complete(req, len) => {
+            buf.drain(..len);
+            let head_only = req.method == "HEAD";
+            let keep_alive = req.keep_alive();
+            // compression applies to what this server produces itself, not to proxied responses
+            let compress = |res: Response| match &config.compress {
+                Some(compress) => compress.apply(&req, res),
+                None => res,
+            };
+            let response = if req.path == "/metrics" {
+                let res = Response::new(200)
+                    .header("Content-Type", "text/plain; version=0.0.4")
+                    .body(Body::Bytes(metrics.render().into_bytes()));
+                compress(res).into_outgoing(head_only, keep_alive)
+            } else if let (Some(handler), false) =
+                (handlers::route(&req.path), matches!(mode, Mode::Proxy(_)))
+            {
+                match streams.open(token) {
+                    // the handler keeps producing the body after this returns
+                    Some((sender, body)) => {
+                        let res = handler(sender).body(body);
+                        // HTTP/1.0 clients can't take a chunked body
+                        let res = if req.version == "HTTP/1.0" {
+                            res.close_delimited()
+                        } else {
+                            res
+                        };
+                        res.into_outgoing(head_only, keep_alive)
+                    }
+                    None => Response::text(503, "Too Many Streams")
+                        .header("Retry-After", "1")
+                        .into_outgoing(head_only, keep_alive),
+                }
+            } else {
+                match mode {
+                    Mode::Canned => Outgoing::raw(200, RESPONSE.as_bytes()),
+                    Mode::Files(docroot) => {
+                        compress(docroot.serve(&req)).into_outgoing(head_only, keep_alive)
+                    }
+                    Mode::Proxy(proxy) => {
+                        let key = connection::key(token);
+                        let peer = conn.socket.peer_addr().ok();
+                        match proxy.forward(registry, key, peer, &req) {
+                            // the upstream fills the response in as it arrives
+                            Ok(upstream) => {
+                                conn.upstream = Some(upstream);
+                                Outgoing::streaming(keep_alive)
+                            }
+                            Err(res) => res.into_outgoing(head_only, keep_alive),
+                        }
+                    }
+                }
+            };
+            Some(Exchange {
+                method: req.method,
+                path: req.path,
+                started,
+                response,
+            })
+        }
+    }
+}
+
+fn close(connections: &mut Slab<Connection>, mode: &mut Mode, key: usize) {
+    let conn = connections.remove(key);
+    if let (Some(upstream), Mode::Proxy(proxy)) = (conn.upstream, mode) {
+        proxy.cancel(upstream);
+    }
 }
 
 fn main() {
-    let addr = "0.0.0.0:8080";
-    let mut listener = TcpListener::bind(addr.parse().unwrap()).unwrap();
+    let config = Config::from_args();
+    let mut mode = if !config.upstreams.is_empty() {
+        Mode::Proxy(Proxy::new(config.upstreams.clone()))
+    } else if let Some(root) = &config.root {
+        Mode::Files(DocRoot::new(root).unwrap_or_else(|e| panic!("can't serve {}: {}", root, e)))
+    } else {
+        Mode::Canned
+    };
 
-    let mut counter: usize = 0;
-    let mut sockets: HashMap<Token, TcpStream> = HashMap::new();
+    let mut listener = TcpListener::bind(config.addr.parse().unwrap()).unwrap();
 
-    // let mut response: HashMap<Token, usize> = HashMap::new();
+    // Every open connection lives in the slab, its key (shifted by one) is its token
+    // Keys of closed connections are reused, so tokens stay small and dense.
+    let mut connections: Slab<Connection> = Slab::with_capacity(config.max_connections);
 
     // Fixed size buffer for reading/writing to/from sockets
-    let mut buffer = [0 as u8; 1024];
+    let mut buffer = [0_u8; 1024];
 
-    // Mocking HTTP
-    let mut requests: HashMap<Token, Vec<u8>> = HashMap::new();
+    let mut metrics = Metrics::new();
+    let mut rejected = Rejected::new();
 
     // Then create Poll object and register listener at Token(0) for readable events, activated by edge
     let mut poll = Poll::new().unwrap();
     poll.registry()
-        .register(&mut listener, Token(0), Interest::READABLE)
+        .register(&mut listener, LISTENER, Interest::READABLE)
         .unwrap();
+    let streams = Streams::new(poll.registry());
 
     // create events object of a given capacity
     // and a main loop
     let mut events = Events::with_capacity(1024);
     loop {
-        poll.poll(&mut events, None).unwrap();
+        // the proxy wakes the loop up for its health checks
+        // and the sockets turned away at the connection limit have to be closed eventually
+        let timeout = match &mode {
+            Mode::Proxy(proxy) => Some(proxy.timeout()),
+            _ => None,
+        };
+        let timeout = match (timeout, rejected.timeout()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        poll.poll(&mut events, timeout).unwrap();
+        let iteration = Instant::now();
+        metrics.open_connections = connections.len();
+
         for event in &events {
             // accepting connections and dropping them
             // readable events on the listener means incoming connections are waiting to be accepted
@@ -62,14 +207,22 @@ fn main() {
             // simple event handling -  accept all connections, drop all packets
 
             match event.token() {
-                Token(0) => {
+                LISTENER => {
                     loop {
                         match listener.accept() {
                             Ok((mut socket, _address)) => {
                                 // println!("Got connection from {:?}", address);
+                                if connections.len() >= config.max_connections {
+                                    // The accept backlog still has to be drained (the listener is edge triggered),
+                                    // so take the connection and turn it away.
+                                    rejected.reject(poll.registry(), socket, &mut buffer);
+                                    metrics.rejected_connections += 1;
+                                    continue;
+                                }
+
                                 // register sockets to poll
-                                counter += 1;
-                                let token = Token(counter);
+                                let entry = connections.vacant_entry();
+                                let token = connection::token(entry.key());
 
                                 // register readable events
                                 poll.registry()
@@ -83,8 +236,7 @@ fn main() {
                                     )
                                     .unwrap();
 
-                                sockets.insert(token, socket);
-                                requests.insert(token, Vec::with_capacity(192));
+                                entry.insert(Connection::new(socket));
                             } // connection dropped
                             Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                                 // no more connections (the error connection says it's about to block)
@@ -96,66 +248,123 @@ fn main() {
                         }
                     }
                 }
+                WAKER => {
+                    // handlers sent more of their bodies (or finished), write it out
+                    for token in streams.ready() {
+                        let conn = match connections.get_mut(connection::key(token)) {
+                            Some(conn) => conn,
+                            None => continue, // the client is gone
+                        };
+                        // the key may have been reused by a connection that isn't streaming
+                        match &conn.exchange {
+                            Some(exchange) if exchange.response.is_stream() => {}
+                            _ => continue,
+                        }
+                        poll.registry()
+                            .reregister(&mut conn.socket, token, Interest::WRITABLE)
+                            .unwrap();
+                    }
+                }
+                token if proxy::is_upstream(token) => {
+                    if let Mode::Proxy(proxy) = &mut mode {
+                        proxy.ready(poll.registry(), event, &mut connections, &mut buffer);
+                    }
+                }
+                token if reject::is_rejected(token) => {
+                    rejected.ready(poll.registry(), token, &mut buffer);
+                }
                 token if event.is_readable() => {
-                    // let mut bytes_read: usize = 0;
+                    let key = connection::key(token);
+                    let conn = match connections.get_mut(key) {
+                        Some(conn) => conn,
+                        None => continue, // already closed
+                    };
+
                     // Socket associated with token is ready for reading data from it
-                    loop {
-                        let read = sockets.get_mut(&token).unwrap().read(&mut buffer);
-                        match read {
-                            Ok(0) => {
-                                // successful read of zero bytes means connected is closed
-                                sockets.remove(&token);
-                                break;
-                            }
-                            Ok(n) => {
-                                // println!("Read {} bytes for token {}", n, token.0);
-                                let req = requests.get_mut(&token).unwrap();
-                                for b in &buffer[0..n] {
-                                    req.push(*b);
-                                }
-                                // bytes_read += n;
-                            }
-                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                            Err(e) => panic!("Unexpected error: {}", e),
+                    match conn.fill(&mut buffer) {
+                        Some(n) => metrics.bytes_read += n as u64,
+                        None => {
+                            close(&mut connections, &mut mode, key);
+                            continue;
                         }
                     }
-                    // response.insert(token, bytes_read);
-
-                    // once reading is done, mark socket for writing
-                    let ready = requests
-                        .get(&token)
-                        .unwrap()
-                        .windows(4)
-                        .find(|window| is_double_crnl(*window))
-                        .is_some();
-
-                    if ready {
-                        let socket = sockets.get_mut(&token).unwrap();
+
+                    // once a full request is in, mark socket for writing
+                    if let Some(exchange) = respond(
+                        &mut mode,
+                        &config,
+                        &metrics,
+                        poll.registry(),
+                        &streams,
+                        token,
+                        conn,
+                    ) {
+                        conn.exchange = Some(exchange);
                         poll.registry()
-                            .reregister(socket, token, Interest::WRITABLE)
+                            .reregister(&mut conn.socket, token, Interest::WRITABLE)
                             .unwrap();
                     }
                 }
                 token if event.is_writable() => {
-                    requests.get_mut(&token).unwrap().clear();
-                    sockets
-                        .get_mut(&token)
-                        .unwrap()
-                        .write_all(RESPONSE.as_bytes())
-                        .unwrap();
-                    // let n_bytes = response[&token];
-                    // let message = format!("Received {} bytes!", n_bytes);
-                    // sockets.get_mut(&token).unwrap().write_all(message.as_bytes()).unwrap();
-                    // response.remove(&token);
-                    // sockets.remove(&token);
+                    let key = connection::key(token);
+                    let conn = match connections.get_mut(key) {
+                        Some(conn) => conn,
+                        None => continue,
+                    };
+                    let exchange = match conn.exchange.as_mut() {
+                        Some(exchange) => exchange,
+                        None => continue,
+                    };
 
-                    // Re-use existing connection ("keep-alive") - switch back to reading
+                    let out = &mut exchange.response;
+                    let written = out.written;
+                    let result = out.write_to(&mut conn.socket);
+                    metrics.bytes_written += out.written - written;
+                    if let (Some(upstream), Mode::Proxy(proxy)) = (conn.upstream, &mut mode) {
+                        proxy.resume(poll.registry(), upstream, out.pending());
+                    }
+
+                    match result {
+                        // the rest goes out on the next writable event
+                        Ok(false) => continue,
+                        Ok(true) if out.keep_alive => {
+                            metrics.completed(token, exchange, config.access_log);
+                        }
+                        Ok(true) | Err(_) => {
+                            metrics.completed(token, exchange, config.access_log);
+                            close(&mut connections, &mut mode, key);
+                            continue;
+                        }
+                    }
+
+                    // A pipelined request may already be buffered, otherwise
+                    // re-use existing connection ("keep-alive") - switch back to reading
+                    // (re-registering re-arms the edge, so we hear about data or space that's already there)
+                    conn.exchange = respond(
+                        &mut mode,
+                        &config,
+                        &metrics,
+                        poll.registry(),
+                        &streams,
+                        token,
+                        conn,
+                    );
+                    let interest = match conn.exchange {
+                        Some(_) => Interest::WRITABLE,
+                        None => Interest::READABLE,
+                    };
                     poll.registry()
-                        .reregister(sockets.get_mut(&token).unwrap(), token, Interest::READABLE)
+                        .reregister(&mut conn.socket, token, interest)
                         .unwrap();
                 }
                 _ => {} // ignore everything else
             }
         }
+
+        if let Mode::Proxy(proxy) = &mut mode {
+            proxy.check_health(poll.registry(), &mut connections);
+        }
+        rejected.expire(poll.registry());
+        metrics.observe_iteration(iteration);
     }
 }
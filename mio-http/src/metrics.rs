@@ -0,0 +1,246 @@
+// Counters for the /metrics endpoint, in the Prometheus text format
+// Only the event loop thread ever touches them, so they're plain integers rather than atomics.
+
+use crate::connection::Exchange;
+use mio::Token;
+use std::{
+    fmt::Write,
+    io::{self, Write as _},
+    time::{Duration, Instant},
+};
+
+// upper bounds of the loop iteration histogram buckets, in seconds
+const ITERATION_BUCKETS: [f64; 9] = [
+    0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1,
+];
+
+pub struct Metrics {
+    pub open_connections: usize,
+    pub rejected_connections: u64,
+    pub requests: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+
+    // requests per second, measured over the last full one second window
+    // (the window is only rolled over by loop iterations, see `requests_per_second`)
+    requests_per_second: f64,
+    window_start: Instant,
+    window_requests: u64,
+
+    // time spent handling the events returned by one poll
+    iteration_buckets: [u64; ITERATION_BUCKETS.len()],
+    iteration_count: u64,
+    iteration_sum: Duration,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            open_connections: 0,
+            rejected_connections: 0,
+            requests: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            requests_per_second: 0.0,
+            window_start: Instant::now(),
+            window_requests: 0,
+            iteration_buckets: [0; ITERATION_BUCKETS.len()],
+            iteration_count: 0,
+            iteration_sum: Duration::from_secs(0),
+        }
+    }
+
+    pub fn observe_iteration(&mut self, started: Instant) {
+        let now = Instant::now();
+        let took = now - started;
+        let secs = took.as_secs_f64();
+        for (bucket, le) in self.iteration_buckets.iter_mut().zip(&ITERATION_BUCKETS) {
+            if secs <= *le {
+                *bucket += 1;
+            }
+        }
+        self.iteration_count += 1;
+        self.iteration_sum += took;
+
+        let window = now - self.window_start;
+        if window >= Duration::from_secs(1) {
+            self.requests_per_second =
+                (self.requests - self.window_requests) as f64 / window.as_secs_f64();
+            self.window_start = now;
+            self.window_requests = self.requests;
+        }
+    }
+
+    // An idle loop doesn't iterate, so the window can be far longer than a second by the
+    // time /metrics is asked for, the rate is then taken over the whole window instead.
+    fn requests_per_second(&self, now: Instant) -> f64 {
+        let window = now.saturating_duration_since(self.window_start);
+        if window >= Duration::from_secs(1) {
+            (self.requests - self.window_requests) as f64 / window.as_secs_f64()
+        } else {
+            self.requests_per_second
+        }
+    }
+
+    // a finished request/response, optionally written to the access log
+    pub fn completed(&mut self, token: Token, exchange: &Exchange, access_log: bool) {
+        self.requests += 1;
+        if access_log {
+            let out = io::stdout();
+            let _ = writeln!(
+                out.lock(),
+                "method={} path={:?} status={} bytes={} latency_us={} token={}",
+                exchange.method,
+                exchange.path,
+                exchange.response.status,
+                exchange.response.written,
+                exchange.started.elapsed().as_micros(),
+                token.0
+            );
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n",
+                name = name,
+                kind = kind,
+                help = help,
+                value = value
+            );
+        };
+        metric(
+            "mio_http_open_connections",
+            "gauge",
+            "Client connections currently open.",
+            self.open_connections.to_string(),
+        );
+        metric(
+            "mio_http_rejected_connections_total",
+            "counter",
+            "Connections turned away with a 503 because the server was full.",
+            self.rejected_connections.to_string(),
+        );
+        metric(
+            "mio_http_requests_total",
+            "counter",
+            "Requests answered.",
+            self.requests.to_string(),
+        );
+        metric(
+            "mio_http_requests_per_second",
+            "gauge",
+            "Requests answered per second over the last full second, or since the last sample while idle.",
+            format!("{:.2}", self.requests_per_second(Instant::now())),
+        );
+        metric(
+            "mio_http_read_bytes_total",
+            "counter",
+            "Bytes read from client sockets.",
+            self.bytes_read.to_string(),
+        );
+        metric(
+            "mio_http_written_bytes_total",
+            "counter",
+            "Bytes written to client sockets.",
+            self.bytes_written.to_string(),
+        );
+
+        let name = "mio_http_loop_iteration_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {} Time spent handling the events returned by one poll.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (count, le) in self.iteration_buckets.iter().zip(&ITERATION_BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count);
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{le=\"+Inf\"}} {}",
+            name, self.iteration_count
+        );
+        let _ = writeln!(out, "{}_sum {:.6}", name, self.iteration_sum.as_secs_f64());
+        let _ = writeln!(out, "{}_count {}", name, self.iteration_count);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value<'a>(out: &'a str, name: &str) -> &'a str {
+        out.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("{} missing from\n{}", name, out))
+    }
+
+    #[test]
+    fn render() {
+        let mut metrics = Metrics::new();
+        metrics.open_connections = 3;
+        metrics.rejected_connections = 1;
+        metrics.bytes_read = 100;
+        metrics.bytes_written = 2000;
+        metrics.requests = 7;
+        metrics.observe_iteration(Instant::now());
+
+        let out = metrics.render();
+        assert!(out.contains("# TYPE mio_http_requests_total counter\n"));
+        assert!(out.contains("# TYPE mio_http_open_connections gauge\n"));
+        assert_eq!(value(&out, "mio_http_open_connections"), "3");
+        assert_eq!(value(&out, "mio_http_rejected_connections_total"), "1");
+        assert_eq!(value(&out, "mio_http_requests_total"), "7");
+        assert_eq!(value(&out, "mio_http_read_bytes_total"), "100");
+        assert_eq!(value(&out, "mio_http_written_bytes_total"), "2000");
+
+        // one fast iteration lands in every bucket
+        assert!(out.contains("# TYPE mio_http_loop_iteration_seconds histogram\n"));
+        assert_eq!(
+            value(&out, "mio_http_loop_iteration_seconds_bucket{le=\"0.1\"}"),
+            "1"
+        );
+        assert_eq!(
+            value(&out, "mio_http_loop_iteration_seconds_bucket{le=\"+Inf\"}"),
+            "1"
+        );
+        assert_eq!(value(&out, "mio_http_loop_iteration_seconds_count"), "1");
+    }
+
+    #[test]
+    fn slow_iterations() {
+        let mut metrics = Metrics::new();
+        metrics.observe_iteration(Instant::now() - Duration::from_millis(20));
+        let out = metrics.render();
+        assert_eq!(
+            value(&out, "mio_http_loop_iteration_seconds_bucket{le=\"0.01\"}"),
+            "0"
+        );
+        assert_eq!(
+            value(&out, "mio_http_loop_iteration_seconds_bucket{le=\"0.05\"}"),
+            "1"
+        );
+    }
+
+    #[test]
+    fn requests_per_second() {
+        let mut metrics = Metrics::new();
+        // a full window of 50 requests
+        metrics.window_start = Instant::now() - Duration::from_secs(1);
+        metrics.requests = 50;
+        metrics.observe_iteration(Instant::now());
+        let start = metrics.window_start;
+        let rate = metrics.requests_per_second(start);
+        assert!(rate > 45.0 && rate <= 50.0, "{}", rate);
+
+        // five more requests, then nothing for ten seconds without the loop waking up
+        metrics.requests = 55;
+        let rate = metrics.requests_per_second(start + Duration::from_secs(10));
+        assert!((rate - 0.5).abs() < 1e-9, "{}", rate);
+    }
+}
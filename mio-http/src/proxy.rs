@@ -0,0 +1,696 @@
+// Reverse proxy to a list of upstream backends
+//
+// Upstream sockets are registered with the same Poll as the clients, using tokens from their own
+// range so the event loop can tell them apart. Every proxied request gets a fresh upstream
+// connection (sent with `Connection: close`) and the response is relayed to the client as it
+// arrives. Backends are picked round-robin among the healthy ones. A backend is marked down when
+// connecting to it fails, and a periodic connect probe brings it back once it accepts again.
+// A backend that accepts but doesn't send a response head in time gets the client a 504.
+
+use crate::{
+    connection::{self, Connection},
+    request::Request,
+    response::Response,
+};
+use mio::{event::Event, net::TcpStream, Interest, Registry, Token};
+use slab::Slab;
+use std::{
+    io::{self, Read, Write},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+// upstream tokens start here, client tokens stay below it
+const UPSTREAM: usize = usize::MAX / 2;
+
+const HEALTH_INTERVAL: Duration = Duration::from_secs(2);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+// how long an upstream has to send back its response head
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// stop reading from an upstream while this much is still waiting to be written to its client
+const MAX_BUFFERED: usize = 256 * 1024;
+// an upstream response head larger than this is treated as garbage
+const MAX_HEAD: usize = 64 * 1024;
+
+// headers that only make sense for a single hop, they aren't forwarded
+const HOP_BY_HOP: [&str; 7] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Upgrade",
+    "Expect",
+];
+
+pub fn is_upstream(token: Token) -> bool {
+    token.0 >= UPSTREAM
+}
+
+fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name))
+}
+
+struct Backend {
+    addr: SocketAddr,
+    healthy: bool,
+    // the outstanding health probe: its upstream key and when it was sent
+    probe: Option<(usize, Instant)>,
+}
+
+struct Upstream {
+    socket: TcpStream,
+    backend: usize,
+    // the client waiting for this response, None for a health probe
+    client: Option<usize>,
+    connected: bool,
+    // request bytes not yet written
+    out: Vec<u8>,
+    // the response head while it's being collected, None once it has gone to the client
+    head: Option<Vec<u8>>,
+    head_only: bool,
+    keep_alive: bool,
+    // reading stopped because the client is slower than the upstream
+    paused: bool,
+    started: Instant,
+}
+
+pub struct Proxy {
+    backends: Vec<Backend>,
+    next: usize, // round-robin cursor
+    upstreams: Slab<Upstream>,
+    next_check: Instant,
+}
+
+impl Proxy {
+    pub fn new(addrs: Vec<SocketAddr>) -> Proxy {
+        Proxy {
+            // optimistic until the first probe says otherwise
+            backends: addrs
+                .into_iter()
+                .map(|addr| Backend {
+                    addr,
+                    healthy: true,
+                    probe: None,
+                })
+                .collect(),
+            next: 0,
+            upstreams: Slab::new(),
+            next_check: Instant::now(),
+        }
+    }
+
+    // how long the event loop may sleep before health checking is due again
+    pub fn timeout(&self) -> Duration {
+        let now = Instant::now();
+        let probes = self
+            .backends
+            .iter()
+            .filter_map(|b| b.probe.map(|(_, sent)| sent + PROBE_TIMEOUT));
+        let responses = self
+            .upstreams
+            .iter()
+            .filter(|(_, up)| up.client.is_some() && up.head.is_some())
+            .map(|(_, up)| up.started + RESPONSE_TIMEOUT);
+        probes
+            .chain(responses)
+            .fold(self.next_check, |a, b| a.min(b))
+            .saturating_duration_since(now)
+    }
+
+    fn pick(&mut self) -> Option<usize> {
+        for _ in 0..self.backends.len() {
+            let i = self.next % self.backends.len();
+            self.next = self.next.wrapping_add(1);
+            if self.backends[i].healthy {
+                return Some(i);
+            }
+        }
+        None
+    }
+
+    fn connect(&mut self, registry: &Registry, mut upstream: Upstream) -> usize {
+        let entry = self.upstreams.vacant_entry();
+        let key = entry.key();
+        registry
+            .register(
+                &mut upstream.socket,
+                Token(UPSTREAM + key),
+                Interest::READABLE | Interest::WRITABLE,
+            )
+            .unwrap();
+        entry.insert(upstream);
+        key
+    }
+
+    // Send the client's request to the next healthy backend and return the upstream key
+    // Err is the response to give the client instead.
+    pub fn forward(
+        &mut self,
+        registry: &Registry,
+        client: usize,
+        peer: Option<SocketAddr>,
+        req: &Request,
+    ) -> Result<usize, Response> {
+        let backend = self
+            .pick()
+            .ok_or_else(|| Response::text(503, "No Healthy Upstream"))?;
+        let socket = match TcpStream::connect(self.backends[backend].addr) {
+            Ok(socket) => socket,
+            Err(_) => {
+                self.backends[backend].healthy = false;
+                return Err(Response::text(502, "Bad Gateway"));
+            }
+        };
+
+        let upstream = Upstream {
+            socket,
+            backend,
+            client: Some(client),
+            connected: false,
+            out: upstream_request(req, peer),
+            head: Some(Vec::new()),
+            head_only: req.method == "HEAD",
+            keep_alive: req.keep_alive(),
+            paused: false,
+            started: Instant::now(),
+        };
+        Ok(self.connect(registry, upstream))
+    }
+
+    // the client went away, so its upstream connection isn't needed any more
+    pub fn cancel(&mut self, key: usize) {
+        if self.upstreams.contains(key) {
+            self.upstreams.remove(key);
+        }
+    }
+
+    // the client has drained enough of the relayed response to read from the upstream again
+    pub fn resume(&mut self, registry: &Registry, key: usize, pending: usize) {
+        if let Some(up) = self.upstreams.get_mut(key) {
+            if up.paused && pending < MAX_BUFFERED {
+                up.paused = false;
+                // re-registering re-arms the edge for data that's already waiting
+                registry
+                    .reregister(
+                        &mut up.socket,
+                        Token(UPSTREAM + key),
+                        Interest::READABLE | Interest::WRITABLE,
+                    )
+                    .unwrap();
+            }
+        }
+    }
+
+    // Probe every backend once per interval, and give up on probes and responses that take too long
+    pub fn check_health(&mut self, registry: &Registry, connections: &mut Slab<Connection>) {
+        let now = Instant::now();
+        let late: Vec<usize> = self
+            .upstreams
+            .iter()
+            .filter(|(_, up)| {
+                up.client.is_some() && up.head.is_some() && now - up.started >= RESPONSE_TIMEOUT
+            })
+            .map(|(key, _)| key)
+            .collect();
+        for key in late {
+            let timeout = Response::text(504, "Gateway Timeout");
+            self.abort(registry, key, connections, timeout);
+        }
+
+        for i in 0..self.backends.len() {
+            if let Some((key, sent)) = self.backends[i].probe {
+                if now - sent >= PROBE_TIMEOUT {
+                    self.upstreams.remove(key);
+                    self.backends[i].probe = None;
+                    self.backends[i].healthy = false;
+                }
+            }
+        }
+
+        if now < self.next_check {
+            return;
+        }
+        self.next_check = now + HEALTH_INTERVAL;
+
+        for i in 0..self.backends.len() {
+            if self.backends[i].probe.is_some() {
+                continue;
+            }
+            let socket = match TcpStream::connect(self.backends[i].addr) {
+                Ok(socket) => socket,
+                Err(_) => {
+                    self.backends[i].healthy = false;
+                    continue;
+                }
+            };
+            let probe = Upstream {
+                socket,
+                backend: i,
+                client: None,
+                connected: false,
+                out: Vec::new(),
+                head: None,
+                head_only: false,
+                keep_alive: false,
+                paused: false,
+                started: now,
+            };
+            let key = self.connect(registry, probe);
+            self.backends[i].probe = Some((key, now));
+        }
+    }
+
+    // An event on an upstream token
+    pub fn ready(
+        &mut self,
+        registry: &Registry,
+        event: &Event,
+        connections: &mut Slab<Connection>,
+        buffer: &mut [u8],
+    ) {
+        let key = event.token().0 - UPSTREAM;
+        let up = match self.upstreams.get_mut(key) {
+            Some(up) => up,
+            None => return,
+        };
+
+        if !up.connected {
+            // the first writable (or error) event tells us how the non-blocking connect went
+            if !(event.is_writable() || event.is_error() || event.is_write_closed()) {
+                return;
+            }
+            let backend = &mut self.backends[up.backend];
+            match (up.socket.take_error(), up.socket.peer_addr()) {
+                (Ok(None), Ok(_)) => {
+                    up.connected = true;
+                    backend.healthy = true;
+                }
+                _ => {
+                    backend.healthy = false;
+                    return self.fail(registry, key, connections);
+                }
+            }
+
+            if up.client.is_none() {
+                // a health probe only needed the connect to succeed
+                backend.probe = None;
+                self.upstreams.remove(key);
+                return;
+            }
+        }
+
+        if event.is_writable() && !up.out.is_empty() {
+            match write_some(&mut up.socket, &mut up.out) {
+                Ok(()) => {}
+                Err(_) => return self.fail(registry, key, connections),
+            }
+        }
+
+        if event.is_readable() || event.is_read_closed() || event.is_error() {
+            self.relay(registry, key, connections, buffer);
+        }
+    }
+
+    // Move response bytes from the upstream to its client's output buffer
+    fn relay(
+        &mut self,
+        registry: &Registry,
+        key: usize,
+        connections: &mut Slab<Connection>,
+        buffer: &mut [u8],
+    ) {
+        let up = &mut self.upstreams[key];
+        let client = match up.client {
+            Some(client) => client,
+            // probes are done once they connect, so one shouldn't get this far
+            None => {
+                self.upstreams.remove(key);
+                return;
+            }
+        };
+        let conn = match connections.get_mut(client) {
+            Some(conn) if conn.upstream == Some(key) => conn,
+            _ => {
+                self.upstreams.remove(key);
+                return;
+            }
+        };
+        let out = &mut conn.exchange.as_mut().unwrap().response;
+
+        let mut done = false;
+        loop {
+            if out.pending() >= MAX_BUFFERED {
+                up.paused = true;
+                break;
+            }
+            match up.socket.read(buffer) {
+                Ok(0) => {
+                    done = true;
+                    break;
+                }
+                Ok(n) => {
+                    if up.head.is_none() {
+                        out.push(&buffer[..n]);
+                        continue;
+                    }
+                    let head = up.head.as_mut().unwrap();
+                    head.extend_from_slice(&buffer[..n]);
+                    match head.windows(4).position(|w| w == b"\r\n\r\n") {
+                        Some(end) => {
+                            let body = head.split_off(end + 4);
+                            let (head, status, keep_alive) =
+                                rewrite_head(&up.head.take().unwrap(), up.head_only, up.keep_alive);
+                            out.status = status;
+                            out.keep_alive = keep_alive;
+                            out.push(&head);
+                            out.push(&body);
+                        }
+                        None if head.len() > MAX_HEAD => {
+                            done = true;
+                            break;
+                        }
+                        None => {}
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    done = true;
+                    break;
+                }
+            }
+        }
+
+        if done {
+            if up.head.is_some() {
+                // nothing usable came back
+                return self.fail(registry, key, connections);
+            }
+            out.finish();
+            conn.upstream = None;
+            self.upstreams.remove(key);
+        }
+        wake(registry, client, conn);
+    }
+
+    // The upstream connection broke, answer the client with a 502 if nothing was relayed yet,
+    // otherwise cut the client off since its response can't be completed
+    fn fail(&mut self, registry: &Registry, key: usize, connections: &mut Slab<Connection>) {
+        self.abort(
+            registry,
+            key,
+            connections,
+            Response::text(502, "Bad Gateway"),
+        );
+    }
+
+    // Drop the upstream and answer its client with `error` instead, if nothing was relayed yet
+    fn abort(
+        &mut self,
+        registry: &Registry,
+        key: usize,
+        connections: &mut Slab<Connection>,
+        error: Response,
+    ) {
+        let up = self.upstreams.remove(key);
+        let client = match up.client {
+            Some(client) => client,
+            None => {
+                self.backends[up.backend].probe = None;
+                return;
+            }
+        };
+        let conn = match connections.get_mut(client) {
+            Some(conn) if conn.upstream == Some(key) => conn,
+            _ => return,
+        };
+        conn.upstream = None;
+
+        let exchange = conn.exchange.as_mut().unwrap();
+        if up.head.is_some() {
+            exchange.response = error.into_outgoing(up.head_only, up.keep_alive);
+        } else {
+            exchange.response.keep_alive = false;
+            exchange.response.finish();
+        }
+        wake(registry, client, conn);
+    }
+}
+
+// re-registering for writes re-arms the edge, so the event loop gets a writable event for the client
+fn wake(registry: &Registry, key: usize, conn: &mut Connection) {
+    registry
+        .reregister(&mut conn.socket, connection::token(key), Interest::WRITABLE)
+        .unwrap();
+}
+
+fn write_some(socket: &mut TcpStream, out: &mut Vec<u8>) -> io::Result<()> {
+    while !out.is_empty() {
+        match socket.write(out) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                out.drain(..n);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// the request as it goes to the backend
+fn upstream_request(req: &Request, peer: Option<SocketAddr>) -> Vec<u8> {
+    let mut head = format!("{} {} HTTP/1.1\r\n", req.method, req.path);
+    let mut forwarded_for = req.header("X-Forwarded-For").map(|v| v.to_string());
+    for (name, value) in &req.headers {
+        if is_hop_by_hop(name) || name.eq_ignore_ascii_case("X-Forwarded-For") {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(peer) = peer {
+        forwarded_for = Some(match forwarded_for {
+            Some(list) => format!("{}, {}", list, peer.ip()),
+            None => peer.ip().to_string(),
+        });
+    }
+    if let Some(forwarded_for) = forwarded_for {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(&req.body);
+    bytes
+}
+
+// Swap the backend's hop-by-hop headers for our own and decide whether the client connection
+// survives: the backend closes after every response, so a body delimited only by that close
+// has to be delimited the same way for the client.
+fn rewrite_head(head: &[u8], head_only: bool, keep_alive: bool) -> (Vec<u8>, u16, bool) {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+    let status_line = lines.next().unwrap_or("HTTP/1.1 502 Bad Gateway");
+    let status: u16 = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(502);
+
+    let mut framed = head_only || status == 204 || status == 304 || (100..200).contains(&status);
+    let mut out = format!("{}\r\n", status_line);
+    for line in lines {
+        let name = line.split(':').next().unwrap_or("").trim();
+        if is_hop_by_hop(name) {
+            continue;
+        }
+        if name.eq_ignore_ascii_case("Content-Length")
+            || name.eq_ignore_ascii_case("Transfer-Encoding")
+        {
+            framed = true;
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+
+    let keep_alive = keep_alive && framed;
+    if !keep_alive {
+        out.push_str("Connection: close\r\n");
+    }
+    out.push_str("\r\n");
+    (out.into_bytes(), status, keep_alive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)], body: &[u8]) -> Request {
+        Request {
+            method: "POST".to_string(),
+            path: "/submit?x=1".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            body: body.to_vec(),
+        }
+    }
+
+    fn proxy(backends: usize) -> Proxy {
+        Proxy::new(
+            (0..backends)
+                .map(|i| SocketAddr::from(([127, 0, 0, 1], 9001 + i as u16)))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn upstream_requests() {
+        let req = request(
+            &[
+                ("Host", "example.com"),
+                ("Connection", "keep-alive"),
+                ("Keep-Alive", "timeout=5"),
+                ("TE", "trailers"),
+                ("Upgrade", "websocket"),
+                ("x-forwarded-for", "10.0.0.1"),
+                ("Content-Length", "3"),
+            ],
+            b"abc",
+        );
+        let peer = SocketAddr::from(([192, 168, 1, 2], 50000));
+        let out = String::from_utf8(upstream_request(&req, Some(peer))).unwrap();
+
+        assert_eq!(
+            out,
+            "POST /submit?x=1 HTTP/1.1\r\n\
+             Host: example.com\r\n\
+             Content-Length: 3\r\n\
+             X-Forwarded-For: 10.0.0.1, 192.168.1.2\r\n\
+             Connection: close\r\n\r\nabc"
+        );
+
+        // the first proxy in the chain starts the list
+        let req = request(&[("Host", "example.com")], b"");
+        let out = String::from_utf8(upstream_request(&req, Some(peer))).unwrap();
+        assert!(out.contains("\r\nHost: example.com\r\n"));
+        assert!(out.contains("\r\nX-Forwarded-For: 192.168.1.2\r\n"));
+    }
+
+    #[test]
+    fn response_heads() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\nX-Backend: a\r\n\r\n";
+        let (out, status, keep_alive) = rewrite_head(head, false, true);
+        assert_eq!(status, 200);
+        assert!(keep_alive);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Backend: a\r\n\r\n"
+        );
+
+        // a body that only ends with the upstream connection closes the client's too
+        let (out, _, keep_alive) =
+            rewrite_head(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n", false, true);
+        assert!(!keep_alive);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n"
+        );
+
+        // no body to delimit
+        let (_, status, keep_alive) =
+            rewrite_head(b"HTTP/1.1 304 Not Modified\r\n\r\n", false, true);
+        assert_eq!(status, 304);
+        assert!(keep_alive);
+        let (_, _, keep_alive) = rewrite_head(b"HTTP/1.1 200 OK\r\n\r\n", true, true);
+        assert!(keep_alive);
+
+        // the client asked for a close
+        let (out, _, keep_alive) = rewrite_head(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+            false,
+            false,
+        );
+        assert!(!keep_alive);
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .ends_with("Connection: close\r\n\r\n"));
+
+        let (_, status, _) = rewrite_head(b"garbage", false, true);
+        assert_eq!(status, 502);
+    }
+
+    #[test]
+    fn slow_upstreams_get_a_504() {
+        use crate::{connection::Exchange, response::Outgoing};
+        use mio::Poll;
+        use std::net::TcpListener;
+
+        // the backend accepts connections but never answers
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut proxy = Proxy::new(vec![backend.local_addr().unwrap()]);
+        let poll = Poll::new().unwrap();
+
+        let front = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = std::net::TcpStream::connect(front.local_addr().unwrap()).unwrap();
+        let mut socket = TcpStream::from_std(front.accept().unwrap().0);
+        let mut connections = Slab::new();
+        let key = connections.vacant_entry().key();
+        poll.registry()
+            .register(&mut socket, connection::token(key), Interest::READABLE)
+            .unwrap();
+        connections.insert(Connection::new(socket));
+
+        let req = request(&[("Host", "example.com")], b"");
+        let upstream = proxy
+            .forward(poll.registry(), key, None, &req)
+            .ok()
+            .unwrap();
+        let conn = &mut connections[key];
+        conn.upstream = Some(upstream);
+        conn.exchange = Some(Exchange {
+            method: req.method.clone(),
+            path: req.path.clone(),
+            started: Instant::now(),
+            response: Outgoing::streaming(true),
+        });
+
+        // still within the deadline
+        proxy.check_health(poll.registry(), &mut connections);
+        assert!(proxy.upstreams.contains(upstream));
+        assert!(proxy.timeout() <= RESPONSE_TIMEOUT);
+
+        proxy.upstreams[upstream].started -= RESPONSE_TIMEOUT;
+        assert_eq!(proxy.timeout(), Duration::ZERO);
+        proxy.check_health(poll.registry(), &mut connections);
+        assert!(!proxy.upstreams.contains(upstream));
+        let conn = &connections[key];
+        assert_eq!(conn.upstream, None);
+        assert_eq!(conn.exchange.as_ref().unwrap().response.status, 504);
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut proxy = proxy(3);
+        let picks: Vec<_> = (0..6).map(|_| proxy.pick()).collect();
+        assert_eq!(picks, [0, 1, 2, 0, 1, 2].map(Some));
+
+        proxy.backends[1].healthy = false;
+        let picks: Vec<_> = (0..4).map(|_| proxy.pick()).collect();
+        assert_eq!(picks, [0, 2, 0, 2].map(Some));
+
+        proxy.backends[0].healthy = false;
+        proxy.backends[2].healthy = false;
+        assert_eq!(proxy.pick(), None);
+
+        // a backend that comes back is picked again
+        proxy.backends[1].healthy = true;
+        assert_eq!(proxy.pick(), Some(1));
+        assert_eq!(proxy.pick(), Some(1));
+    }
+}
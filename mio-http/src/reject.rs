@@ -0,0 +1,118 @@
+// Connections turned away while the server is full
+// The 503 is written and our side is shut down, but the socket is kept until the client has
+// closed its side (or a short grace period is over). Closing a socket with unread data in it
+// sends a reset, which can throw away the 503 before the client has read it.
+
+use mio::{net::TcpStream, Interest, Registry, Token};
+use slab::Slab;
+use std::{
+    io::{self, Read, Write},
+    net::Shutdown,
+    time::{Duration, Instant},
+};
+
+// tokens of rejected sockets, above the connections and below the upstreams
+const REJECTED: usize = usize::MAX / 4;
+
+// how long a client gets to close its side after the 503
+const LINGER: Duration = Duration::from_secs(2);
+// more than this and further connections are closed straight away
+const MAX_LINGERING: usize = 256;
+
+// sent to clients that connect while the server is full
+static SERVICE_UNAVAILABLE: &str = "HTTP/1.1 503 Service Unavailable\r
+Content-Type: text/plain\r
+Content-Length: 5\r
+Retry-After: 1\r
+Connection: close\r
+\r
+busy
+";
+
+pub fn is_rejected(token: Token) -> bool {
+    token.0 >= REJECTED
+}
+
+pub struct Rejected {
+    sockets: Slab<(TcpStream, Instant)>,
+}
+
+impl Rejected {
+    pub fn new() -> Rejected {
+        Rejected {
+            sockets: Slab::new(),
+        }
+    }
+
+    // The send buffer of a fresh socket is empty, a short write here won't block.
+    pub fn reject(&mut self, registry: &Registry, mut socket: TcpStream, buffer: &mut [u8]) {
+        let _ = socket.write(SERVICE_UNAVAILABLE.as_bytes());
+        if socket.shutdown(Shutdown::Write).is_err() {
+            return;
+        }
+        if self.sockets.len() >= MAX_LINGERING {
+            // whatever the client sent so far is all we wait for
+            let _ = drain(&mut socket, buffer);
+            return;
+        }
+
+        let entry = self.sockets.vacant_entry();
+        let token = Token(REJECTED + entry.key());
+        if registry
+            .register(&mut socket, token, Interest::READABLE)
+            .is_ok()
+        {
+            entry.insert((socket, Instant::now() + LINGER));
+        }
+    }
+
+    // read and discard, the socket is dropped once the client has closed its side
+    pub fn ready(&mut self, registry: &Registry, token: Token, buffer: &mut [u8]) {
+        let key = token.0 - REJECTED;
+        let done = match self.sockets.get_mut(key) {
+            Some((socket, _)) => drain(socket, buffer),
+            None => return,
+        };
+        if done {
+            let (mut socket, _) = self.sockets.remove(key);
+            let _ = registry.deregister(&mut socket);
+        }
+    }
+
+    // close the sockets whose grace period is over
+    pub fn expire(&mut self, registry: &Registry) {
+        let now = Instant::now();
+        let expired: Vec<usize> = self
+            .sockets
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            let (mut socket, _) = self.sockets.remove(key);
+            let _ = registry.deregister(&mut socket);
+        }
+    }
+
+    // how long the event loop may sleep before the next socket expires
+    pub fn timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.sockets
+            .iter()
+            .map(|(_, (_, deadline))| deadline.saturating_duration_since(now))
+            .min()
+    }
+}
+
+// true once the client has closed its side (or the socket failed)
+fn drain(socket: &mut TcpStream, buffer: &mut [u8]) -> bool {
+    loop {
+        match socket.read(buffer) {
+            Ok(0) => return true,
+            Ok(_) => continue,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return true,
+        }
+    }
+}
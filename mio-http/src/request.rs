@@ -0,0 +1,180 @@
+// A very small HTTP/1.x request head parser
+// It only understands what the event loop needs: the request line and the headers.
+// Bodies are delimited by Content-Length (no chunked request bodies).
+
+// the most a client can make the server buffer for one request
+pub const MAX_HEAD_SIZE: usize = 16 * 1024;
+pub const MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+
+pub struct Request {
+    pub method: String,
+    pub path: String, // raw request target, including any query string
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    // header names are case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // HTTP/1.1 defaults to keep-alive, HTTP/1.0 defaults to close
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or("");
+        let has = |token: &str| {
+            connection
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        };
+        if self.version == "HTTP/1.0" {
+            has("keep-alive")
+        } else {
+            !has("close")
+        }
+    }
+}
+
+fn is_double_crnl(window: &[u8]) -> bool {
+    window.len() >= 4
+        && (window[0] == b'\r')
+        && (window[1] == b'\n')
+        && (window[2] == b'\r')
+        && (window[3] == b'\n')
+}
+
+pub enum Parsed {
+    // the head (and body, if any) isn't fully buffered yet
+    Partial,
+    // a complete request and the number of bytes it occupied in the buffer
+    Complete(Request, usize),
+    // the bytes can never become a valid request
+    Invalid,
+    // the body is over MAX_BODY_SIZE
+    TooLarge,
+}
+
+pub fn parse(buf: &[u8]) -> Parsed {
+    let end = match buf.windows(4).position(is_double_crnl) {
+        Some(pos) => pos,
+        None if buf.len() > MAX_HEAD_SIZE => return Parsed::Invalid,
+        None => return Parsed::Partial,
+    };
+
+    let head = match std::str::from_utf8(&buf[..end]) {
+        Ok(head) => head,
+        Err(_) => return Parsed::Invalid,
+    };
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let (method, path, version) = match (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) {
+        (Some(m), Some(p), Some(v)) if v.starts_with("HTTP/1.") => (m, p, v),
+        _ => return Parsed::Invalid,
+    };
+
+    let mut headers = Vec::new();
+    for line in lines {
+        match line.find(':') {
+            Some(colon) => headers.push((
+                line[..colon].trim().to_string(),
+                line[colon + 1..].trim().to_string(),
+            )),
+            None => return Parsed::Invalid,
+        }
+    }
+
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        version: version.to_string(),
+        headers,
+        body: Vec::new(),
+    };
+
+    let body_len = match request.header("Content-Length") {
+        Some(len) => match len.parse::<usize>() {
+            Ok(len) => len,
+            Err(_) => return Parsed::Invalid,
+        },
+        None => 0,
+    };
+
+    if body_len > MAX_BODY_SIZE {
+        return Parsed::TooLarge;
+    }
+    // the length comes from the client, it can't be trusted not to overflow
+    let total = match (end + 4).checked_add(body_len) {
+        Some(total) => total,
+        None => return Parsed::TooLarge,
+    };
+    if buf.len() < total {
+        return Parsed::Partial;
+    }
+    request.body = buf[end + 4..total].to_vec();
+    Parsed::Complete(request, total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_with_body() {
+        let buf = b"POST /form HTTP/1.1\r\nContent-Length: 3\r\nconnection: close\r\n\r\nabcGET";
+        match parse(buf) {
+            Parsed::Complete(req, len) => {
+                assert_eq!(req.method, "POST");
+                assert_eq!(req.path, "/form");
+                assert_eq!(req.body, b"abc");
+                assert!(!req.keep_alive());
+                assert_eq!(len, buf.len() - 3);
+            }
+            _ => panic!("expected a complete request"),
+        }
+    }
+
+    #[test]
+    fn partial_requests() {
+        assert!(matches!(
+            parse(b"GET / HTTP/1.1\r\nHost: x\r\n"),
+            Parsed::Partial
+        ));
+        let buf = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc";
+        assert!(matches!(parse(buf), Parsed::Partial));
+    }
+
+    #[test]
+    fn invalid_requests() {
+        assert!(matches!(parse(b"GET /\r\n\r\n"), Parsed::Invalid));
+        assert!(matches!(
+            parse(b"GET / HTTP/1.1\r\nno colon\r\n\r\n"),
+            Parsed::Invalid
+        ));
+        let buf = b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n";
+        assert!(matches!(parse(buf), Parsed::Invalid));
+        // a head that never ends
+        let mut buf = b"GET / HTTP/1.1\r\n".to_vec();
+        buf.resize(MAX_HEAD_SIZE + 1, b'a');
+        assert!(matches!(parse(&buf), Parsed::Invalid));
+    }
+
+    #[test]
+    fn oversized_bodies() {
+        let huge = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+        assert!(matches!(parse(huge.as_bytes()), Parsed::TooLarge));
+        let over = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert!(matches!(parse(over.as_bytes()), Parsed::TooLarge));
+    }
+}
@@ -0,0 +1,431 @@
+use crate::compress::{self, Encoder, Encoding};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    sync::mpsc::{Receiver, TryRecvError},
+};
+
+// how much of a file body is pulled into memory at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    // an open file positioned at the first byte to send, and how many bytes to send
+    File(File, u64),
+    // chunks produced over time by a handler, see `stream::Streams`
+    Stream(Receiver<Vec<u8>>),
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+    // a file body that gets compressed on its way out
+    pub encoding: Option<Encoding>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Empty,
+            encoding: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: impl ToString) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    // Switch to a compressed representation of the body
+    // A file body can only be compressed if the client can take a chunked body.
+    pub fn encode(mut self, encoding: Encoding, chunked_ok: bool) -> Response {
+        match self.body {
+            Body::Bytes(ref bytes) => {
+                let compressed = compress::encode_all(encoding, bytes);
+                self.remove_header("Content-Length");
+                self = self.body(Body::Bytes(compressed));
+            }
+            Body::File(..) if chunked_ok => {
+                self.remove_header("Content-Length");
+                self.encoding = Some(encoding);
+                self = self.header("Transfer-Encoding", "chunked");
+            }
+            _ => return self,
+        }
+
+        // the compressed representation needs its own validator
+        for (name, value) in self.headers.iter_mut() {
+            if name.eq_ignore_ascii_case("ETag") && value.ends_with('"') {
+                value.insert_str(value.len() - 1, &format!("-{}", encoding.name()));
+            }
+        }
+        self.header("Content-Encoding", encoding.name())
+    }
+
+    pub fn body(self, body: Body) -> Response {
+        let mut res = match &body {
+            Body::Empty => self.header("Content-Length", 0),
+            Body::Bytes(bytes) => self.header("Content-Length", bytes.len()),
+            Body::File(_, len) => self.header("Content-Length", len),
+            Body::Stream(_) => self.header("Transfer-Encoding", "chunked"),
+        };
+        res.body = body;
+        res
+    }
+
+    // A streamed body for a client that can't take chunked encoding (HTTP/1.0)
+    // Its chunks go out as they are and closing the connection ends the body.
+    pub fn close_delimited(mut self) -> Response {
+        if let Body::Stream(_) = self.body {
+            self.remove_header("Transfer-Encoding");
+        }
+        self
+    }
+
+    // a short plain text response, used for errors
+    pub fn text(status: u16, text: &str) -> Response {
+        Response::new(status)
+            .header("Content-Type", "text/plain")
+            .body(Body::Bytes(format!("{}\n", text).into_bytes()))
+    }
+
+    // serialise the status line and headers and queue the body behind them
+    pub fn into_outgoing(self, head_only: bool, keep_alive: bool) -> Outgoing {
+        let chunked = self.header_value("Transfer-Encoding").is_some();
+        // without framing, only the close tells the client where the body ends
+        let keep_alive = keep_alive && (chunked || !matches!(self.body, Body::Stream(_)));
+        let mut buf = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            buf.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !keep_alive {
+            buf.push_str("Connection: close\r\n");
+        }
+        buf.push_str("\r\n");
+
+        let mut buf = buf.into_bytes();
+        let mut file = None;
+        let mut stream = None;
+        match self.body {
+            _ if head_only => {}
+            Body::Empty => {}
+            Body::Bytes(bytes) => buf.extend_from_slice(&bytes),
+            Body::File(f, len) => file = Some((f, len)),
+            Body::Stream(rx) => stream = Some(rx),
+        }
+        let encoder = match (&file, self.encoding) {
+            (Some(_), Some(encoding)) => Some(Encoder::new(encoding)),
+            _ => None,
+        };
+
+        Outgoing {
+            buf,
+            pos: 0,
+            file,
+            encoder,
+            open: stream.is_some(),
+            stream,
+            chunked,
+            keep_alive,
+            status: self.status,
+            written: 0,
+        }
+    }
+}
+
+// Bytes waiting to go out on a socket
+// File bodies are read a chunk at a time, only once the previous chunk has been written,
+// so a large file never sits in memory and a slow client never blocks the event loop.
+pub struct Outgoing {
+    buf: Vec<u8>,
+    pos: usize,
+    file: Option<(File, u64)>,
+    // compresses the file body, whose chunks then go out with chunked framing
+    encoder: Option<Encoder>,
+    // a body that's still being produced, each message is sent as one chunk
+    stream: Option<Receiver<Vec<u8>>>,
+    // the stream's chunks are framed, otherwise they go out as they are (see `close_delimited`)
+    chunked: bool,
+    pub keep_alive: bool,
+    pub status: u16,
+    // bytes handed to the socket so far
+    pub written: u64,
+    // more bytes are still to be pushed (e.g. a proxied response that's still arriving)
+    open: bool,
+}
+
+impl Outgoing {
+    // an already serialised keep-alive response
+    pub fn raw(status: u16, bytes: &[u8]) -> Outgoing {
+        Outgoing {
+            buf: bytes.to_vec(),
+            pos: 0,
+            file: None,
+            encoder: None,
+            stream: None,
+            chunked: false,
+            keep_alive: true,
+            status,
+            written: 0,
+            open: false,
+        }
+    }
+
+    // A response whose bytes are pushed in as they become available, until `finish` is called
+    // The status is filled in once it's known.
+    pub fn streaming(keep_alive: bool) -> Outgoing {
+        Outgoing {
+            keep_alive,
+            open: true,
+            ..Outgoing::raw(0, &[])
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        if self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+        }
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn finish(&mut self) {
+        self.open = false;
+    }
+
+    // bytes buffered but not yet written
+    pub fn pending(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub fn is_stream(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    // write as much as the socket accepts
+    // Ok(true) means everything was written, Ok(false) means the socket would block
+    // or a streaming response is waiting for more bytes to be pushed
+    pub fn write_to<W: Write>(&mut self, socket: &mut W) -> io::Result<bool> {
+        loop {
+            if self.pos == self.buf.len() && !self.refill()? {
+                return Ok(!self.open);
+            }
+            match socket.write(&self.buf[self.pos..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.pos += n;
+                    self.written += n as u64;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // load the next chunk of the body, false when there's nothing left (for now)
+    fn refill(&mut self) -> io::Result<bool> {
+        if self.stream.is_some() {
+            return Ok(self.pull_stream());
+        }
+        loop {
+            let (file, remaining) = match self.file.as_mut() {
+                Some((file, remaining)) if *remaining > 0 => (file, remaining),
+                _ => return self.finish_encoding(),
+            };
+
+            let want = (*remaining).min(CHUNK_SIZE as u64) as usize;
+            self.buf.resize(want, 0);
+            let n = file.read(&mut self.buf)?;
+            if n == 0 {
+                // the file shrunk underneath us, the advertised length can't be honoured
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.buf.truncate(n);
+            self.pos = 0;
+            *remaining -= n as u64;
+
+            let encoder = match self.encoder.as_mut() {
+                Some(encoder) => encoder,
+                None => return Ok(true),
+            };
+            // the compressor may hold on to small inputs, keep reading until it has output
+            let compressed = encoder.encode(&self.buf)?;
+            self.buf.clear();
+            push_chunk(&mut self.buf, &compressed);
+            if !self.buf.is_empty() {
+                return Ok(true);
+            }
+        }
+    }
+
+    // take the next chunk a handler has sent, if there's one
+    // Empty chunks are skipped, they'd end a chunked body early.
+    fn pull_stream(&mut self) -> bool {
+        self.buf.clear();
+        self.pos = 0;
+        while self.buf.is_empty() {
+            let received = match self.stream.as_ref() {
+                Some(rx) => rx.try_recv(),
+                None => return false,
+            };
+            match received {
+                Ok(chunk) if self.chunked => push_chunk(&mut self.buf, &chunk),
+                Ok(chunk) => self.buf.extend_from_slice(&chunk),
+                // the handler hasn't sent anything new, the waker tells the loop when it does
+                Err(TryRecvError::Empty) => return false,
+                // the handler dropped its sender, that's the end of the body
+                Err(TryRecvError::Disconnected) => {
+                    if self.chunked {
+                        self.buf.extend_from_slice(b"0\r\n\r\n");
+                    }
+                    self.stream = None;
+                    self.open = false;
+                    return !self.buf.is_empty();
+                }
+            }
+        }
+        true
+    }
+
+    // flush the compressor and terminate the chunked body
+    fn finish_encoding(&mut self) -> io::Result<bool> {
+        let encoder = match self.encoder.take() {
+            Some(encoder) => encoder,
+            None => return Ok(false),
+        };
+        let compressed = encoder.finish()?;
+        self.buf.clear();
+        self.pos = 0;
+        push_chunk(&mut self.buf, &compressed);
+        self.buf.extend_from_slice(b"0\r\n\r\n");
+        Ok(true)
+    }
+}
+
+// Frame some bytes as one chunk of a chunked body
+// Nothing is written for empty input, an empty chunk would end the body.
+pub fn push_chunk(buf: &mut Vec<u8>, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    buf.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    buf.extend_from_slice(data);
+    buf.extend_from_slice(b"\r\n");
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        416 => "Range Not Satisfiable",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    // write everything that's ready, the bool is whether the response is complete
+    fn drain(out: &mut Outgoing) -> (String, bool) {
+        let mut socket = Vec::new();
+        let done = out.write_to(&mut socket).unwrap();
+        (String::from_utf8(socket).unwrap(), done)
+    }
+
+    #[test]
+    fn chunks() {
+        let mut buf = Vec::new();
+        push_chunk(&mut buf, b"hello");
+        push_chunk(&mut buf, b"");
+        push_chunk(&mut buf, &[b'x'; 26]);
+        let expected = format!("5\r\nhello\r\n1a\r\n{}\r\n", "x".repeat(26));
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+    }
+
+    #[test]
+    fn chunked_stream() {
+        let (tx, rx) = sync_channel(4);
+        let mut out = Response::new(200)
+            .body(Body::Stream(rx))
+            .into_outgoing(false, true);
+        assert!(out.keep_alive);
+
+        let (head, done) = drain(&mut out);
+        assert!(!done);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!head.contains("Connection: close"));
+
+        tx.send(b"abc".to_vec()).unwrap();
+        tx.send(Vec::new()).unwrap();
+        tx.send(b"0123456789abcdef".to_vec()).unwrap();
+        assert_eq!(
+            drain(&mut out),
+            ("3\r\nabc\r\n10\r\n0123456789abcdef\r\n".to_string(), false)
+        );
+
+        drop(tx);
+        assert_eq!(drain(&mut out), ("0\r\n\r\n".to_string(), true));
+    }
+
+    #[test]
+    fn close_delimited_stream() {
+        let (tx, rx) = sync_channel(4);
+        let mut out = Response::new(200)
+            .body(Body::Stream(rx))
+            .close_delimited()
+            .into_outgoing(false, true);
+        assert!(!out.keep_alive);
+
+        let (head, done) = drain(&mut out);
+        assert!(!done);
+        assert!(!head.contains("Transfer-Encoding"));
+        assert!(head.ends_with("Connection: close\r\n\r\n"));
+
+        tx.send(b"abc".to_vec()).unwrap();
+        tx.send(b"def".to_vec()).unwrap();
+        assert_eq!(drain(&mut out), ("abcdef".to_string(), false));
+
+        // no terminating chunk, the close ends the body
+        drop(tx);
+        assert_eq!(drain(&mut out), (String::new(), true));
+    }
+
+    #[test]
+    fn head_only() {
+        let (_tx, rx) = sync_channel(4);
+        let mut out = Response::new(200)
+            .body(Body::Stream(rx))
+            .into_outgoing(true, true);
+        let (head, done) = drain(&mut out);
+        assert!(done);
+        assert!(head.ends_with("Transfer-Encoding: chunked\r\n\r\n"));
+    }
+}
@@ -0,0 +1,126 @@
+// Streaming response bodies
+//
+// A handler that can't produce its whole body up front (a long report, server-sent events) gets a
+// `BodySender` it can move to another thread. Every chunk it sends goes out as one chunk of a
+// `Transfer-Encoding: chunked` body, and dropping the sender ends the body. Sending wakes the poll
+// through a `mio::Waker` and queues the connection's token, so the event loop knows which
+// connections have new data without looking at all of them.
+//
+// Every sender usually lives on a thread of its own, so only MAX_STREAMS of them may be open at
+// once and further streaming requests are turned away.
+
+use crate::response::Body;
+use mio::{Registry, Token, Waker};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
+    Arc,
+};
+
+// The waker's token, kept clear of the client and upstream token ranges
+pub const WAKER: Token = Token(usize::MAX);
+
+// how many chunks may be queued before a sender has to wait for the client to catch up
+const QUEUED_CHUNKS: usize = 16;
+// how many streaming bodies may be produced at the same time
+pub const MAX_STREAMS: usize = 64;
+
+pub struct Streams {
+    waker: Arc<Waker>,
+    ready_tx: Sender<Token>,
+    ready_rx: Receiver<Token>,
+    // senders that haven't been dropped yet
+    open: Arc<AtomicUsize>,
+}
+
+impl Streams {
+    pub fn new(registry: &Registry) -> Streams {
+        let (ready_tx, ready_rx) = channel();
+        Streams {
+            waker: Arc::new(Waker::new(registry, WAKER).unwrap()),
+            ready_tx,
+            ready_rx,
+            open: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // A streaming body for the connection at `token` and the sender that feeds it,
+    // None while MAX_STREAMS bodies are still being produced
+    pub fn open(&self, token: Token) -> Option<(BodySender, Body)> {
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < MAX_STREAMS).then(|| open + 1)
+            })
+            .ok()?;
+        let (chunks_tx, chunks_rx) = sync_channel(QUEUED_CHUNKS);
+        let sender = BodySender {
+            token,
+            chunks: Some(chunks_tx),
+            ready: self.ready_tx.clone(),
+            waker: Arc::clone(&self.waker),
+            open: Arc::clone(&self.open),
+        };
+        Some((sender, Body::Stream(chunks_rx)))
+    }
+
+    // tokens of the connections that were sent something since the last call
+    pub fn ready(&self) -> impl Iterator<Item = Token> + '_ {
+        self.ready_rx.try_iter()
+    }
+}
+
+pub struct BodySender {
+    token: Token,
+    chunks: Option<SyncSender<Vec<u8>>>,
+    ready: Sender<Token>,
+    waker: Arc<Waker>,
+    open: Arc<AtomicUsize>,
+}
+
+// the connection is gone, nobody will read the rest of the body
+#[derive(Debug)]
+pub struct Closed;
+
+impl BodySender {
+    // Queue a chunk, blocking while the client is QUEUED_CHUNKS behind
+    pub fn send(&self, chunk: impl Into<Vec<u8>>) -> Result<(), Closed> {
+        let chunks = self.chunks.as_ref().unwrap();
+        chunks.send(chunk.into()).map_err(|_| Closed)?;
+        self.wake()
+    }
+
+    fn wake(&self) -> Result<(), Closed> {
+        self.ready.send(self.token).map_err(|_| Closed)?;
+        self.waker.wake().map_err(|_| Closed)
+    }
+}
+
+impl Drop for BodySender {
+    // The event loop has to hear about the end of the body too
+    // The channel is closed before the wakeup, so the loop can't miss the disconnect.
+    fn drop(&mut self) {
+        self.chunks.take();
+        let _ = self.wake();
+        self.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::Poll;
+
+    #[test]
+    fn open_streams_are_capped() {
+        let poll = Poll::new().unwrap();
+        let streams = Streams::new(poll.registry());
+        let mut senders: Vec<_> = (0..MAX_STREAMS)
+            .map(|i| streams.open(Token(i)).unwrap().0)
+            .collect();
+        assert!(streams.open(Token(MAX_STREAMS)).is_none());
+
+        // a finished body makes room for the next one
+        senders.pop();
+        assert!(streams.open(Token(MAX_STREAMS)).is_some());
+    }
+}
@@ -0,0 +1,305 @@
+// Websocket conformance tests, after the Autobahn testsuite's categories
+// Every test connects a scripted client (see testing.rs) to the test server and checks what comes
+// back: the echo of valid messages, or a close frame with the right code followed by the end of
+// the TCP connection for protocol violations.
+// The case numbers in the comments are Autobahn's.
+
+use crate::frame::{self, Frame, Message, Opcode};
+use crate::testing::{Client, MASK};
+
+// a frame header with a reserved opcode or an unmasked/oversized payload, which Frame can't encode
+fn raw_header(first: u8, len: u64) -> Vec<u8> {
+    let mut header = vec![first, 0x80 | 127];
+    header.extend_from_slice(&len.to_be_bytes());
+    header.extend_from_slice(&MASK);
+    header
+}
+
+// 1.1.x, 1.2.x: text and binary messages of every length encoding are echoed
+#[test]
+fn framing_echo() {
+    let mut client = Client::connect();
+    for &len in &[0, 1, 125, 126, 127, 128, 65535, 65536, 1 << 20] {
+        let text = vec![b'*'; len];
+        client.send_text(&text);
+        client.expect_echo(&text);
+
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        client.send_fragment(Opcode::Binary, &data, true);
+        assert_eq!(client.recv(), Message::Binary(data));
+    }
+    client.send(Frame::close(frame::CLOSE_NORMAL, ""));
+    client.expect_close(frame::CLOSE_NORMAL);
+}
+
+#[test]
+fn framing_violations() {
+    // clients have to mask
+    let mut client = Client::connect();
+    client.send_raw(&[0x81, 0x02, b'h', b'i']);
+    client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+
+    // 4.1.x, 4.2.x: reserved opcodes, for data and control frames
+    for op in (0x3..=0x7).chain(0xb..=0xf) {
+        let mut client = Client::connect();
+        client.send_raw(&[0x80 | op, 0x80, MASK[0], MASK[1], MASK[2], MASK[3]]);
+        client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+    }
+
+    // 4.1.3: the messages before the bad frame are still answered
+    let mut client = Client::connect();
+    client.send_text(b"hello");
+    client.send_raw(&[0x85, 0x80, MASK[0], MASK[1], MASK[2], MASK[3]]);
+    client.send_fragment(Opcode::Ping, b"", true);
+    client.expect_echo(b"hello");
+    client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+
+    // the length alone is enough to refuse a message
+    let mut client = Client::connect();
+    client.send_raw(&raw_header(0x82, frame::MAX_MESSAGE_SIZE as u64 + 1));
+    client.expect_close(frame::CLOSE_TOO_BIG);
+
+    // 64 bit lengths can't use the most significant bit
+    let mut client = Client::connect();
+    client.send_raw(&raw_header(0x82, 1 << 63));
+    client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+}
+
+// 2.1 - 2.4, 2.10: pings are answered with their payload, in order
+#[test]
+fn pings() {
+    let mut client = Client::connect();
+    let payloads: [&[u8]; 4] = [
+        b"",
+        b"Hello, world!",
+        &[0x00, 0xff, 0xfe, 0xfd],
+        &[0xfe; 125],
+    ];
+    for payload in payloads.iter() {
+        client.send_fragment(Opcode::Ping, payload, true);
+        assert_eq!(client.recv(), Message::Pong(payload.to_vec()));
+    }
+    for i in 0..10_u8 {
+        client.send_fragment(Opcode::Ping, &[i], true);
+    }
+    for i in 0..10_u8 {
+        assert_eq!(client.recv(), Message::Pong(vec![i]));
+    }
+
+    // 2.7, 2.8: unsolicited pongs are ignored
+    client.send_fragment(Opcode::Pong, b"", true);
+    client.send_fragment(Opcode::Pong, b"unsolicited", true);
+    client.send_text(b"after the pongs");
+    client.expect_echo(b"after the pongs");
+}
+
+#[test]
+fn ping_violations() {
+    // 2.5: control frames carry at most 125 bytes
+    let mut client = Client::connect();
+    client.send_fragment(Opcode::Ping, &[0xfe; 126], true);
+    client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+
+    // 5.1, 5.2: control frames can't be fragmented
+    for &opcode in &[Opcode::Ping, Opcode::Pong] {
+        let mut client = Client::connect();
+        client.send_fragment(opcode, b"frag", false);
+        client.send_fragment(Opcode::Continuation, b"ment", true);
+        client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+    }
+}
+
+// 3.x: without an extension negotiated, frames with reserved bits set fail the connection
+#[test]
+fn reserved_bits() {
+    let opcodes = [Opcode::Text, Opcode::Binary, Opcode::Ping, Opcode::Close];
+    for rsv in 1..=7 {
+        for &opcode in &opcodes {
+            let mut client = Client::connect();
+            client.send_text(b"before");
+            client.send(Frame {
+                fin: true,
+                rsv,
+                opcode,
+                payload: b"\x03\xe8".to_vec(),
+            });
+            client.send_fragment(Opcode::Ping, b"", true);
+            // what came before the bad frame is still answered, nothing after it
+            client.expect_echo(b"before");
+            client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+        }
+    }
+}
+
+// 5.3 - 5.20: fragmented messages are echoed whole, control frames may come in between
+#[test]
+fn fragmentation() {
+    let mut client = Client::connect();
+    client.send_fragment(Opcode::Text, b"frag", false);
+    client.send_fragment(Opcode::Continuation, b"ment", true);
+    client.expect_echo(b"fragment");
+
+    client.send_fragment(Opcode::Binary, &[1, 2], false);
+    client.send_fragment(Opcode::Continuation, &[3], false);
+    client.send_fragment(Opcode::Continuation, &[4, 5], true);
+    assert_eq!(client.recv(), Message::Binary(vec![1, 2, 3, 4, 5]));
+
+    // a ping in the middle of a message is answered right away
+    client.send_fragment(Opcode::Text, b"frag", false);
+    client.send_fragment(Opcode::Ping, b"in between", true);
+    client.send_fragment(Opcode::Pong, b"", true);
+    client.send_fragment(Opcode::Continuation, b"ment", true);
+    assert_eq!(client.recv(), Message::Pong(b"in between".to_vec()));
+    client.expect_echo(b"fragment");
+
+    // empty fragments, and a message one byte at a time
+    client.send_fragment(Opcode::Text, b"", false);
+    client.send_fragment(Opcode::Continuation, b"", false);
+    client.send_fragment(Opcode::Continuation, b"", true);
+    client.expect_echo(b"");
+    let message = b"one byte per fragment";
+    client.send_fragment(Opcode::Text, &message[..1], false);
+    for b in message[1..message.len() - 1].iter() {
+        client.send_fragment(Opcode::Continuation, &[*b], false);
+    }
+    client.send_fragment(Opcode::Continuation, &message[message.len() - 1..], true);
+    client.expect_echo(message);
+}
+
+#[test]
+fn fragmentation_violations() {
+    // 5.9 - 5.14: a continuation with no message to continue
+    for &fin in &[true, false] {
+        let mut client = Client::connect();
+        client.send_fragment(Opcode::Continuation, b"nothing", fin);
+        client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+    }
+
+    // 5.18: a new message before the fragmented one has ended
+    let mut client = Client::connect();
+    client.send_fragment(Opcode::Text, b"first", false);
+    client.send_fragment(Opcode::Text, b"second", true);
+    client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+
+    // a fragmented close
+    let mut client = Client::connect();
+    client.send_fragment(Opcode::Close, b"\x03\xe8", false);
+    client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+}
+
+// 6.2 - 6.4: text is checked as UTF-8, sequences may be split over fragments
+#[test]
+fn utf8_valid() {
+    let texts: [&str; 4] = [
+        "κόσμε",
+        "Hello-µ@ßöäüàá-UTF-8!!",
+        "\u{10ffff} \u{ffff} \u{7ff} \u{7f} \u{0}",
+        "𝄞 and 🎉 are four bytes",
+    ];
+    let mut client = Client::connect();
+    for text in texts.iter() {
+        let bytes = text.as_bytes();
+        client.send_text(bytes);
+        client.expect_echo(bytes);
+
+        // split at every byte, in the middle of sequences too
+        for at in 1..bytes.len() {
+            client.send_fragment(Opcode::Text, &bytes[..at], false);
+            client.send_fragment(Opcode::Continuation, &bytes[at..], true);
+            client.expect_echo(bytes);
+        }
+    }
+}
+
+#[test]
+fn utf8_invalid() {
+    let invalid: [&[u8]; 8] = [
+        // 6.3.1: a surrogate half in the middle of valid text
+        b"\xce\xba\xe1\xbd\xb9\xcf\x83\xce\xbc\xce\xb5\xed\xa0\x80edited",
+        b"\xc0\xaf",             // an overlong '/'
+        b"\xf4\x90\x80\x80",     // past U+10FFFF
+        b"\x80",                 // a lone continuation byte
+        b"\xfe",                 // never valid
+        b"\xce",                 // cut off at the end of the message
+        b"\xed\x9f\xbf\xed\xa0", // valid, then a cut off surrogate
+        b"\xf8\x88\x80\x80\x80", // 5 byte sequences don't exist
+    ];
+    for bytes in invalid.iter() {
+        let mut client = Client::connect();
+        client.send_text(bytes);
+        client.expect_close(frame::CLOSE_INVALID_DATA);
+    }
+
+    // 6.4.x: a bad fragment fails the message before it's finished
+    let mut client = Client::connect();
+    client.send_fragment(
+        Opcode::Text,
+        b"\xce\xba\xe1\xbd\xb9\xcf\x83\xce\xbc\xce\xb5",
+        false,
+    );
+    client.send_fragment(Opcode::Continuation, b"\xf4\x90\x80\x80", false);
+    client.expect_close(frame::CLOSE_INVALID_DATA);
+
+    // binary messages aren't checked
+    let mut client = Client::connect();
+    client.send_fragment(Opcode::Binary, b"\xc0\xaf", true);
+    assert_eq!(client.recv(), Message::Binary(b"\xc0\xaf".to_vec()));
+}
+
+// 7.1 - 7.3, 7.7: the server answers a close with the same code and closes the connection
+#[test]
+fn close_handshake() {
+    let codes = [
+        1000, 1001, 1002, 1003, 1007, 1008, 1009, 1010, 1011, 3000, 3999, 4000, 4999,
+    ];
+    for &code in &codes {
+        let mut client = Client::connect();
+        client.send(Frame::close(code, "bye"));
+        client.expect_close(code);
+    }
+
+    // no status at all is answered with a normal close
+    let mut client = Client::connect();
+    client.send_fragment(Opcode::Close, b"", true);
+    client.expect_close(frame::CLOSE_NORMAL);
+
+    // a reason as long as a control frame allows
+    let mut client = Client::connect();
+    client.send(Frame::close(frame::CLOSE_NORMAL, &"*".repeat(123)));
+    client.expect_close(frame::CLOSE_NORMAL);
+
+    // 7.1.2 - 7.1.5: nothing after the close is answered
+    let mut client = Client::connect();
+    client.send_text(b"before");
+    client.send(Frame::close(frame::CLOSE_NORMAL, ""));
+    client.send_text(b"after");
+    client.send_fragment(Opcode::Ping, b"after", true);
+    client.expect_echo(b"before");
+    client.expect_close(frame::CLOSE_NORMAL);
+}
+
+#[test]
+fn close_violations() {
+    // 7.3.2: a status code has two bytes
+    let mut client = Client::connect();
+    client.send_fragment(Opcode::Close, b"\x03", true);
+    client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+
+    // 7.9.x: codes that are reserved, or must never be sent
+    for &code in &[
+        0, 999, 1004, 1005, 1006, 1016, 1100, 2000, 2999, 5000, 65535,
+    ] {
+        let mut client = Client::connect();
+        client.send(Frame::close(code, ""));
+        client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+    }
+
+    // 7.5.1: the reason has to be UTF-8
+    let mut client = Client::connect();
+    client.send_fragment(
+        Opcode::Close,
+        b"\x03\xe8\xce\xba\xe1\xbd\xb9\xed\xa0\x80",
+        true,
+    );
+    client.expect_close(frame::CLOSE_INVALID_DATA);
+}
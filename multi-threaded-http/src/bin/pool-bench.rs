@@ -0,0 +1,126 @@
+// Throughput of the work-stealing pool at different worker counts
+// cargo run --release -p multi-threaded-http --bin pool-bench -- --jobs 1000000 --work 200
+//
+// Every run submits the same number of small CPU-bound jobs and times how long the pool takes to
+// get through all of them. The same jobs also go through a pool of workers sharing one
+// Mutex<Receiver> queue (how the pool used to work) for comparison.
+
+use multi_threaded_http::pool::ThreadPool;
+use std::{
+    hint::black_box,
+    process,
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+type Runnable = Box<dyn FnOnce() + Send + 'static>;
+
+struct Options {
+    jobs: usize,
+    work: u64, // loop iterations in every job
+}
+
+impl Options {
+    fn from_args() -> Options {
+        let mut options = Options {
+            jobs: 1_000_000,
+            work: 200,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let value = args.next().and_then(|v| v.parse().ok());
+            match (arg.as_str(), value) {
+                ("--jobs", Some(v)) => options.jobs = v as usize,
+                ("--work", Some(v)) => options.work = v,
+                _ => {
+                    eprintln!("usage: pool-bench [--jobs N] [--work ITERATIONS]");
+                    process::exit(2);
+                }
+            }
+        }
+        options
+    }
+}
+
+// a little bit of arithmetic the optimizer can't throw away
+fn job(work: u64) {
+    let mut x = 0_u64;
+    for i in 0..work {
+        x = black_box(x.wrapping_mul(31).wrapping_add(i));
+    }
+    black_box(x);
+}
+
+fn work_stealing(workers: usize, options: &Options) -> Duration {
+    let mut pool = ThreadPool::new(workers);
+    let work = options.work;
+    let started = Instant::now();
+    for _ in 0..options.jobs {
+        pool.submit(move || job(work));
+    }
+    pool.join(Duration::from_secs(3600)).unwrap();
+    started.elapsed()
+}
+
+// the old design: every worker locks the same receiver to take a job
+fn shared_queue(workers: usize, options: &Options) -> Duration {
+    let (sender, receiver) = channel::<Runnable>();
+    let receiver: Arc<Mutex<Receiver<Runnable>>> = Arc::new(Mutex::new(receiver));
+    let threads: Vec<_> = (0..workers)
+        .map(|_| {
+            let receiver = Arc::clone(&receiver);
+            thread::spawn(move || loop {
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    Ok(f) => f(),
+                    Err(_) => break,
+                }
+            })
+        })
+        .collect();
+
+    let work = options.work;
+    let started = Instant::now();
+    for _ in 0..options.jobs {
+        sender.send(Box::new(move || job(work))).unwrap();
+    }
+    drop(sender);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    started.elapsed()
+}
+
+fn main() {
+    let options = Options::from_args();
+    let cores = thread::available_parallelism().map_or(4, |n| n.get());
+
+    let mut counts = vec![1];
+    while counts[counts.len() - 1] * 2 <= cores * 2 {
+        counts.push(counts[counts.len() - 1] * 2);
+    }
+
+    println!(
+        "{} jobs of {} iterations, {} cores",
+        options.jobs, options.work, cores
+    );
+    println!(
+        "{:>8} {:>16} {:>16} {:>8}",
+        "workers", "stealing jobs/s", "mutex jobs/s", "speedup"
+    );
+    for workers in counts {
+        let stealing = options.jobs as f64 / work_stealing(workers, &options).as_secs_f64();
+        let mutex = options.jobs as f64 / shared_queue(workers, &options).as_secs_f64();
+        println!(
+            "{:>8} {:>16.0} {:>16.0} {:>7.2}x",
+            workers,
+            stealing,
+            mutex,
+            stealing / mutex
+        );
+    }
+}
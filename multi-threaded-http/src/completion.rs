@@ -0,0 +1,60 @@
+// Getting finished handler jobs back to the event loop
+// The loop awaits a job's JobHandle like a future, with a task waker that queues the connection's
+// token and wakes the poll through a `mio::Waker`. On the WAKER event the loop polls the handles of
+// the queued connections again, which by then are ready, so it never waits on a job itself.
+
+use mio::{Registry, Token, Waker};
+use multi_threaded_http::pool::{JobHandle, JobResult};
+use std::{
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Wake},
+};
+
+// The waker's token, kept clear of the connection tokens
+pub const WAKER: Token = Token(usize::MAX);
+
+pub struct Completions {
+    queue: Mutex<Vec<Token>>,
+    waker: Waker,
+}
+
+impl Completions {
+    pub fn new(registry: &Registry) -> Arc<Completions> {
+        Arc::new(Completions {
+            queue: Mutex::new(Vec::new()),
+            waker: Waker::new(registry, WAKER).unwrap(),
+        })
+    }
+
+    // tokens of the connections whose jobs finished since the last call
+    pub fn take(&self) -> Vec<Token> {
+        mem::take(&mut *self.queue.lock().unwrap())
+    }
+
+    // Check on the job the connection at `token` is waiting for, without blocking
+    // If it isn't done yet the token is queued (and the loop woken) as soon as it is.
+    pub fn poll<T>(self: &Arc<Self>, token: Token, job: &mut JobHandle<T>) -> Poll<JobResult<T>> {
+        let waker = Arc::new(Completed {
+            token,
+            completions: Arc::clone(self),
+        })
+        .into();
+        Pin::new(job).poll(&mut Context::from_waker(&waker))
+    }
+}
+
+struct Completed {
+    token: Token,
+    completions: Arc<Completions>,
+}
+
+impl Wake for Completed {
+    fn wake(self: Arc<Self>) {
+        self.completions.queue.lock().unwrap().push(self.token);
+        // the loop only misses this if it's gone, and then nobody cares about the result
+        let _ = self.completions.waker.wake();
+    }
+}
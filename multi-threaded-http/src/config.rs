@@ -0,0 +1,37 @@
+// Command line options
+// multi-threaded-http [--addr 127.0.0.1:9000] [--allow-origin http://localhost:9000]...
+// Without --allow-origin, upgrades are only accepted from pages served at the server's own address.
+
+use std::process;
+
+pub struct Config {
+    pub addr: String,
+    // the sites whose pages may open websockets, see Handshake::allow_origin
+    pub origins: Vec<String>,
+}
+
+impl Config {
+    pub fn from_args() -> Config {
+        let mut config = Config {
+            addr: "127.0.0.1:9000".to_string(),
+            origins: Vec::new(),
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().unwrap_or_else(|| usage(&arg));
+            match arg.as_str() {
+                "--addr" => config.addr = value(),
+                "--allow-origin" => config.origins.push(value()),
+                _ => usage(&arg),
+            }
+        }
+        config
+    }
+}
+
+fn usage(arg: &str) -> ! {
+    eprintln!("unexpected argument: {}", arg);
+    eprintln!("usage: multi-threaded-http [--addr ADDR] [--allow-origin ORIGIN]...");
+    process::exit(2);
+}
@@ -0,0 +1,633 @@
+// The permessage-deflate websocket extension (RFC 7692)
+// Messages are compressed with raw deflate, each one ending in a sync flush whose trailing
+// 00 00 ff ff is left off on the wire. The compressed message is sent with RSV1 set on its first
+// frame. Unless a side asked for "no context takeover", its compressor keeps its window between
+// messages, which is what makes repetitive messages (the same JSON shape over and over) small.
+
+use crate::frame::Error;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::fmt;
+
+// messages shorter than this go out uncompressed, the extension lets every message choose
+const MIN_COMPRESS_SIZE: usize = 64;
+
+// the end of a sync flush, removed by the sender and put back by the receiver
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// What was agreed on for a connection
+#[derive(Debug, PartialEq)]
+pub struct Params {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    // the server's compression window (the client may have asked for a smaller one)
+    pub server_max_window_bits: u8,
+}
+
+// Pick the first permessage-deflate offer among the extensions a client listed in its
+// Sec-WebSocket-Extensions headers that can be accepted, None if there's nothing to accept
+// (the connection goes on uncompressed)
+pub fn negotiate<'a>(mut offers: impl Iterator<Item = &'a str>) -> Option<Params> {
+    offers.find_map(Params::parse)
+}
+
+impl Params {
+    // One extension from a Sec-WebSocket-Extensions header, either a client's offer or the
+    // server's answer. None if it isn't permessage-deflate or asks for something unsupported.
+    pub fn parse(extension: &str) -> Option<Params> {
+        let mut parts = extension.split(';').map(str::trim);
+        if parts.next()? != "permessage-deflate" {
+            return None;
+        }
+
+        let mut params = Params {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: 15,
+        };
+        let mut seen = Vec::new();
+        for part in parts {
+            let mut kv = part.splitn(2, '=');
+            let name = kv.next()?.trim();
+            let value = kv.next().map(|v| v.trim().trim_matches('"'));
+            // a parameter given twice makes the offer invalid
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+
+            match (name, value) {
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                ("server_max_window_bits", Some(bits)) => {
+                    // zlib can't do raw deflate with a 256 byte window, offers asking for it are declined
+                    params.server_max_window_bits = window_bits(bits).filter(|b| *b >= 9)?;
+                }
+                // the client's window only matters to its own compressor, inflating works with any
+                ("client_max_window_bits", None) => {}
+                ("client_max_window_bits", Some(bits)) => {
+                    window_bits(bits)?;
+                }
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+
+    pub fn deflater(&self) -> Deflater {
+        Deflater {
+            inner: Compress::new_with_window_bits(
+                Compression::default(),
+                false,
+                self.server_max_window_bits,
+            ),
+            reset: self.server_no_context_takeover,
+        }
+    }
+
+    pub fn inflater(&self) -> Inflater {
+        Inflater {
+            inner: Decompress::new_with_window_bits(false, 15),
+            reset: self.client_no_context_takeover,
+            ended: false,
+        }
+    }
+}
+
+fn window_bits(value: &str) -> Option<u8> {
+    // no leading zeros or signs, just 8 to 15
+    match value.parse::<u8>() {
+        Ok(bits) if (8..=15).contains(&bits) && !value.starts_with('0') => Some(bits),
+        _ => None,
+    }
+}
+
+// The server's answer, for its Sec-WebSocket-Extensions header
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "permessage-deflate")?;
+        if self.server_no_context_takeover {
+            write!(f, "; server_no_context_takeover")?;
+        }
+        if self.client_no_context_takeover {
+            write!(f, "; client_no_context_takeover")?;
+        }
+        if self.server_max_window_bits < 15 {
+            write!(
+                f,
+                "; server_max_window_bits={}",
+                self.server_max_window_bits
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Deflater {
+    inner: Compress,
+    reset: bool, // no context takeover, every message starts from an empty window
+}
+
+impl Deflater {
+    // The compressed payload for a message, None if it isn't worth compressing
+    pub fn deflate(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < MIN_COMPRESS_SIZE {
+            return None;
+        }
+        let mut out = Vec::with_capacity(data.len() / 2 + 16);
+        let start = self.inner.total_in();
+        loop {
+            let consumed = (self.inner.total_in() - start) as usize;
+            // compressing into a Vec only fails on a broken stream, which would be a bug here
+            self.inner
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .unwrap();
+            let consumed = (self.inner.total_in() - start) as usize;
+            // the flush is complete once it didn't fill the space it had
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(out.capacity().max(64));
+        }
+        if out.ends_with(&TAIL) {
+            out.truncate(out.len() - TAIL.len());
+        }
+        if self.reset {
+            self.inner.reset();
+        }
+        Some(out)
+    }
+}
+
+pub struct Inflater {
+    inner: Decompress,
+    reset: bool, // the client starts every message from an empty window
+    // the message ended its deflate stream with a final block, the rest of it is ignored
+    ended: bool,
+}
+
+impl Inflater {
+    // Inflate a compressed fragment onto `out`, `fin` for the last fragment of the message
+    // Decompressed messages can't grow past `limit` either.
+    pub fn inflate(
+        &mut self,
+        input: &[u8],
+        fin: bool,
+        out: &mut Vec<u8>,
+        limit: usize,
+    ) -> Result<(), Error> {
+        self.feed(input, out, limit)?;
+        if fin {
+            self.feed(&TAIL, out, limit)?;
+            // after a final block the next message is a new stream
+            if self.reset || self.ended {
+                self.inner.reset(false);
+                self.ended = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn feed(&mut self, input: &[u8], out: &mut Vec<u8>, limit: usize) -> Result<(), Error> {
+        let start = self.inner.total_in();
+        loop {
+            if self.ended {
+                return Ok(());
+            }
+            if out.len() == out.capacity() {
+                out.reserve((input.len() * 2).max(256));
+            }
+            let consumed = (self.inner.total_in() - start) as usize;
+            let status = self
+                .inner
+                .decompress_vec(&input[consumed..], out, FlushDecompress::Sync)
+                .map_err(|_| Error::Protocol("bad compressed data"))?;
+            if out.len() > limit {
+                return Err(Error::TooBig);
+            }
+            let consumed = (self.inner.total_in() - start) as usize;
+            match status {
+                // A final block (BFINAL set) may end a message (RFC 7692 section 7.2.3.5), what
+                // follows it in the message, the tail included, isn't part of the stream
+                Status::StreamEnd => self.ended = true,
+                _ if consumed == input.len() && out.len() < out.capacity() => return Ok(()),
+                Status::BufError if out.len() < out.capacity() => {
+                    // no progress with room to spare, the input is used up
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{self, Frame, Message, Opcode, MAX_MESSAGE_SIZE};
+    use crate::testing::Client;
+    use flate2::{Compress, Compression, FlushCompress};
+
+    // messages with the same shape over and over, what context takeover makes small
+    fn json(i: usize) -> String {
+        format!(
+            r#"{{"id": {}, "sensor": "temperature", "unit": "celsius", "values": [20.5, 21.0, 21.5]}}"#,
+            i
+        )
+    }
+
+    fn params(offer: &str) -> Params {
+        Params::parse(offer).unwrap()
+    }
+
+    fn inflate(inflater: &mut Inflater, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        inflater
+            .inflate(payload, true, &mut out, MAX_MESSAGE_SIZE)
+            .unwrap();
+        out
+    }
+
+    // a message that repeats itself, so a window that still holds the last one pays off
+    fn message(i: usize) -> Vec<u8> {
+        format!(
+            "{{\"type\":\"update\",\"topic\":\"prices\",\"payload\":{{\"symbol\":\"ABC\",\"seq\":{}}}}}",
+            i
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn parameters() {
+        assert_eq!(
+            params("permessage-deflate"),
+            Params {
+                server_no_context_takeover: false,
+                client_no_context_takeover: false,
+                server_max_window_bits: 15,
+            }
+        );
+        assert_eq!(
+            params("permessage-deflate; server_no_context_takeover; client_no_context_takeover; server_max_window_bits=10; client_max_window_bits"),
+            Params {
+                server_no_context_takeover: true,
+                client_no_context_takeover: true,
+                server_max_window_bits: 10,
+            }
+        );
+        // quoted values are allowed
+        assert_eq!(
+            params("permessage-deflate; server_max_window_bits=\"12\"").server_max_window_bits,
+            12
+        );
+        assert!(Params::parse("permessage-deflate; client_max_window_bits=9").is_some());
+
+        for offer in &[
+            "x-webkit-deflate-frame",
+            "permessage-deflate; server_max_window_bits=8",
+            "permessage-deflate; server_max_window_bits=16",
+            "permessage-deflate; server_max_window_bits=010",
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; client_max_window_bits=7",
+            "permessage-deflate; server_no_context_takeover=1",
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+            "permessage-deflate; unknown",
+        ] {
+            assert_eq!(Params::parse(offer), None, "{}", offer);
+        }
+    }
+
+    #[test]
+    fn answers() {
+        assert_eq!(
+            params("permessage-deflate").to_string(),
+            "permessage-deflate"
+        );
+        let answer = "permessage-deflate; server_no_context_takeover; client_no_context_takeover; server_max_window_bits=9";
+        assert_eq!(params(answer).to_string(), answer);
+        // the client's window isn't part of the answer
+        assert_eq!(
+            params("permessage-deflate; client_max_window_bits=10").to_string(),
+            "permessage-deflate"
+        );
+    }
+
+    #[test]
+    fn offers() {
+        // the first offer that can be accepted wins
+        let offers = vec![
+            "permessage-deflate; server_max_window_bits=8",
+            "permessage-deflate; client_no_context_takeover",
+            "permessage-deflate",
+        ];
+        let agreed = negotiate(offers.into_iter()).unwrap();
+        assert!(agreed.client_no_context_takeover);
+
+        assert_eq!(negotiate(vec!["x-webkit-deflate-frame"].into_iter()), None);
+        assert_eq!(negotiate(Vec::new().into_iter()), None);
+    }
+
+    #[test]
+    fn roundtrip_with_context_takeover() {
+        let agreed = params("permessage-deflate");
+        let (mut deflater, mut inflater) = (agreed.deflater(), agreed.inflater());
+
+        let mut sizes = Vec::new();
+        for i in 0..10 {
+            let payload = deflater.deflate(&message(i)).unwrap();
+            assert!(!payload.ends_with(&TAIL));
+            sizes.push(payload.len());
+            assert_eq!(inflate(&mut inflater, &payload), message(i));
+        }
+        // later messages refer back to the earlier ones
+        assert!(sizes[9] < sizes[0] / 2, "{:?}", sizes);
+
+        // too short to be worth it
+        assert_eq!(deflater.deflate(b"short"), None);
+    }
+
+    #[test]
+    fn roundtrip_without_context_takeover() {
+        let agreed =
+            params("permessage-deflate; server_no_context_takeover; client_no_context_takeover");
+        let (mut deflater, mut inflater) = (agreed.deflater(), agreed.inflater());
+
+        let first = deflater.deflate(&message(1)).unwrap();
+        for _ in 0..5 {
+            // every message starts from scratch, so the same one compresses the same way
+            let payload = deflater.deflate(&message(1)).unwrap();
+            assert_eq!(payload, first);
+            assert_eq!(inflate(&mut inflater, &payload), message(1));
+        }
+        // a fresh inflater can read any of them
+        let mut fresh = agreed.inflater();
+        assert_eq!(inflate(&mut fresh, &first), message(1));
+    }
+
+    #[test]
+    fn fragmented_messages() {
+        let agreed = params("permessage-deflate; server_max_window_bits=9");
+        let (mut deflater, mut inflater) = (agreed.deflater(), agreed.inflater());
+        let text = "fragmented and compressed ".repeat(100).into_bytes();
+        let payload = deflater.deflate(&text).unwrap();
+
+        let mut out = Vec::new();
+        let chunks: Vec<&[u8]> = payload.chunks(7).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let fin = i == chunks.len() - 1;
+            inflater
+                .inflate(chunk, fin, &mut out, MAX_MESSAGE_SIZE)
+                .unwrap();
+        }
+        assert_eq!(out, text);
+    }
+
+    #[test]
+    fn final_blocks() {
+        // a sender that ends its messages with BFINAL set instead of a sync flush
+        let mut inflater = params("permessage-deflate").inflater();
+        for i in 0..3 {
+            let mut compress = Compress::new(Compression::default(), false);
+            let mut payload = Vec::with_capacity(1024);
+            compress
+                .compress_vec(&message(i), &mut payload, FlushCompress::Finish)
+                .unwrap();
+            assert_eq!(inflate(&mut inflater, &payload), message(i));
+        }
+        // and then goes back to sync flushes
+        let payload = params("permessage-deflate").deflater().deflate(&message(3));
+        assert_eq!(inflate(&mut inflater, &payload.unwrap()), message(3));
+    }
+
+    #[test]
+    fn limits_and_garbage() {
+        let agreed = params("permessage-deflate");
+        let payload = agreed.deflater().deflate(&[b'a'; 10_000]).unwrap();
+        let mut out = Vec::new();
+        let result = agreed.inflater().inflate(&payload, true, &mut out, 1000);
+        assert_eq!(result, Err(Error::TooBig));
+
+        let mut out = Vec::new();
+        let result =
+            agreed
+                .inflater()
+                .inflate(&[0xff, 0xff, 0xff], true, &mut out, MAX_MESSAGE_SIZE);
+        assert_eq!(result, Err(Error::Protocol("bad compressed data")));
+    }
+
+    // End to end, after the Autobahn testsuite's categories 12.x and 13.x
+
+    #[test]
+    fn compression_negotiation() {
+        let offers: [(&str, Option<&str>); 8] = [
+            ("permessage-deflate", Some("permessage-deflate")),
+            (
+                "permessage-deflate; client_max_window_bits",
+                Some("permessage-deflate"),
+            ),
+            (
+                "permessage-deflate; client_max_window_bits=10",
+                Some("permessage-deflate"),
+            ),
+            (
+                "permessage-deflate; server_no_context_takeover; client_no_context_takeover",
+                Some("permessage-deflate; server_no_context_takeover; client_no_context_takeover"),
+            ),
+            (
+                "permessage-deflate; server_max_window_bits=10",
+                Some("permessage-deflate; server_max_window_bits=10"),
+            ),
+            // the first offer that can be accepted wins
+            (
+                "permessage-deflate; server_max_window_bits=8, permessage-deflate; server_max_window_bits=9",
+                Some("permessage-deflate; server_max_window_bits=9"),
+            ),
+            ("permessage-deflate; unknown_parameter", None),
+            ("x-webkit-deflate-frame", None),
+        ];
+        for (offer, expected) in offers.iter() {
+            let (mut client, answer) = Client::compressed(offer);
+            assert_eq!(answer.as_deref(), *expected, "answer to {}", offer);
+
+            let text = json(0);
+            if expected.is_some() {
+                client.send_compressed(text.as_bytes(), 1);
+                assert_eq!(client.peek().0, frame::RSV1, "echo of {}", offer);
+                client.expect_echo(text.as_bytes());
+            } else {
+                // without an answer nothing is compressed, either way
+                client.send_text(text.as_bytes());
+                assert_eq!(client.peek().0, 0, "echo of {}", offer);
+                client.expect_echo(text.as_bytes());
+                client.send_split(&[0x4a, 0x05, 0x00], 1);
+                client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+            }
+        }
+    }
+
+    // 12.1.x: compressed messages of every size are echoed compressed
+    #[test]
+    fn compressed_echo() {
+        let (mut client, _) = Client::compressed("permessage-deflate");
+        for &len in &[64, 125, 126, 1000, 65535, 65536, 1 << 20] {
+            let text: Vec<u8> = (0..len).map(|i| b"abcdefghij"[i * 7 % 10]).collect();
+            client.send_compressed(&text, 1);
+            let (rsv, compressed) = client.peek();
+            assert_eq!(rsv, frame::RSV1);
+            assert!(
+                compressed < len / 2,
+                "{} bytes compressed to {}",
+                len,
+                compressed
+            );
+            client.expect_echo(&text);
+        }
+
+        // messages may still go out uncompressed, and short ones are echoed uncompressed
+        let text = json(1);
+        client.send_text(text.as_bytes());
+        assert_eq!(client.peek().0, frame::RSV1);
+        client.expect_echo(text.as_bytes());
+        client.send_text(b"short");
+        assert_eq!(client.peek().0, 0);
+        client.expect_echo(b"short");
+        client.send_fragment(Opcode::Binary, &[0; 1000], true);
+        assert_eq!(client.peek().0, frame::RSV1);
+        assert_eq!(client.recv(), Message::Binary(vec![0; 1000]));
+    }
+
+    // 13.x: every combination of context takeover, both sides keep to what was agreed on
+    #[test]
+    fn context_takeover() {
+        for &server_reset in &[false, true] {
+            for &client_reset in &[false, true] {
+                let mut offer = String::from("permessage-deflate");
+                if server_reset {
+                    offer.push_str("; server_no_context_takeover");
+                }
+                if client_reset {
+                    offer.push_str("; client_no_context_takeover");
+                }
+                let (mut client, answer) = Client::compressed(&offer);
+                assert_eq!(answer.as_deref(), Some(offer.as_str()));
+
+                let mut sizes = Vec::new();
+                for i in 0..5 {
+                    let text = json(i);
+                    client.send_compressed(text.as_bytes(), 1);
+                    sizes.push(client.peek().1);
+                    client.expect_echo(text.as_bytes());
+                }
+                // with its window kept, the server's later messages refer back to the first one
+                let first = sizes[0];
+                if server_reset {
+                    assert!(sizes.iter().all(|&size| size + 2 >= first), "{:?}", sizes);
+                } else {
+                    assert!(
+                        sizes[1..].iter().all(|&size| size < first / 2),
+                        "{:?}",
+                        sizes
+                    );
+                }
+            }
+        }
+    }
+
+    // 12.x with fragmentation: the compressed payload may be split anywhere
+    #[test]
+    fn compressed_fragments() {
+        let (mut client, _) = Client::compressed("permessage-deflate");
+        let text = json(2).repeat(10);
+        for &fragments in &[2, 3, 7, 1000] {
+            client.send_compressed(text.as_bytes(), fragments);
+            client.expect_echo(text.as_bytes());
+        }
+
+        // a ping in the middle of a compressed message
+        let payload = client
+            .deflater
+            .as_mut()
+            .unwrap()
+            .deflate(text.as_bytes())
+            .unwrap();
+        let (first, rest) = payload.split_at(payload.len() / 2);
+        client.send(Frame {
+            fin: false,
+            rsv: frame::RSV1,
+            opcode: Opcode::Text,
+            payload: first.to_vec(),
+        });
+        client.send_fragment(Opcode::Ping, b"in between", true);
+        client.send_fragment(Opcode::Continuation, rest, true);
+        assert_eq!(client.recv(), Message::Pong(b"in between".to_vec()));
+        client.expect_echo(text.as_bytes());
+    }
+
+    // RFC 7692 section 7.2.3.5: a message may end its deflate stream with a final block
+    #[test]
+    fn compressed_final_blocks() {
+        let (mut client, _) = Client::compressed("permessage-deflate; client_no_context_takeover");
+        for i in 0..3 {
+            let text = json(i);
+            let mut compress = Compress::new(Compression::default(), false);
+            let mut payload = Vec::with_capacity(text.len() + 64);
+            compress
+                .compress_vec(text.as_bytes(), &mut payload, FlushCompress::Finish)
+                .unwrap();
+            client.send_split(&payload, 1);
+            client.expect_echo(text.as_bytes());
+        }
+
+        // and the messages after them start over as usual
+        let text = json(3);
+        client.send_compressed(text.as_bytes(), 2);
+        client.expect_echo(text.as_bytes());
+    }
+
+    #[test]
+    fn compression_violations() {
+        // RSV1 is only set on the first frame of a message
+        let (mut client, _) = Client::compressed("permessage-deflate");
+        client.send_fragment(Opcode::Text, b"frag", false);
+        client.send(Frame {
+            fin: true,
+            rsv: frame::RSV1,
+            opcode: Opcode::Continuation,
+            payload: b"ment".to_vec(),
+        });
+        client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+
+        // control frames are never compressed, and the other reserved bits stay reserved
+        for &(rsv, opcode) in &[
+            (frame::RSV1, Opcode::Ping),
+            (frame::RSV1, Opcode::Close),
+            (0b010, Opcode::Text),
+            (frame::RSV1 | 0b001, Opcode::Text),
+        ] {
+            let (mut client, _) = Client::compressed("permessage-deflate");
+            client.send(Frame {
+                fin: true,
+                rsv,
+                opcode,
+                payload: b"\x03\xe8".to_vec(),
+            });
+            client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+        }
+
+        // data that doesn't inflate (a reserved block type)
+        let (mut client, _) = Client::compressed("permessage-deflate");
+        client.send_split(&[0xff; 16], 1);
+        client.expect_close(frame::CLOSE_PROTOCOL_ERROR);
+
+        // the limit applies to the inflated message, however small it was on the wire
+        let (mut client, _) = Client::compressed("permessage-deflate");
+        client.send_compressed(&vec![b'*'; frame::MAX_MESSAGE_SIZE + 1], 1);
+        client.expect_close(frame::CLOSE_TOO_BIG);
+
+        // inflated text is checked as UTF-8 too
+        let (mut client, _) = Client::compressed("permessage-deflate");
+        let mut text = json(0).into_bytes();
+        text.extend_from_slice(b"\xc0\xaf");
+        client.send_compressed(&text, 1);
+        client.expect_close(frame::CLOSE_INVALID_DATA);
+    }
+}
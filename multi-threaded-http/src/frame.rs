@@ -0,0 +1,632 @@
+// Websocket framing (RFC 6455 section 5)
+// `decode` takes single frames off a ByteStream, `Decoder` puts fragmented messages back together
+// and checks what the RFC requires of them, `Frame::encode` writes frames out.
+// Frames from clients are masked, frames from servers are not.
+
+use crate::deflate::Inflater;
+use parser_combinators::stream::ByteStream;
+
+// close codes (section 7.4.1)
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+// messages larger than this are refused rather than buffered
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+// the first reserved bit, permessage-deflate marks compressed messages with it
+pub const RSV1: u8 = 0b100;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_u8(op: u8) -> Option<Opcode> {
+        match op {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None, // reserved
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub rsv: u8, // the three reserved bits, only extensions may set them
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+// Whatever went wrong, the connection is failed with the matching close code
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Protocol(&'static str),
+    InvalidUtf8,
+    TooBig,
+}
+
+impl Error {
+    pub fn close_code(&self) -> u16 {
+        match self {
+            Error::Protocol(_) => CLOSE_PROTOCOL_ERROR,
+            Error::InvalidUtf8 => CLOSE_INVALID_DATA,
+            Error::TooBig => CLOSE_TOO_BIG,
+        }
+    }
+}
+
+impl Frame {
+    // a complete (unfragmented) frame
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> Frame {
+        Frame {
+            fin: true,
+            rsv: 0,
+            opcode,
+            payload,
+        }
+    }
+
+    pub fn close(code: u16, reason: &str) -> Frame {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        Frame::new(Opcode::Close, payload)
+    }
+
+    // Write the frame, masking the payload with `mask` if there is one (clients must mask)
+    pub fn encode(&self, stream: &mut ByteStream, mask: Option<[u8; 4]>) {
+        let mut head = Vec::with_capacity(14);
+        head.push((self.fin as u8) << 7 | (self.rsv & 0x07) << 4 | self.opcode.as_u8());
+
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        let len = self.payload.len();
+        if len < 126 {
+            head.push(mask_bit | len as u8);
+        } else if len <= u16::MAX as usize {
+            head.push(mask_bit | 126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            head.push(mask_bit | 127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+
+        match mask {
+            Some(mask) => {
+                head.extend_from_slice(&mask);
+                stream.put(&head);
+                let mut payload = self.payload.clone();
+                apply_mask(&mut payload, mask);
+                stream.put(&payload);
+            }
+            None => {
+                stream.put(&head);
+                stream.put(&self.payload);
+            }
+        }
+    }
+}
+
+// masking and unmasking are the same xor
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+// drop the first `n` unread bytes of the stream
+pub fn consume(stream: &mut ByteStream, n: usize) {
+    stream.skip(n);
+    stream.pull();
+}
+
+// Take one frame off the front of the stream, None until all of it has arrived
+// `masked` is whether frames from the other side must be masked (they must if it's a client).
+// Payloads over `max_payload` are refused as soon as the length is known.
+pub fn decode(
+    stream: &mut ByteStream,
+    masked: bool,
+    max_payload: usize,
+) -> Result<Option<Frame>, Error> {
+    let buf = stream.as_ref();
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buf[0] & 0x80 != 0;
+    let rsv = (buf[0] >> 4) & 0x07;
+    let opcode = Opcode::from_u8(buf[0] & 0x0F).ok_or(Error::Protocol("reserved opcode"))?;
+    if (buf[1] & 0x80 != 0) != masked {
+        return Err(Error::Protocol(if masked {
+            "unmasked frame from a client"
+        } else {
+            "masked frame from a server"
+        }));
+    }
+
+    // the 7 bit length, or 126/127 for a 16/64 bit length that follows
+    let (len, mut at) = match buf[1] & 0x7F {
+        126 if buf.len() < 4 => return Ok(None),
+        126 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() < 10 => return Ok(None),
+        127 => {
+            let mut len = [0_u8; 8];
+            len.copy_from_slice(&buf[2..10]);
+            let len = u64::from_be_bytes(len);
+            if len >> 63 != 0 {
+                return Err(Error::Protocol("most significant bit of the length set"));
+            }
+            (len, 10)
+        }
+        len => (len as u64, 2),
+    };
+
+    if opcode.is_control() {
+        if !fin {
+            return Err(Error::Protocol("fragmented control frame"));
+        }
+        if len > 125 {
+            return Err(Error::Protocol("control frame payload over 125 bytes"));
+        }
+    }
+    if len > max_payload as u64 {
+        return Err(Error::TooBig);
+    }
+    let len = len as usize;
+
+    let mask = if masked {
+        if buf.len() < at + 4 {
+            return Ok(None);
+        }
+        let mask = [buf[at], buf[at + 1], buf[at + 2], buf[at + 3]];
+        at += 4;
+        Some(mask)
+    } else {
+        None
+    };
+
+    if buf.len() < at + len {
+        return Ok(None);
+    }
+    let mut payload = buf[at..at + len].to_vec();
+    if let Some(mask) = mask {
+        apply_mask(&mut payload, mask);
+    }
+    consume(stream, at + len);
+
+    Ok(Some(Frame {
+        fin,
+        rsv,
+        opcode,
+        payload,
+    }))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    // the status code and reason, if the peer sent one
+    Close(Option<(u16, String)>),
+}
+
+// Codes a peer may send in a close frame (section 7.4)
+fn valid_close_code(code: u16) -> bool {
+    match code {
+        // 1004 is reserved, 1005 and 1006 must never be sent
+        1000..=1003 | 1007..=1011 => true,
+        3000..=4999 => true, // for libraries and applications
+        _ => false,
+    }
+}
+
+fn close_message(payload: &[u8]) -> Result<Message, Error> {
+    match payload.len() {
+        0 => Ok(Message::Close(None)),
+        1 => Err(Error::Protocol("close payload too short for a status code")),
+        _ => {
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            if !valid_close_code(code) {
+                return Err(Error::Protocol("invalid close code"));
+            }
+            let reason = std::str::from_utf8(&payload[2..]).map_err(|_| Error::InvalidUtf8)?;
+            Ok(Message::Close(Some((code, reason.to_string()))))
+        }
+    }
+}
+
+// Text has to be valid UTF-8, checked as fragments arrive so a bad message fails early
+// Only what's new since the last fragment is looked at, starting from the end of the valid
+// prefix. A sequence cut off at the end of an unfinished message may still be completed by the
+// next fragment, so it's checked again then. Returns the new length of the valid prefix.
+fn check_utf8(payload: &[u8], valid: usize, fin: bool) -> Result<usize, Error> {
+    match std::str::from_utf8(&payload[valid..]) {
+        Ok(_) => Ok(payload.len()),
+        Err(e) if e.error_len().is_none() && !fin => Ok(valid + e.valid_up_to()),
+        Err(_) => Err(Error::InvalidUtf8),
+    }
+}
+
+// Reads whole messages, reassembling fragmented ones
+// Control frames may arrive in between the fragments of a message and are returned right away.
+pub struct Decoder {
+    masked: bool,
+    max_size: usize,
+    // a message still being fragmented
+    partial: Option<Partial>,
+    // set once permessage-deflate has been negotiated
+    inflater: Option<Inflater>,
+}
+
+struct Partial {
+    opcode: Opcode,
+    compressed: bool,
+    payload: Vec<u8>, // decompressed if it was compressed
+    // how much of a text payload is known to be valid UTF-8
+    valid: usize,
+}
+
+impl Decoder {
+    // A decoder for what clients send (masked frames)
+    pub fn server() -> Decoder {
+        Decoder {
+            masked: true,
+            max_size: MAX_MESSAGE_SIZE,
+            partial: None,
+            inflater: None,
+        }
+    }
+
+    // A decoder for what servers send (unmasked frames), only the tests play the client
+    #[cfg(test)]
+    pub fn client() -> Decoder {
+        Decoder {
+            masked: false,
+            max_size: MAX_MESSAGE_SIZE,
+            partial: None,
+            inflater: None,
+        }
+    }
+
+    // Accept compressed messages from now on
+    pub fn set_inflater(&mut self, inflater: Inflater) {
+        self.inflater = Some(inflater);
+    }
+
+    // The next complete message, None until one has fully arrived
+    pub fn next(&mut self, stream: &mut ByteStream) -> Result<Option<Message>, Error> {
+        loop {
+            let buffered = self.partial.as_ref().map_or(0, |p| p.payload.len());
+            let frame = match decode(stream, self.masked, self.max_size - buffered)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            // RSV1 only on the first frame of a data message, and only with the extension
+            let compressed = frame.rsv & RSV1 != 0;
+            if frame.rsv & !RSV1 != 0
+                || compressed
+                    && (self.inflater.is_none()
+                        || frame.opcode.is_control()
+                        || frame.opcode == Opcode::Continuation)
+            {
+                return Err(Error::Protocol("reserved bits set"));
+            }
+
+            let mut partial = match frame.opcode {
+                Opcode::Ping => return Ok(Some(Message::Ping(frame.payload))),
+                Opcode::Pong => return Ok(Some(Message::Pong(frame.payload))),
+                Opcode::Close => return close_message(&frame.payload).map(Some),
+                Opcode::Continuation => match self.partial.take() {
+                    Some(partial) => partial,
+                    None => return Err(Error::Protocol("continuation of nothing")),
+                },
+                opcode => {
+                    if self.partial.is_some() {
+                        return Err(Error::Protocol("new message inside a fragmented one"));
+                    }
+                    Partial {
+                        opcode,
+                        compressed,
+                        payload: Vec::new(),
+                        valid: 0,
+                    }
+                }
+            };
+
+            if partial.compressed {
+                // only set with an inflater, checked above
+                let inflater = self.inflater.as_mut().unwrap();
+                inflater.inflate(
+                    &frame.payload,
+                    frame.fin,
+                    &mut partial.payload,
+                    self.max_size,
+                )?;
+            } else {
+                partial.payload.extend_from_slice(&frame.payload);
+            }
+
+            if partial.opcode == Opcode::Text {
+                partial.valid = check_utf8(&partial.payload, partial.valid, frame.fin)?;
+            }
+            if !frame.fin {
+                self.partial = Some(partial);
+                continue;
+            }
+            return Ok(Some(match partial.opcode {
+                // checked above
+                Opcode::Text => Message::Text(String::from_utf8(partial.payload).unwrap()),
+                _ => Message::Binary(partial.payload),
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the examples from RFC 6455 section 5.7
+    const HELLO: [u8; 7] = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+    const MASKED_HELLO: [u8; 11] = [
+        0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+    ];
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    fn stream(bytes: &[u8]) -> ByteStream {
+        let mut stream = ByteStream::with_capacity(bytes.len());
+        stream.put(bytes);
+        stream
+    }
+
+    fn encoded(frame: &Frame, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut out = ByteStream::with_capacity(16);
+        frame.encode(&mut out, mask);
+        out.as_ref().to_vec()
+    }
+
+    fn text(s: &str) -> Frame {
+        Frame::new(Opcode::Text, s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn unmasked_text() {
+        let frame = decode(&mut stream(&HELLO), false, MAX_MESSAGE_SIZE).unwrap();
+        assert_eq!(frame, Some(text("Hello")));
+        assert_eq!(encoded(&text("Hello"), None), HELLO);
+    }
+
+    #[test]
+    fn masked_text() {
+        let frame = decode(&mut stream(&MASKED_HELLO), true, MAX_MESSAGE_SIZE).unwrap();
+        assert_eq!(frame, Some(text("Hello")));
+        assert_eq!(encoded(&text("Hello"), Some(MASK)), MASKED_HELLO);
+    }
+
+    #[test]
+    fn masking_must_match_the_side() {
+        let err = Error::Protocol("unmasked frame from a client");
+        assert_eq!(decode(&mut stream(&HELLO), true, 125), Err(err));
+        let err = Error::Protocol("masked frame from a server");
+        assert_eq!(decode(&mut stream(&MASKED_HELLO), false, 125), Err(err));
+    }
+
+    #[test]
+    fn partial_frames_wait() {
+        for end in 0..MASKED_HELLO.len() {
+            let mut partial = stream(&MASKED_HELLO[..end]);
+            assert_eq!(decode(&mut partial, true, MAX_MESSAGE_SIZE), Ok(None));
+            // nothing is consumed until the whole frame is there
+            assert_eq!(partial.as_ref(), &MASKED_HELLO[..end]);
+        }
+    }
+
+    #[test]
+    fn fragmented_text() {
+        // "Hel" then "lo"
+        let bytes = [0x01, 0x03, 0x48, 0x65, 0x6c, 0x80, 0x02, 0x6c, 0x6f];
+        let mut bytes = stream(&bytes);
+        let first = decode(&mut bytes, false, MAX_MESSAGE_SIZE)
+            .unwrap()
+            .unwrap();
+        assert_eq!((first.fin, first.opcode), (false, Opcode::Text));
+        assert_eq!(first.payload, b"Hel");
+        let second = decode(&mut bytes, false, MAX_MESSAGE_SIZE)
+            .unwrap()
+            .unwrap();
+        assert_eq!((second.fin, second.opcode), (true, Opcode::Continuation));
+        assert_eq!(second.payload, b"lo");
+
+        let bytes = [0x01, 0x03, 0x48, 0x65, 0x6c, 0x80, 0x02, 0x6c, 0x6f];
+        let message = Decoder::client().next(&mut stream(&bytes)).unwrap();
+        assert_eq!(message, Some(Message::Text("Hello".to_string())));
+    }
+
+    #[test]
+    fn ping_and_pong() {
+        let ping = [0x89, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        let pong = [
+            0x8a, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let message = Decoder::client().next(&mut stream(&ping)).unwrap();
+        assert_eq!(message, Some(Message::Ping(b"Hello".to_vec())));
+        let message = Decoder::server().next(&mut stream(&pong)).unwrap();
+        assert_eq!(message, Some(Message::Pong(b"Hello".to_vec())));
+        let frame = Frame::new(Opcode::Pong, b"Hello".to_vec());
+        assert_eq!(encoded(&frame, Some(MASK)), pong);
+    }
+
+    #[test]
+    fn sixteen_bit_length() {
+        let frame = Frame::new(Opcode::Binary, vec![7; 256]);
+        let bytes = encoded(&frame, None);
+        assert_eq!(bytes[..4], [0x82, 0x7e, 0x01, 0x00]);
+        assert_eq!(bytes.len(), 4 + 256);
+        assert_eq!(decode(&mut stream(&bytes), false, 256), Ok(Some(frame)));
+    }
+
+    #[test]
+    fn sixty_four_bit_length() {
+        let frame = Frame::new(Opcode::Binary, vec![7; 65536]);
+        let bytes = encoded(&frame, Some(MASK));
+        assert_eq!(
+            bytes[..10],
+            [0x82, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00]
+        );
+        assert_eq!(bytes.len(), 10 + 4 + 65536);
+        assert_eq!(decode(&mut stream(&bytes), true, 65536), Ok(Some(frame)));
+    }
+
+    #[test]
+    fn oversized_payload_is_refused_early() {
+        // only the head of a 64 KiB frame has arrived
+        let head = [0x82, 0x7f, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00];
+        assert_eq!(decode(&mut stream(&head), false, 1024), Err(Error::TooBig));
+        let head = [0x82, 0x7f, 0x80, 0, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(
+            decode(&mut stream(&head), false, 1024),
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn close_with_status() {
+        let bytes = encoded(&Frame::close(CLOSE_NORMAL, "bye"), None);
+        assert_eq!(bytes, [0x88, 0x05, 0x03, 0xe8, b'b', b'y', b'e']);
+        let message = Decoder::client().next(&mut stream(&bytes)).unwrap();
+        assert_eq!(
+            message,
+            Some(Message::Close(Some((1000, "bye".to_string()))))
+        );
+
+        let empty = Decoder::client().next(&mut stream(&[0x88, 0x00])).unwrap();
+        assert_eq!(empty, Some(Message::Close(None)));
+    }
+
+    #[test]
+    fn bad_close_frames() {
+        let one_byte = [0x88, 0x01, 0x03];
+        let reserved_code = [0x88, 0x02, 0x03, 0xed]; // 1005
+        let bad_reason = [0x88, 0x04, 0x03, 0xe8, 0xff, 0xfe];
+        let close_code = |bytes: &[u8]| {
+            let err = Decoder::client().next(&mut stream(bytes)).unwrap_err();
+            err.close_code()
+        };
+        assert_eq!(close_code(&one_byte), CLOSE_PROTOCOL_ERROR);
+        assert_eq!(close_code(&reserved_code), CLOSE_PROTOCOL_ERROR);
+        assert_eq!(close_code(&bad_reason), CLOSE_INVALID_DATA);
+    }
+
+    #[test]
+    fn control_frames_between_fragments() {
+        // "Hel", a ping, then "lo"
+        let bytes = [
+            0x01, 0x03, 0x48, 0x65, 0x6c, 0x89, 0x00, 0x80, 0x02, 0x6c, 0x6f,
+        ];
+        let mut bytes = stream(&bytes);
+        let mut decoder = Decoder::client();
+        assert_eq!(decoder.next(&mut bytes), Ok(Some(Message::Ping(vec![]))));
+        assert_eq!(
+            decoder.next(&mut bytes),
+            Ok(Some(Message::Text("Hello".to_string())))
+        );
+    }
+
+    #[test]
+    fn fragmentation_errors() {
+        let cases: [&[u8]; 4] = [
+            &[0x80, 0x00],             // continuation of nothing
+            &[0x01, 0x00, 0x81, 0x00], // a new message inside a fragmented one
+            &[0x09, 0x00],             // fragmented ping
+            &[0x83, 0x00],             // reserved opcode
+        ];
+        for bytes in cases.iter() {
+            let result = Decoder::client().next(&mut stream(bytes));
+            assert!(matches!(result, Err(Error::Protocol(_))), "{:?}", bytes);
+        }
+        let rsv = [0xc1, 0x00];
+        let result = Decoder::client().next(&mut stream(&rsv));
+        assert_eq!(result, Err(Error::Protocol("reserved bits set")));
+    }
+
+    #[test]
+    fn utf8_validation() {
+        // "κόσμε" split in the middle of a two byte sequence is fine
+        let kosme = "κόσμε".as_bytes();
+        let mut bytes = vec![0x01, 0x03];
+        bytes.extend_from_slice(&kosme[..3]);
+        bytes.extend_from_slice(&[0x80, (kosme.len() - 3) as u8]);
+        bytes.extend_from_slice(&kosme[3..]);
+        let message = Decoder::client().next(&mut stream(&bytes)).unwrap();
+        assert_eq!(message, Some(Message::Text("κόσμε".to_string())));
+
+        // a byte that can never appear in UTF-8 fails without waiting for the rest
+        let bytes = [0x01, 0x02, 0xce, 0xff];
+        let result = Decoder::client().next(&mut stream(&bytes));
+        assert_eq!(result, Err(Error::InvalidUtf8));
+
+        // a sequence left unfinished by the final fragment
+        let bytes = [0x81, 0x01, 0xce];
+        let result = Decoder::client().next(&mut stream(&bytes));
+        assert_eq!(result, Err(Error::InvalidUtf8));
+    }
+
+    #[test]
+    fn utf8_one_byte_fragments() {
+        // every byte of a four byte sequence in its own fragment
+        let text = "a😀b";
+        let bytes = text.as_bytes();
+        let mut frames = Vec::new();
+        for (i, byte) in bytes.iter().enumerate() {
+            let opcode = if i == 0 { 0x01 } else { 0x00 };
+            let fin = if i == bytes.len() - 1 { 0x80 } else { 0x00 };
+            frames.extend_from_slice(&[fin | opcode, 0x01, *byte]);
+        }
+        let message = Decoder::client().next(&mut stream(&frames)).unwrap();
+        assert_eq!(message, Some(Message::Text(text.to_string())));
+
+        // the carried over bytes are checked again once the sequence goes on
+        let bytes = [0x01, 0x01, 0xf0, 0x00, 0x01, 0x9f, 0x00, 0x01, 0x41];
+        let result = Decoder::client().next(&mut stream(&bytes));
+        assert_eq!(result, Err(Error::InvalidUtf8));
+    }
+
+    #[test]
+    fn utf8_prefix() {
+        let payload = "ab😀".as_bytes();
+        assert_eq!(check_utf8(&payload[..4], 0, false), Ok(2));
+        assert_eq!(check_utf8(&payload[..5], 2, false), Ok(2));
+        assert_eq!(check_utf8(payload, 2, false), Ok(6));
+        assert_eq!(check_utf8(payload, 6, true), Ok(6));
+        assert_eq!(check_utf8(&payload[..5], 2, true), Err(Error::InvalidUtf8));
+    }
+}
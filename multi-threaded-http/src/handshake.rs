@@ -0,0 +1,298 @@
+// Checking websocket upgrade requests (RFC 6455 section 4.2.1)
+// Besides asking to upgrade, a client has to send a proper key and speak version 13 of the protocol.
+// Browsers also say in Origin which site opened the socket, so the server can refuse sites it
+// doesn't trust. Clients may offer subprotocols, the first one the application registered is agreed on.
+
+use parser_combinators::http::Request;
+
+// the only version RFC 6455 defines
+pub const VERSION: &str = "13";
+
+// Why an upgrade was turned down, each has its own status code
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    // 400, the request isn't a valid handshake
+    BadRequest(&'static str),
+    // 403, opened from a site that isn't allowed
+    Forbidden,
+    // 426, a version the server doesn't speak (the answer says which one it does)
+    UnsupportedVersion,
+}
+
+#[derive(Default)]
+pub struct Handshake {
+    origins: Vec<String>,
+    protocols: Vec<String>,
+}
+
+impl Handshake {
+    // Only accept upgrades from pages at `origin` (like "http://localhost:9000")
+    // Every origin is accepted until one is allowed.
+    pub fn allow_origin(mut self, origin: &str) -> Handshake {
+        self.origins.push(origin.to_string());
+        self
+    }
+
+    // A subprotocol the application speaks
+    pub fn protocol(mut self, name: &str) -> Handshake {
+        self.protocols.push(name.to_string());
+        self
+    }
+
+    // Check an upgrade request, returning the subprotocol agreed on if there is one
+    pub fn check(&self, req: &Request) -> Result<Option<String>, Rejection> {
+        if req.method != "GET" {
+            return Err(Rejection::BadRequest(
+                "websocket upgrades must be GET requests",
+            ));
+        }
+
+        // 16 random bytes, base64 encoded
+        match req.headers.get("Sec-WebSocket-Key") {
+            None => return Err(Rejection::BadRequest("missing Sec-WebSocket-Key")),
+            Some(key) if base64::decode(key.trim()).map_or(true, |k| k.len() != 16) => {
+                return Err(Rejection::BadRequest("invalid Sec-WebSocket-Key"));
+            }
+            Some(_) => {}
+        }
+
+        match req.headers.get("Sec-WebSocket-Version") {
+            None => return Err(Rejection::BadRequest("missing Sec-WebSocket-Version")),
+            Some(version) if version.trim() != VERSION => {
+                return Err(Rejection::UnsupportedVersion)
+            }
+            Some(_) => {}
+        }
+
+        // only browsers send Origin, other clients could claim any origin anyway
+        if let Some(origin) = req.headers.get("Origin") {
+            let allowed = self.origins.is_empty()
+                || self
+                    .origins
+                    .iter()
+                    .any(|o| o.eq_ignore_ascii_case(origin.trim()));
+            if !allowed {
+                return Err(Rejection::Forbidden);
+            }
+        }
+
+        // Offered in the client's order of preference. If none of them is registered the answer
+        // names none, and it's up to the client whether to go on without one.
+        let protocol = req
+            .headers
+            .tokens("Sec-WebSocket-Protocol")
+            .find(|p| self.protocols.iter().any(|name| name == p))
+            .map(str::to_string);
+        Ok(protocol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{header, request, valid_upgrade, Client};
+    use crate::{handler, upgrade_checks};
+    use parser_combinators::http::parse_request;
+
+    fn parse(target: &str, headers: &[&str]) -> Request {
+        let request = request(target, headers);
+        parse_request(request.as_bytes()).unwrap().unwrap().0
+    }
+
+    fn check(handshake: &Handshake, with: &[&str]) -> Result<Option<String>, Rejection> {
+        handshake.check(&parse("GET /echo", &valid_upgrade(&[], with)))
+    }
+
+    fn origin(handshake: &Handshake, origin: &str) -> Result<Option<String>, Rejection> {
+        check(handshake, &[&format!("Origin: {}", origin)])
+    }
+
+    #[test]
+    fn rejections() {
+        let handshake = Handshake::default();
+        assert_eq!(check(&handshake, &[]), Ok(None));
+
+        let bad_requests: [(&str, Vec<&str>, &str); 5] = [
+            (
+                "GET /echo",
+                valid_upgrade(&["Sec-WebSocket-Key"], &[]),
+                "missing Sec-WebSocket-Key",
+            ),
+            (
+                "GET /echo",
+                valid_upgrade(&["Sec-WebSocket-Key"], &["Sec-WebSocket-Key: c2hvcnQ="]),
+                "invalid Sec-WebSocket-Key",
+            ),
+            (
+                "GET /echo",
+                valid_upgrade(&["Sec-WebSocket-Key"], &["Sec-WebSocket-Key: not base64!"]),
+                "invalid Sec-WebSocket-Key",
+            ),
+            (
+                "GET /echo",
+                valid_upgrade(&["Sec-WebSocket-Version"], &[]),
+                "missing Sec-WebSocket-Version",
+            ),
+            (
+                "POST /echo",
+                valid_upgrade(&[], &["Content-Length: 0"]),
+                "websocket upgrades must be GET requests",
+            ),
+        ];
+        for (target, headers, reason) in bad_requests.iter() {
+            assert_eq!(
+                handshake.check(&parse(target, headers)),
+                Err(Rejection::BadRequest(reason)),
+                "for {:?}",
+                headers
+            );
+        }
+
+        for version in &["8", "14", "banana"] {
+            let version = format!("Sec-WebSocket-Version: {}", version);
+            let req = parse(
+                "GET /echo",
+                &valid_upgrade(&["Sec-WebSocket-Version"], &[&version]),
+            );
+            assert_eq!(handshake.check(&req), Err(Rejection::UnsupportedVersion));
+        }
+    }
+
+    // pages from other sites can't open websockets, the server's own pages and non-browsers can
+    #[test]
+    fn origins() {
+        for addr in &["127.0.0.1:9000", "0.0.0.0:9000"] {
+            let handshake = upgrade_checks(addr.parse().unwrap(), &[]);
+            for own in &[
+                "http://localhost:9000",
+                "http://127.0.0.1:9000",
+                "HTTP://LOCALHOST:9000",
+            ] {
+                assert_eq!(origin(&handshake, own), Ok(None), "{} at {}", own, addr);
+            }
+            for other in &[
+                "http://evil.example",
+                "http://localhost:9001",
+                "https://localhost:9000",
+                "null",
+            ] {
+                assert_eq!(
+                    origin(&handshake, other),
+                    Err(Rejection::Forbidden),
+                    "{} at {}",
+                    other,
+                    addr
+                );
+            }
+            assert_eq!(check(&handshake, &[]), Ok(None));
+        }
+
+        // any other address is its own origin
+        let handshake = upgrade_checks("192.168.1.5:9000".parse().unwrap(), &[]);
+        assert_eq!(origin(&handshake, "http://192.168.1.5:9000"), Ok(None));
+        assert_eq!(
+            origin(&handshake, "http://localhost:9000"),
+            Err(Rejection::Forbidden)
+        );
+
+        // origins given on the command line take the place of the server's own
+        let allowed = ["https://app.example".to_string()];
+        let handshake = upgrade_checks("127.0.0.1:9000".parse().unwrap(), &allowed);
+        assert_eq!(origin(&handshake, "https://app.example"), Ok(None));
+        assert_eq!(
+            origin(&handshake, "http://localhost:9000"),
+            Err(Rejection::Forbidden)
+        );
+
+        // and without any allowed origin every page may connect
+        assert_eq!(
+            origin(&Handshake::default(), "http://evil.example"),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn subprotocols() {
+        let handshake = Handshake::default().protocol("pubsub").protocol("chat");
+        // the client's preference decides among the ones the server speaks
+        let offers = [
+            ("Sec-WebSocket-Protocol: chat, pubsub", Some("chat")),
+            ("Sec-WebSocket-Protocol: mqtt, pubsub", Some("pubsub")),
+            ("Sec-WebSocket-Protocol: mqtt", None),
+        ];
+        for (offer, expected) in offers.iter() {
+            assert_eq!(
+                check(&handshake, &[offer]),
+                Ok(expected.map(str::to_string)),
+                "{}",
+                offer
+            );
+        }
+        assert_eq!(check(&handshake, &[]), Ok(None));
+    }
+
+    #[test]
+    fn responses() {
+        let handshake = upgrade_checks("127.0.0.1:9000".parse().unwrap(), &[]);
+        let respond = |target: &str, headers: &[&str]| handler(parse(target, headers), &handshake);
+
+        // the accept value for the key in RFC 6455 section 1.3
+        let res = respond(
+            "GET /echo",
+            &valid_upgrade(&[], &["Sec-WebSocket-Protocol: chat, pubsub"]),
+        );
+        assert_eq!(res.code, 101);
+        assert_eq!(
+            res.headers.get("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert_eq!(res.headers.get("Sec-WebSocket-Protocol"), Some("pubsub"));
+        let res = respond("GET /echo", &valid_upgrade(&[], &[]));
+        assert_eq!(res.headers.get("Sec-WebSocket-Protocol"), None);
+
+        let res = respond("GET /echo", &valid_upgrade(&["Sec-WebSocket-Key"], &[]));
+        assert_eq!(res.code, 400);
+        assert_eq!(res.content, b"missing Sec-WebSocket-Key\n");
+
+        let res = respond(
+            "GET /echo",
+            &valid_upgrade(&[], &["Origin: http://evil.example"]),
+        );
+        assert_eq!(res.code, 403);
+        assert_eq!(res.content, b"origin not allowed\n");
+
+        // a version the server doesn't speak is answered with the one it does
+        let res = respond(
+            "GET /echo",
+            &valid_upgrade(&["Sec-WebSocket-Version"], &["Sec-WebSocket-Version: 8"]),
+        );
+        assert_eq!(res.code, 426);
+        assert_eq!(res.headers.get("Sec-WebSocket-Version"), Some(VERSION));
+
+        // without Upgrade and Connection it's just an HTTP request
+        let res = respond("GET /echo", &valid_upgrade(&["Upgrade", "Connection"], &[]));
+        assert_eq!(res.code, 200);
+        assert_eq!(res.content, b"hello\n");
+    }
+
+    // the pubsub subprotocol makes /echo a pubsub connection
+    #[test]
+    fn pubsub_subprotocol() {
+        let (mut client, head) = Client::handshake("Sec-WebSocket-Protocol: chat, pubsub\r\n");
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+        assert_eq!(
+            header(&head, "Sec-WebSocket-Protocol").as_deref(),
+            Some("pubsub")
+        );
+        client.send_text(b"SUB news");
+        client.send_text(b"PUB news hello");
+        client.expect_echo(b"hello");
+
+        // none of them, the answer names none and the connection is an echo
+        let (mut client, head) = Client::handshake("Sec-WebSocket-Protocol: chat\r\n");
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+        assert_eq!(header(&head, "Sec-WebSocket-Protocol"), None);
+        client.send_text(b"SUB news");
+        client.expect_echo(b"SUB news");
+    }
+}
@@ -0,0 +1,238 @@
+// Publish/subscribe between websocket connections
+// Clients connected to /pubsub (or that asked for the "pubsub" subprotocol) send text commands:
+//   SUB <topic>              get every message published to <topic> from now on
+//   UNSUB <topic>            stop getting them
+//   PUB <topic> <message>    send <message> to everyone subscribed to <topic>
+// The hub lives on the event loop thread with the connections, so publishing is just appending
+// the message to every subscriber's send buffer.
+
+use mio::Token;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Subscribe(String),
+    Unsubscribe(String),
+    Publish(String, String),
+}
+
+// None for anything that isn't a well-formed command
+pub fn parse(text: &str) -> Option<Command> {
+    let mut parts = text.splitn(3, ' ');
+    let command = parts.next()?;
+    let topic = parts.next().filter(|t| !t.is_empty())?.to_string();
+    match (command, parts.next()) {
+        ("SUB", None) => Some(Command::Subscribe(topic)),
+        ("UNSUB", None) => Some(Command::Unsubscribe(topic)),
+        ("PUB", Some(message)) => Some(Command::Publish(topic, message.to_string())),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+pub struct Hub {
+    topics: HashMap<String, HashSet<Token>>,
+}
+
+impl Hub {
+    pub fn subscribe(&mut self, topic: &str, token: Token) {
+        self.topics
+            .entry(topic.to_string())
+            .or_default()
+            .insert(token);
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str, token: Token) {
+        if let Some(subscribers) = self.topics.get_mut(topic) {
+            subscribers.remove(&token);
+            if subscribers.is_empty() {
+                self.topics.remove(topic);
+            }
+        }
+    }
+
+    // the connection is gone, drop it from every topic
+    pub fn leave(&mut self, token: Token) {
+        self.topics.retain(|_, subscribers| {
+            subscribers.remove(&token);
+            !subscribers.is_empty()
+        });
+    }
+
+    pub fn subscribers(&self, topic: &str) -> Vec<Token> {
+        self.topics
+            .get(topic)
+            .map_or_else(Vec::new, |s| s.iter().copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{self, Frame, Message, Opcode};
+    use crate::testing::{request, valid_upgrade, Client};
+
+    // a connection to /pubsub
+    fn subscriber() -> Client {
+        let (client, head) = Client::open(&request("GET /pubsub", &valid_upgrade(&[], &[])));
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+        client
+    }
+
+    fn sorted(mut tokens: Vec<Token>) -> Vec<Token> {
+        tokens.sort();
+        tokens
+    }
+
+    #[test]
+    fn commands() {
+        let cases: [(&str, Option<Command>); 9] = [
+            ("SUB news", Some(Command::Subscribe("news".to_string()))),
+            ("UNSUB news", Some(Command::Unsubscribe("news".to_string()))),
+            (
+                "PUB news hello world",
+                Some(Command::Publish(
+                    "news".to_string(),
+                    "hello world".to_string(),
+                )),
+            ),
+            // the message may be empty, the topic may not
+            (
+                "PUB news ",
+                Some(Command::Publish("news".to_string(), String::new())),
+            ),
+            ("PUB news", None),
+            ("SUB", None),
+            ("SUB  news", None),
+            ("SUB news extra", None),
+            ("sub news", None),
+        ];
+        for (text, command) in cases.iter() {
+            assert_eq!(parse(text), *command, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn subscriptions() {
+        let mut hub = Hub::default();
+        hub.subscribe("news", Token(1));
+        hub.subscribe("news", Token(2));
+        hub.subscribe("news", Token(2));
+        hub.subscribe("weather", Token(2));
+        // a message goes out once to everyone on the topic
+        assert_eq!(sorted(hub.subscribers("news")), [Token(1), Token(2)]);
+        assert_eq!(hub.subscribers("weather"), [Token(2)]);
+        assert_eq!(hub.subscribers("sports"), []);
+
+        // unsubscribing keeps the other topics, and topics nobody is on are forgotten
+        hub.unsubscribe("news", Token(2));
+        hub.unsubscribe("sports", Token(2));
+        assert_eq!(hub.subscribers("news"), [Token(1)]);
+        assert_eq!(hub.subscribers("weather"), [Token(2)]);
+        hub.unsubscribe("news", Token(1));
+        assert!(!hub.topics.contains_key("news"));
+
+        hub.subscribe("news", Token(1));
+        hub.leave(Token(2));
+        assert_eq!(hub.subscribers("news"), [Token(1)]);
+        assert!(!hub.topics.contains_key("weather"));
+    }
+
+    // anything that isn't a command is answered with an error, the connection stays open
+    #[test]
+    fn unknown_commands() {
+        let mut client = subscriber();
+        for text in &["HELLO", "SUB", "PUB news", "sub news"] {
+            client.send_text(text.as_bytes());
+            client.expect_echo(b"ERR unknown command");
+        }
+        client.send_fragment(Opcode::Binary, b"SUB news", true);
+        client.expect_echo(b"ERR unknown command");
+        client.sync();
+    }
+
+    #[test]
+    fn fan_out() {
+        let mut alice = subscriber();
+        let mut bob = subscriber();
+        let mut carol = subscriber();
+        alice.send_text(b"SUB news");
+        bob.send_text(b"SUB news");
+        bob.send_text(b"SUB weather");
+        alice.sync();
+        bob.sync();
+
+        // every subscriber gets it, the publisher too if it's subscribed
+        carol.send_text(b"PUB news extra extra");
+        alice.expect_echo(b"extra extra");
+        bob.expect_echo(b"extra extra");
+        bob.send_text(b"PUB weather sunny");
+        bob.expect_echo(b"sunny");
+        carol.send_text(b"PUB sports nobody listens");
+        carol.sync();
+
+        // after UNSUB nothing more arrives, other topics are kept
+        bob.send_text(b"UNSUB news");
+        bob.sync();
+        carol.send_text(b"PUB news second edition");
+        carol.send_text(b"PUB weather rain");
+        carol.sync();
+        alice.expect_echo(b"second edition");
+        bob.expect_echo(b"rain");
+        alice.sync();
+        bob.sync();
+
+        // a subscriber that's gone is dropped, the others still get what's published
+        alice.send(Frame::close(frame::CLOSE_NORMAL, ""));
+        alice.expect_close(frame::CLOSE_NORMAL);
+        drop(alice);
+        bob.send_text(b"SUB news");
+        bob.sync();
+        carol.send_text(b"PUB news third edition");
+        bob.expect_echo(b"third edition");
+        bob.sync();
+    }
+
+    // A subscriber that doesn't read what it's sent is cut off once too much has piled up for it,
+    // without holding up the others
+    #[test]
+    fn slow_consumers() {
+        let mut slow = subscriber();
+        let mut fast = subscriber();
+        slow.send_text(b"SUB firehose");
+        fast.send_text(b"SUB firehose");
+        slow.sync();
+        fast.sync();
+
+        // more than the socket buffers and MAX_PENDING hold between them, 16 MiB in all
+        let message = "x".repeat(64 * 1024);
+        let publish = format!("PUB firehose {}", message);
+        for _ in 0..256 {
+            fast.send_text(publish.as_bytes());
+            fast.expect_echo(message.as_bytes());
+        }
+
+        let mut received = 0;
+        loop {
+            match slow.recv() {
+                Message::Text(text) => {
+                    assert_eq!(text, message);
+                    received += 1;
+                }
+                Message::Close(close) => {
+                    assert_eq!(
+                        close,
+                        Some((frame::CLOSE_POLICY_VIOLATION, "slow consumer".to_string()))
+                    );
+                    break;
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert!(received < 256);
+        // and the connection ends there, while the others still get what's published
+        while slow.fill() {}
+        fast.send_text(b"PUB firehose still going");
+        fast.expect_echo(b"still going");
+    }
+}
@@ -0,0 +1,4 @@
+// The work-stealing thread pool, as a library so that all of its API is there for other programs
+// The server (main.rs) and pool-bench use it from here.
+
+pub mod pool;
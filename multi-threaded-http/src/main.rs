@@ -1,55 +1,736 @@
-mod pool;
+// A websocket-capable HTTP server on top of mio
+// Plain requests get a small canned page, requests asking to upgrade to a websocket get
+// "101 Switching Protocols" if the handshake checks out (see handshake.rs), after which every message
+// the client sends is echoed back, or on /pubsub (or with the "pubsub" subprotocol), goes through
+// the publish/subscribe hub (see hub.rs). Clients offering permessage-deflate get their messages
+// compressed (see deflate.rs).
+// The mio thread owns the sockets and does the parsing, `handler` runs on the thread pool and
+// its response comes back through the completion queue (see completion.rs).
+// Testing: cargo run -p multi-threaded-http, then
+// curl -i -H "Connection: Upgrade" -H "Upgrade: websocket" -H "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==" -H "Sec-WebSocket-Version: 13" http://127.0.0.1:9000/
 
+#[cfg(test)]
+mod autobahn;
+mod completion;
+mod config;
+mod deflate;
+mod frame;
+mod handshake;
+mod hub;
+#[cfg(test)]
+mod testing;
+
+use completion::{Completions, WAKER};
+use config::Config;
+use deflate::Deflater;
+use frame::{Decoder, Frame, Message, Opcode};
+use handshake::{Handshake, Rejection};
+use hub::{Command, Hub};
 use log::debug;
 use mio::{
     net::{TcpListener, TcpStream},
-    Events, Interest, Poll, Token,
+    Events, Interest, Poll, Registry, Token,
 };
+use multi_threaded_http::pool::{panic_message, JobHandle, JobResult, SubmitError, ThreadPool};
 use parser_combinators::{
-    http::{as_string, parse_http_request, Header, Request, Response},
+    http::{as_string, parse_request, HeaderMap, Invalid, Request, Response},
     stream::ByteStream,
 };
-use pool::ThreadPool;
 use sha1::{Digest, Sha1};
 use std::{
     collections::HashMap,
     io::{Read, Write},
+    net::SocketAddr,
+    sync::Arc,
+    task::Poll as JobPoll,
+    thread,
+    time::Duration,
 };
 
+const LISTENER: Token = Token(0);
+
+// A connection with this much waiting to be written isn't read from until it has caught up,
+// and a subscriber that would go over it is disconnected
+const MAX_PENDING: usize = 1024 * 1024;
+
+// Reading stops once this much has been received and not handled yet, enough for a frame of the
+// largest message (and more than the largest request). The rest waits in the socket.
+const MAX_RECEIVED: usize = frame::MAX_MESSAGE_SIZE + 14;
+
 fn blocks(e: &std::io::Error) -> bool {
     e.kind() == std::io::ErrorKind::WouldBlock
 }
 
-fn get_header<'a>(headers: &'a Vec<Header>, name: &String) -> Option<&'a String> {
-    headers.iter().find(|h| &h.name == name).map(|h| &h.value)
-}
-
-fn res_sec_websocket_accept(req_sec_websocket_key: &String) -> String {
+fn res_sec_websocket_accept(req_sec_websocket_key: &str) -> String {
     let mut hasher = Sha1::new();
     hasher.update(req_sec_websocket_key.to_owned() + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
     base64::encode(hasher.finalize())
 }
 
-fn handler(req: Request) -> Response {
-    let connection = get_header(&req.headers, &"Connection".to_string())
-        .map(|h| h.contains("Upgrade"))
-        .unwrap_or_default();
+fn response(code: u16, message: &str, headers: HeaderMap, content: Vec<u8>) -> Response {
+    Response {
+        protocol: "HTTP/1.1".to_string(),
+        code,
+        message: message.to_string(),
+        headers,
+        content,
+    }
+}
 
-    let upgrade = get_header(&req.headers, &"Upgrade".to_string())
-        .map(|h| h.contains("websocket"))
-        .unwrap_or_default();
+fn text(code: u16, message: &str, body: &str) -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "text/plain");
+    headers.insert("Content-Length", &body.len().to_string());
+    response(code, message, headers, body.as_bytes().to_vec())
+}
 
-    
+// what answers a request, run on the thread pool
+type Respond = fn(Request, &Handshake) -> Response;
+
+fn handler(req: Request, handshake: &Handshake) -> Response {
+    // both are lists, "Connection: keep-alive, Upgrade" asks for an upgrade too
+    let connection = req.headers.has_token("Connection", "upgrade");
+    let upgrade = req.headers.has_token("Upgrade", "websocket");
+
+    if !(connection && upgrade) {
+        return text(200, "OK", "hello\n");
+    }
+
+    let protocol = match handshake.check(&req) {
+        Ok(protocol) => protocol,
+        Err(Rejection::BadRequest(reason)) => {
+            return text(400, "Bad Request", &format!("{}\n", reason))
+        }
+        Err(Rejection::Forbidden) => return text(403, "Forbidden", "origin not allowed\n"),
+        Err(Rejection::UnsupportedVersion) => {
+            let mut res = text(426, "Upgrade Required", "unsupported websocket version\n");
+            res.headers
+                .insert("Sec-WebSocket-Version", handshake::VERSION);
+            return res;
+        }
+    };
+
+    // the key proves the server understood the handshake, it's echoed back hashed
+    let key = req.headers.get("Sec-WebSocket-Key").unwrap(); // checked above
+    let mut headers = HeaderMap::new();
+    headers.insert("Upgrade", "websocket");
+    headers.insert("Connection", "Upgrade");
+    headers.insert(
+        "Sec-WebSocket-Accept",
+        &res_sec_websocket_accept(key.trim()),
+    );
+    if let Some(protocol) = protocol {
+        headers.insert("Sec-WebSocket-Protocol", &protocol);
+    }
+    // an offer that can't be accepted is just left out of the answer
+    if let Some(params) = deflate::negotiate(req.headers.tokens("Sec-WebSocket-Extensions")) {
+        headers.insert("Sec-WebSocket-Extensions", &params.to_string());
+    }
+    response(101, "Switching Protocols", headers, vec![])
 }
 
 struct Handler {
     token: Token,
     socket: TcpStream,
     is_open: bool,
+    // once upgraded the connection carries websocket frames instead of HTTP requests
+    is_websocket: bool,
+    decoder: Decoder,
+    // a close frame (or the answer to an invalid request) has been sent, the connection ends
+    // once it's written out
+    is_closing: bool,
+    // upgraded at /pubsub or to the pubsub subprotocol, messages are hub commands rather than echoed
+    is_pubsub: bool,
+    // compresses what's sent once permessage-deflate has been agreed on
+    deflater: Option<Deflater>,
+    // the path of the request being answered
+    path: String,
     recv_stream: ByteStream,
     send_stream: ByteStream,
+    // the handler job for the request being answered, the next one waits until it's done
+    // so responses go out in the order the requests came in
+    job: Option<JobHandle<Response>>,
+}
+
+impl Handler {
+    fn new(token: Token, socket: TcpStream) -> Handler {
+        Handler {
+            token,
+            socket,
+            is_open: true,
+            is_websocket: false,
+            decoder: Decoder::server(),
+            is_closing: false,
+            is_pubsub: false,
+            deflater: None,
+            path: String::new(),
+            recv_stream: ByteStream::with_capacity(1024),
+            send_stream: ByteStream::with_capacity(1024),
+            job: None,
+        }
+    }
+
+    // read everything the socket has (it's edge triggered, what's left unread won't be announced again)
+    fn read(&mut self) {
+        if self.pending() >= MAX_PENDING {
+            // the client isn't keeping up, leave the rest in the socket (TCP slows it down)
+            return;
+        }
+        let mut buffer = [0_u8; 1024];
+        while self.recv_stream.len() < MAX_RECEIVED {
+            match self.socket.read(&mut buffer) {
+                Ok(0) => {
+                    // the client closed its end
+                    self.is_open = false;
+                    break;
+                }
+                Ok(n) => self.recv_stream.put(&buffer[..n]),
+                Err(e) if blocks(&e) => break,
+                Err(_) => {
+                    self.is_open = false;
+                    break;
+                }
+            }
+        }
+    }
+
+    // the next complete request in the receive buffer, if there is one
+    // What isn't a request is answered with a 400 and the connection is closed, there's no telling
+    // where the next request would start.
+    fn pull(&mut self) -> Option<Request> {
+        let req = match parse_request(self.recv_stream.as_ref()) {
+            Ok(Some((req, len))) => {
+                self.recv_stream.skip(len);
+                Some(req)
+            }
+            Ok(None) => None,
+            Err(Invalid(reason)) => {
+                debug!("invalid request ({:?}): {}", self.token, reason);
+                let mut res = text(400, "Bad Request", &format!("{}\n", reason));
+                res.headers.insert("Connection", "close");
+                self.push(res);
+                self.recv_stream.clear();
+                self.is_closing = true;
+                None
+            }
+        };
+        // forget the bytes the request was parsed from
+        self.recv_stream.pull();
+        req
+    }
+
+    fn push(&mut self, res: Response) {
+        if res.code == 101 {
+            self.is_websocket = true;
+            let protocol = res.headers.get("Sec-WebSocket-Protocol");
+            self.is_pubsub = self.path == "/pubsub" || protocol.is_some_and(|p| p == "pubsub");
+            let extensions = res.headers.get("Sec-WebSocket-Extensions");
+            if let Some(params) = extensions.and_then(deflate::Params::parse) {
+                self.deflater = Some(params.deflater());
+                self.decoder.set_inflater(params.inflater());
+            }
+        }
+        self.send_stream.put(as_string(res).as_bytes());
+    }
+
+    fn send(&mut self, mut frame: Frame) {
+        match frame.opcode {
+            Opcode::Close => self.is_closing = true,
+            Opcode::Text | Opcode::Binary => {
+                if let Some(payload) = self
+                    .deflater
+                    .as_mut()
+                    .and_then(|d| d.deflate(&frame.payload))
+                {
+                    frame.payload = payload;
+                    frame.rsv = frame::RSV1;
+                }
+            }
+            _ => {}
+        }
+        frame.encode(&mut self.send_stream, None);
+    }
+
+    fn pending(&self) -> usize {
+        self.send_stream.as_ref().len()
+    }
+
+    // handle every websocket message that's fully buffered (while the client keeps up with the replies)
+    // Returns the (topic, message) pairs the client published.
+    fn exchange(&mut self, hub: &mut Hub) -> Vec<(String, String)> {
+        let mut published = Vec::new();
+        while !self.is_closing && self.pending() < MAX_PENDING {
+            match self.decoder.next(&mut self.recv_stream) {
+                Ok(Some(Message::Text(text))) if self.is_pubsub => match hub::parse(&text) {
+                    Some(Command::Subscribe(topic)) => hub.subscribe(&topic, self.token),
+                    Some(Command::Unsubscribe(topic)) => hub.unsubscribe(&topic, self.token),
+                    Some(Command::Publish(topic, message)) => published.push((topic, message)),
+                    None => self.send(Frame::new(Opcode::Text, b"ERR unknown command".to_vec())),
+                },
+                Ok(Some(Message::Binary(_))) if self.is_pubsub => {
+                    self.send(Frame::new(Opcode::Text, b"ERR unknown command".to_vec()))
+                }
+                Ok(Some(Message::Text(text))) => {
+                    self.send(Frame::new(Opcode::Text, text.into_bytes()))
+                }
+                Ok(Some(Message::Binary(data))) => self.send(Frame::new(Opcode::Binary, data)),
+                Ok(Some(Message::Ping(data))) => self.send(Frame::new(Opcode::Pong, data)),
+                Ok(Some(Message::Pong(_))) => {}
+                Ok(Some(Message::Close(status))) => {
+                    // answer with the same status, then the server closes the TCP connection
+                    let code = status.map_or(frame::CLOSE_NORMAL, |(code, _)| code);
+                    self.send(Frame::close(code, ""));
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("failing websocket {:?}: {:?}", self.token, e);
+                    self.send(Frame::close(e.close_code(), ""));
+                }
+            }
+        }
+        if self.is_closing {
+            // nothing more is read once the close frame is out
+            self.recv_stream.clear();
+        }
+        published
+    }
+
+    // Add a message published to a topic this connection subscribed to
+    // Returns false if the connection is too far behind to take it, it's closed instead.
+    fn deliver(&mut self, message: &str, registry: &Registry) -> bool {
+        if self.is_closing {
+            return false;
+        }
+        if self.pending() + message.len() > MAX_PENDING {
+            debug!("slow consumer {:?}", self.token);
+            self.send(Frame::close(frame::CLOSE_POLICY_VIOLATION, "slow consumer"));
+            self.flush(registry);
+            return false;
+        }
+        self.send(Frame::new(Opcode::Text, message.as_bytes().to_vec()));
+        self.flush(registry);
+        true
+    }
+
+    // write out as much of the send buffer as the socket takes, returns true when it's all gone
+    fn put(&mut self) -> bool {
+        let mut written = 0;
+        let pending = self.send_stream.as_ref();
+        while written < pending.len() {
+            match self.socket.write(&pending[written..]) {
+                Ok(0) => {
+                    self.is_open = false;
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(e) if blocks(&e) => break,
+                Err(_) => {
+                    self.is_open = false;
+                    break;
+                }
+            }
+        }
+
+        // keep what didn't fit for the next writable event
+        frame::consume(&mut self.send_stream, written);
+        self.send_stream.as_ref().is_empty()
+    }
+
+    // hand the request to the pool, the response is pushed once the job is done
+    fn dispatch(
+        &mut self,
+        req: Request,
+        pool: &mut ThreadPool,
+        completions: &Arc<Completions>,
+        handshake: &Arc<Handshake>,
+        respond: Respond,
+    ) {
+        debug!("{} {} ({:?})", req.method, req.path, self.token);
+        self.path = req.path.clone();
+        let handshake = Arc::clone(handshake);
+        let mut job = match pool.try_submit_with_result(move || respond(req, &handshake)) {
+            Ok(job) => job,
+            // every worker is busy and the queue is full, better to say so than to queue forever
+            Err(SubmitError::QueueFull) | Err(SubmitError::ShutDown) => {
+                return self.push(text(503, "Service Unavailable", "server busy\n"));
+            }
+        };
+        match completions.poll(self.token, &mut job) {
+            JobPoll::Ready(result) => self.finish(result),
+            JobPoll::Pending => self.job = Some(job),
+        }
+    }
+
+    // the job this connection was waiting for may be done
+    fn complete(&mut self, completions: &Arc<Completions>) {
+        if let Some(job) = self.job.as_mut() {
+            if let JobPoll::Ready(result) = completions.poll(self.token, job) {
+                self.job = None;
+                self.finish(result);
+            }
+        }
+    }
+
+    fn finish(&mut self, result: JobResult<Response>) {
+        let res = result.unwrap_or_else(|payload| {
+            debug!("handler panicked: {}", panic_message(&*payload));
+            text(500, "Internal Server Error", "internal server error\n")
+        });
+        self.push(res);
+    }
+
+    // start on every request (or message) that's fully buffered, then wait for whichever event comes next
+    // Returns what the client published to the hub.
+    fn serve(
+        &mut self,
+        registry: &Registry,
+        pool: &mut ThreadPool,
+        completions: &Arc<Completions>,
+        handshake: &Arc<Handshake>,
+        respond: Respond,
+        hub: &mut Hub,
+    ) -> Vec<(String, String)> {
+        // the request upgrading the connection may be followed right away by frames,
+        // they stay buffered until the upgrade is answered
+        while !self.is_websocket && !self.is_closing && self.job.is_none() {
+            match self.pull() {
+                Some(req) => self.dispatch(req, pool, completions, handshake, respond),
+                None => break,
+            }
+        }
+        let published = if self.is_websocket {
+            self.exchange(hub)
+        } else {
+            Vec::new()
+        };
+        self.flush(registry);
+        published
+    }
+
+    // write what's pending, and wait for room for the rest or for more to read
+    fn flush(&mut self, registry: &Registry) {
+        let interest = if self.put() {
+            if self.is_closing {
+                self.is_open = false;
+            }
+            Interest::READABLE
+        } else {
+            // the socket's send buffer is full, the rest goes out once there's room
+            Interest::WRITABLE
+        };
+        registry
+            .reregister(&mut self.socket, self.token, interest)
+            .unwrap();
+    }
+}
+
+// Everything the event loop works with besides the listener
+struct Server {
+    handlers: HashMap<Token, Handler>,
+    hub: Hub,
+    pool: ThreadPool,
+    completions: Arc<Completions>,
+    // what websocket upgrades are accepted, shared with the handler jobs
+    handshake: Arc<Handshake>,
+    respond: Respond,
+}
+
+impl Server {
+    // Catch up with the connection after an event on its socket or a finished handler job
+    fn ready(&mut self, registry: &Registry, token: Token) {
+        let handler = match self.handlers.get_mut(&token) {
+            Some(handler) => handler,
+            None => return, // already closed
+        };
+        handler.complete(&self.completions);
+        handler.read();
+        let published = handler.serve(
+            registry,
+            &mut self.pool,
+            &self.completions,
+            &self.handshake,
+            self.respond,
+            &mut self.hub,
+        );
+        let is_open = handler.is_open;
+
+        for (topic, message) in published {
+            self.publish(registry, &topic, &message);
+        }
+        if !is_open {
+            self.close(token);
+        }
+    }
+
+    fn publish(&mut self, registry: &Registry, topic: &str, message: &str) {
+        // every subscriber frames it itself, compression contexts are per connection
+        for token in self.hub.subscribers(topic) {
+            let handler = match self.handlers.get_mut(&token) {
+                Some(handler) => handler,
+                None => continue,
+            };
+            let delivered = handler.deliver(message, registry);
+            if !handler.is_open {
+                self.close(token);
+            } else if !delivered {
+                self.hub.leave(token);
+            }
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        debug!("closing {:?}", token);
+        self.handlers.remove(&token);
+        self.hub.leave(token);
+    }
+}
+
+// The upgrade checks for a server at `addr`: pages from `origins` may open websockets, or if none
+// are given, pages served from the server's own address
+fn upgrade_checks(addr: SocketAddr, origins: &[String]) -> Handshake {
+    let mut own = Vec::new();
+    if origins.is_empty() {
+        if addr.ip().is_loopback() || addr.ip().is_unspecified() {
+            own.push(format!("http://localhost:{}", addr.port()));
+            own.push(format!("http://127.0.0.1:{}", addr.port()));
+        } else {
+            own.push(format!("http://{}", addr));
+        }
+    }
+    origins
+        .iter()
+        .chain(&own)
+        .fold(Handshake::default(), |handshake, origin| {
+            handshake.allow_origin(origin)
+        })
+        .protocol("pubsub")
+}
+
+// Serve connections from `listener` forever, upgrading those that pass `handshake`
+fn run(listener: TcpListener, handshake: Handshake) {
+    run_with(listener, handshake, handler)
+}
+
+// Like run, with requests answered by `respond`
+fn run_with(mut listener: TcpListener, handshake: Handshake, respond: Respond) {
+    let mut poll = Poll::new().unwrap();
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)
+        .unwrap();
+
+    // one worker per core at most, and some room for requests to wait for one
+    let workers = thread::available_parallelism().map_or(4, |n| n.get());
+    let pool = ThreadPool::builder()
+        .min_workers(1)
+        .max_workers(workers)
+        .keep_alive(Duration::from_secs(30))
+        .queue_limit(1024)
+        .name("handler")
+        .build();
+    let mut server = Server {
+        handlers: HashMap::new(),
+        hub: Hub::default(),
+        pool,
+        completions: Completions::new(poll.registry()),
+        handshake: Arc::new(handshake),
+        respond,
+    };
+
+    let mut events = Events::with_capacity(1024);
+    let mut counter: usize = 0;
+
+    loop {
+        poll.poll(&mut events, None).unwrap();
+
+        for event in &events {
+            match event.token() {
+                LISTENER => loop {
+                    match listener.accept() {
+                        Ok((mut socket, address)) => {
+                            counter += 1;
+                            let token = Token(counter);
+                            debug!("connection from {} ({:?})", address, token);
+                            poll.registry()
+                                .register(&mut socket, token, Interest::READABLE)
+                                .unwrap();
+                            server.handlers.insert(token, Handler::new(token, socket));
+                        }
+                        Err(e) if blocks(&e) => break,
+                        Err(e) => panic!("unexpected error: {}", e),
+                    }
+                },
+                WAKER => {
+                    // handler jobs finished, send their responses
+                    for token in server.completions.take() {
+                        server.ready(poll.registry(), token);
+                    }
+                }
+                token => server.ready(poll.registry(), token),
+            }
+        }
+    }
 }
 
 fn main() {
-    println!("Hello, world!");
+    env_logger::init();
+
+    let config = Config::from_args();
+    let listener = TcpListener::bind(config.addr.parse().unwrap()).unwrap();
+    let handshake = upgrade_checks(listener.local_addr().unwrap(), &config.origins);
+    run(listener, handshake);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{header, request, valid_upgrade, Client};
+    use std::{
+        io::ErrorKind,
+        net::TcpStream as StdStream,
+        sync::{Condvar, Mutex},
+    };
+
+    // (started, released) for the request to /slow
+    static GATE: Mutex<(bool, bool)> = Mutex::new((false, false));
+    static GATE_CHANGED: Condvar = Condvar::new();
+
+    // a handler that holds on to /slow until the test lets it go
+    fn slow(req: Request, handshake: &Handshake) -> Response {
+        if req.path != "/slow" {
+            return handler(req, handshake);
+        }
+        let mut gate = GATE.lock().unwrap();
+        gate.0 = true;
+        GATE_CHANGED.notify_all();
+        while !gate.1 {
+            gate = GATE_CHANGED.wait(gate).unwrap();
+        }
+        text(200, "OK", "slow\n")
+    }
+
+    fn start(respond: Respond) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || run_with(listener, upgrade_checks(addr, &[]), respond));
+        addr
+    }
+
+    // A client sending faster than its messages are handled is left waiting in the socket,
+    // rather than everything it sends piling up in the receive buffer
+    #[test]
+    fn reads_stop_at_the_cap() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = StdStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let mut handler = Handler::new(Token(1), TcpStream::from_std(socket));
+        let writer = thread::spawn(move || client.write_all(&vec![0; 2 * MAX_RECEIVED]));
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while handler.recv_stream.len() < MAX_RECEIVED {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "never reached the cap"
+            );
+            handler.read();
+            thread::sleep(Duration::from_millis(1));
+        }
+        // one buffer over at most, and more reads don't pull anything until it's handled
+        let len = handler.recv_stream.len();
+        assert!(len < MAX_RECEIVED + 1024, "{}", len);
+        handler.read();
+        assert_eq!(handler.recv_stream.len(), len);
+        assert!(handler.is_open);
+
+        // the writer gives up once the connection is gone
+        drop(handler);
+        assert!(writer.join().unwrap().is_err());
+    }
+
+    // A handler job that takes its time doesn't hold up the event loop, and the requests pipelined
+    // behind it are still answered in order once it's done
+    #[test]
+    fn slow_handlers_keep_responses_in_order() {
+        let addr = start(slow);
+        let (mut other, head) =
+            Client::open_at(addr, &request("GET /echo", &valid_upgrade(&[], &[])));
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+
+        let mut socket = StdStream::connect(addr).unwrap();
+        let pipelined = request("GET /slow", &[]) + &request("GET /", &[]);
+        socket.write_all(pipelined.as_bytes()).unwrap();
+        let mut gate = GATE.lock().unwrap();
+        while !gate.0 {
+            gate = GATE_CHANGED.wait(gate).unwrap();
+        }
+        drop(gate);
+
+        // the loop still answers another connection while the job runs
+        other.send_text(b"still there");
+        other.expect_echo(b"still there");
+        other.sync();
+        // and nothing has been answered on this one, / waits its turn behind /slow
+        socket
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let mut buffer = [0_u8; 1024];
+        match socket.read(&mut buffer) {
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            other => panic!("answered before the handler was done: {:?}", other),
+        }
+
+        GATE.lock().unwrap().1 = true;
+        GATE_CHANGED.notify_all();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut received = String::new();
+        while !received.ends_with("hello\n") {
+            let n = socket.read(&mut buffer).unwrap();
+            assert!(n > 0, "connection closed after {:?}", received);
+            received.push_str(std::str::from_utf8(&buffer[..n]).unwrap());
+        }
+        let slow = received.find("slow\n").expect("no answer to /slow");
+        assert!(slow < received.find("hello\n").unwrap(), "{}", received);
+        assert_eq!(
+            received.matches("HTTP/1.1 200 OK").count(),
+            2,
+            "{}",
+            received
+        );
+    }
+
+    // what can't be parsed as a request is answered with a 400, then the connection is closed
+    #[test]
+    fn invalid_requests() {
+        let too_long = format!("Content-Length: {}", usize::MAX);
+        let too_large = format!("Content-Length: {}", 2 * 1024 * 1024);
+        let invalid: [(&str, &[&str], &str); 5] = [
+            ("GET /echo", &["Bad Name: x"], "malformed request head"),
+            ("GET", &[], "malformed request head"),
+            (
+                "POST /echo",
+                &["Content-Length: 99999999999999999999999999"],
+                "invalid Content-Length",
+            ),
+            ("POST /echo", &[&too_long], "Content-Length too large"),
+            ("POST /echo", &[&too_large], "request body too large"),
+        ];
+        for (target, headers, reason) in invalid.iter() {
+            let (mut client, head) = Client::open(&request(target, headers));
+            assert!(head.starts_with("HTTP/1.1 400 "), "{}", head);
+            assert_eq!(header(&head, "Connection").as_deref(), Some("close"));
+            while client.fill() {}
+            assert_eq!(client.recv.as_ref(), format!("{}\n", reason).as_bytes());
+        }
+
+        // requests before the invalid one are still answered
+        let mut pipelined = request("GET /", &[]);
+        pipelined.push_str("GET\r\n\r\n");
+        let (mut client, head) = Client::open(&pipelined);
+        assert!(head.starts_with("HTTP/1.1 200 "), "{}", head);
+        while client.fill() {}
+        let rest = String::from_utf8_lossy(client.recv.as_ref()).into_owned();
+        assert!(rest.starts_with("hello\nHTTP/1.1 400 "), "{}", rest);
+    }
 }
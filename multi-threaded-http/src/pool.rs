@@ -1,92 +1,1145 @@
+// A work-stealing thread pool
+// Submitted jobs go into a global injector queue. Every worker has its own deque and refills it
+// from the injector a batch at a time, so workers mostly take jobs without touching shared state.
+// A worker that runs dry takes from the injector again, or steals from the other workers' deques,
+// and goes to sleep only when there's nothing anywhere.
+// The pool keeps between `min_workers` and `max_workers` threads (see Builder). Another one is
+// started when more jobs are queued than there are idle workers, and a worker that found nothing
+// to do for `keep_alive` exits again. The queue can be limited: `submit` then waits for room, while
+// `try_submit` gives up with an error.
+// A panicking job doesn't take its worker down, the panic is caught and reported to the hook set
+// with `on_panic`.
+// `submit_with_result` hands back a JobHandle for the job's return value, which can be waited on
+// from a plain thread or awaited from async code.
+
+use crossbeam_deque::{Injector, Stealer, Worker as Deque};
+use log::warn;
 use std::{
+	any::Any,
+	future::Future,
+	iter,
+	panic::{self, AssertUnwindSafe},
+	pin::Pin,
 	sync::{
+		atomic::{self, AtomicBool, AtomicUsize, Ordering},
 		mpsc::{channel, Receiver, Sender},
-		Arc, Mutex,
+		Arc, Condvar, Mutex,
 	},
+	task::{Context as TaskContext, Poll, Waker},
 	thread::{self, JoinHandle},
+	time::{Duration, Instant},
 };
 
 // Send typ means the jobs should be able to move around in the threads?
 type Runnable = Box<dyn FnOnce() + Send + 'static>;
 
-// Job is either a Task or Stop (waiting to stop)
-// If it is a task, then it has an associated function
-enum Job {
-	Task(Runnable),
-	Stop,
+// called with the worker's id and the panic payload when a job panics
+type PanicHook = dyn Fn(usize, &(dyn Any + Send)) + Send + Sync;
+
+// Why try_submit didn't queue a job
+#[derive(Debug, PartialEq)]
+pub enum SubmitError {
+	QueueFull, // the queue was at its limit
+	ShutDown,  // the pool doesn't take jobs any more
 }
 
-pub struct ThreadPool {
-	sender: Sender<Job>,  // a channel to send work to worker
-	workers: Vec<Worker>, // a bunch of worker threads
+// what the pool and its workers share
+struct Shared {
+	injector: Injector<Runnable>,
+	stealers: Vec<Stealer<Runnable>>,
+	shutdown: AtomicBool, // no more jobs are coming, workers exit once everything is done
+	discard: AtomicBool,  // set by shutdown_now, workers drop queued jobs instead of running them
+	// idle workers wait on the condvar, `sleeping` lets submit skip the lock when nobody is waiting
+	sleeping: AtomicUsize,
+	lock: Mutex<()>,
+	wakeup: Condvar,
+	panic_hook: Mutex<Option<Arc<PanicHook>>>,
+	live: AtomicUsize, // worker threads started and not retired
+	min_workers: usize,
+	keep_alive: Duration,
+	// jobs submitted and not taken by a worker yet, for the queue limit
+	queued: AtomicUsize,
+	// a submit waiting for room in the queue waits on `room`, under `lock`
+	blocked: AtomicBool,
+	room: Condvar,
+	name: Option<String>, // worker threads are called "<name>-<id>"
 }
 
-impl ThreadPool {
-	pub fn new(size: usize) -> ThreadPool {
-		let (sender, receiver) = channel();
-		let receiver = Arc::new(Mutex::new(receiver)); // goes to the worker
+impl Shared {
+	// wake sleeping workers, after a job was queued or the pool was shut down
+	fn notify(&self, all: bool) {
+		// pairs with the fence in sleep: either the worker sees the job, or this sees the worker
+		atomic::fence(Ordering::SeqCst);
+		if self.sleeping.load(Ordering::SeqCst) > 0 {
+			let _guard = self.lock.lock().unwrap();
+			if all {
+				self.wakeup.notify_all();
+			} else {
+				self.wakeup.notify_one();
+			}
+		}
+	}
 
-		let mut workers = Vec::with_capacity(size);
+	// The next job for the worker owning `local`: its own first, then a batch from the injector,
+	// then one stolen from another worker
+	fn find_job(&self, local: &Deque<Runnable>) -> Option<Runnable> {
+		local.pop().or_else(|| {
+			iter::repeat_with(|| {
+				self.injector
+					.steal_batch_and_pop(local)
+					.or_else(|| self.stealers.iter().map(|s| s.steal()).collect())
+			})
+			// Retry means another thread got in the way, not that the queues are empty
+			.find(|s| !s.is_retry())
+			.and_then(|s| s.success())
+		})
+	}
 
-		for _ in 0..size {
-			// every worker gets a copy of the receiver associated with the sender that is sending work
-			let worker = Worker::new(Arc::clone(&receiver));
-			workers.push(worker);
+	// Block until there might be something to do, or for at most `timeout`
+	// Jobs are checked for again under the lock, a submit (or a worker with jobs to spare in its
+	// deque) that doesn't see this worker sleeping queued its job before that check.
+	fn sleep(&self, timeout: Option<Duration>) {
+		let guard = self.lock.lock().unwrap();
+		self.sleeping.fetch_add(1, Ordering::SeqCst);
+		atomic::fence(Ordering::SeqCst);
+		if self.is_idle() && !self.shutdown.load(Ordering::SeqCst) {
+			match timeout {
+				Some(timeout) => drop(self.wakeup.wait_timeout(guard, timeout).unwrap()),
+				None => drop(self.wakeup.wait(guard).unwrap()),
+			}
+		}
+		self.sleeping.fetch_sub(1, Ordering::SeqCst);
+	}
+
+	fn is_idle(&self) -> bool {
+		self.injector.is_empty() && self.stealers.iter().all(|s| s.is_empty())
+	}
+
+	// a worker took a job off the queue, a blocked submit can go on
+	fn dequeued(&self) {
+		self.queued.fetch_sub(1, Ordering::SeqCst);
+		if self.blocked.load(Ordering::SeqCst) {
+			let _guard = self.lock.lock().unwrap();
+			self.room.notify_one();
 		}
+	}
 
-		ThreadPool { sender, workers }
+	// Block until the queue might have room
+	// Like sleep, the queue is checked again after `blocked` is set so a worker can't miss it.
+	fn wait_for_room(&self, limit: usize) {
+		let guard = self.lock.lock().unwrap();
+		self.blocked.store(true, Ordering::SeqCst);
+		if self.queued.load(Ordering::SeqCst) >= limit {
+			drop(self.room.wait(guard).unwrap());
+		}
+		self.blocked.store(false, Ordering::SeqCst);
+	}
+
+	// Whether an idle worker may exit, it's counted out if so
+	fn retire(&self) -> bool {
+		let mut live = self.live.load(Ordering::SeqCst);
+		while live > self.min_workers {
+			match self
+				.live
+				.compare_exchange(live, live - 1, Ordering::SeqCst, Ordering::SeqCst)
+			{
+				Ok(_) => return true,
+				Err(current) => live = current,
+			}
+		}
+		false
+	}
+
+	fn report_panic(&self, worker: usize, payload: &(dyn Any + Send)) {
+		// called outside the lock, a hook that panics itself can't poison it
+		let hook = self.panic_hook.lock().unwrap().clone();
+		if let Some(hook) = hook {
+			hook(worker, payload);
+		}
+	}
+}
+
+// The message a panic was started with, for panics raised by panic!/unwrap/expect
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+	match payload.downcast_ref::<&str>() {
+		Some(message) => message,
+		None => payload
+			.downcast_ref::<String>()
+			.map_or("(not a string)", |message| message.as_str()),
+	}
+}
+
+// How many threads a pool runs and how much it queues
+// ThreadPool::builder().min_workers(2).max_workers(16).queue_limit(1000).build()
+pub struct Builder {
+	min_workers: usize,
+	max_workers: usize,
+	keep_alive: Duration,
+	queue_limit: Option<usize>,
+	name: Option<String>,
+}
+
+impl Default for Builder {
+	// one worker, growing to one per core, no queue limit
+	fn default() -> Builder {
+		Builder {
+			min_workers: 1,
+			max_workers: thread::available_parallelism().map_or(4, |n| n.get()),
+			keep_alive: Duration::from_secs(60),
+			queue_limit: None,
+			name: None,
+		}
+	}
+}
+
+impl Builder {
+	// the workers that are always there, at least one
+	pub fn min_workers(mut self, n: usize) -> Builder {
+		self.min_workers = n;
+		self
+	}
+
+	// how far the pool grows when jobs back up
+	pub fn max_workers(mut self, n: usize) -> Builder {
+		self.max_workers = n;
+		self
+	}
+
+	// how long a worker over the minimum stays around without anything to do
+	pub fn keep_alive(mut self, keep_alive: Duration) -> Builder {
+		self.keep_alive = keep_alive;
+		self
+	}
+
+	// at most `limit` jobs wait for a worker, submit blocks and try_submit fails beyond that
+	pub fn queue_limit(mut self, limit: usize) -> Builder {
+		self.queue_limit = Some(limit);
+		self
+	}
+
+	// name the worker threads "<name>-0", "<name>-1", ...
+	pub fn name(mut self, name: &str) -> Builder {
+		self.name = Some(name.to_string());
+		self
+	}
+
+	pub fn build(self) -> ThreadPool {
+		// with no worker at all a queued job could be left waiting for the next submit
+		assert!(self.min_workers >= 1, "a pool needs at least one worker");
+		assert!(
+			self.min_workers <= self.max_workers,
+			"min_workers is over max_workers"
+		);
+		// a limit of zero would leave submit waiting for room that never comes
+		assert!(
+			self.queue_limit != Some(0),
+			"the queue limit has to be at least one"
+		);
+
+		// the deques have to exist up front, every worker needs all the stealers
+		let deques: Vec<Deque<Runnable>> =
+			(0..self.max_workers).map(|_| Deque::new_fifo()).collect();
+		let shared = Arc::new(Shared {
+			injector: Injector::new(),
+			stealers: deques.iter().map(|d| d.stealer()).collect(),
+			shutdown: AtomicBool::new(false),
+			discard: AtomicBool::new(false),
+			sleeping: AtomicUsize::new(0),
+			lock: Mutex::new(()),
+			wakeup: Condvar::new(),
+			panic_hook: Mutex::new(None),
+			live: AtomicUsize::new(self.min_workers),
+			min_workers: self.min_workers,
+			keep_alive: self.keep_alive,
+			queued: AtomicUsize::new(0),
+			blocked: AtomicBool::new(false),
+			room: Condvar::new(),
+			name: self.name,
+		});
+		let (exit_sender, exited) = channel();
+
+		let workers: Vec<Worker> = deques
+			.into_iter()
+			.enumerate()
+			.map(|(id, local)| Worker::new(id, local))
+			.collect();
+		for worker in &workers[..self.min_workers] {
+			worker.start(&shared, &exit_sender);
+		}
+
+		ThreadPool {
+			shared,
+			workers,
+			exit_sender,
+			exited,
+			queue_limit: self.queue_limit,
+		}
+	}
+}
+
+pub struct ThreadPool {
+	shared: Arc<Shared>,
+	workers: Vec<Worker>, // a slot for each of up to max_workers threads
+	exit_sender: Sender<usize>,
+	exited: Receiver<usize>, // workers send their id here on their way out
+	queue_limit: Option<usize>,
+}
+
+impl ThreadPool {
+	// a pool of exactly `size` workers
+	// Panics if `size` is zero, a pool needs at least one worker.
+	pub fn new(size: usize) -> ThreadPool {
+		ThreadPool::builder()
+			.min_workers(size)
+			.max_workers(size)
+			.build()
+	}
+
+	pub fn builder() -> Builder {
+		Builder::default()
 	}
 
 	// sending job to a thread?
+	// Waits for room if the queue is at its limit. Once the pool has been shut down the job is
+	// dropped without running and a warning is logged, try_submit returns the error instead.
 	pub fn submit<F>(&mut self, f: F)
 	where
 		F: FnOnce() + Send + 'static,
 	{
-		let job = Job::Task(Box::new(f)); // create a new job
-		self.sender.send(job).unwrap(); // send job to threadpool
+		if let Err(e) = self.queue(Box::new(f), true) {
+			warn!("job dropped: {:?}", e);
+		}
+	}
+
+	// Like submit, but a full queue fails rather than waits
+	pub fn try_submit<F>(&mut self, f: F) -> Result<(), SubmitError>
+	where
+		F: FnOnce() + Send + 'static,
+	{
+		self.queue(Box::new(f), false)
 	}
 
+	fn queue(&mut self, job: Runnable, wait: bool) -> Result<(), SubmitError> {
+		// the workers may be gone already, nothing would run the job
+		if self.shared.shutdown.load(Ordering::SeqCst) {
+			return Err(SubmitError::ShutDown);
+		}
+		if let Some(limit) = self.queue_limit {
+			while self.shared.queued.load(Ordering::SeqCst) >= limit {
+				if !wait {
+					return Err(SubmitError::QueueFull);
+				}
+				self.shared.wait_for_room(limit);
+			}
+		}
+		self.shared.queued.fetch_add(1, Ordering::SeqCst);
+		self.shared.injector.push(job);
+		self.shared.notify(false);
+
+		// the queue is backing up, more jobs are waiting than there are workers free to take them
+		let live = self.shared.live.load(Ordering::SeqCst);
+		let busy = self.workers.iter().filter(|w| w.is_busy()).count();
+		let queued = self.shared.queued.load(Ordering::SeqCst);
+		if queued > live.saturating_sub(busy) && live < self.workers.len() {
+			self.grow();
+		}
+		Ok(())
+	}
+
+	// Start another worker, in the slot of one that has exited
+	fn grow(&mut self) {
+		// their threads are done or just about, joining won't block for long
+		while let Ok(id) = self.exited.try_recv() {
+			self.workers[id].join();
+		}
+		if let Some(worker) = self.workers.iter().find(|w| !w.is_running()) {
+			self.shared.live.fetch_add(1, Ordering::SeqCst);
+			worker.start(&self.shared, &self.exit_sender);
+		}
+	}
+
+	// the number of worker threads right now
 	pub fn size(&self) -> usize {
-		self.workers.len()
+		self.shared.live.load(Ordering::SeqCst)
 	}
+
+	// Run `f` on the pool, its return value (or panic) comes back through the handle
+	// A panic in `f` goes to the handle rather than the panic hook. A job that never runs, because
+	// the pool was shut down, ends with Cancelled.
+	pub fn submit_with_result<F, T>(&mut self, f: F) -> JobHandle<T>
+	where
+		F: FnOnce() -> T + Send + 'static,
+		T: Send + 'static,
+	{
+		let (job, handle) = with_result(f);
+		if let Err(e) = self.queue(job, true) {
+			// the job was dropped with its sender, so the handle is already Cancelled
+			warn!("job dropped: {:?}", e);
+		}
+		handle
+	}
+
+	// Like submit_with_result, but a full queue fails rather than waits
+	pub fn try_submit_with_result<F, T>(&mut self, f: F) -> Result<JobHandle<T>, SubmitError>
+	where
+		F: FnOnce() -> T + Send + 'static,
+		T: Send + 'static,
+	{
+		let (job, handle) = with_result(f);
+		self.queue(job, false)?;
+		Ok(handle)
+	}
+
+	// Have panicking jobs reported to `hook`, with the id of the worker that ran the job
+	// The worker carries on with the next job either way.
+	pub fn on_panic<F>(&mut self, hook: F)
+	where
+		F: Fn(usize, &(dyn Any + Send)) + Send + Sync + 'static,
+	{
+		*self.shared.panic_hook.lock().unwrap() = Some(Arc::new(hook));
+	}
+
+	// Stop taking jobs, the workers finish everything already queued and then exit
+	pub fn shutdown(&mut self) {
+		self.shared.shutdown.store(true, Ordering::SeqCst);
+		self.shared.notify(true);
+	}
+
+	// Stop taking jobs and throw the queued ones away, jobs that are already running still finish
+	pub fn shutdown_now(&mut self) {
+		self.shared.discard.store(true, Ordering::SeqCst);
+		self.shutdown();
+	}
+
+	// Wait up to `timeout` for the workers to exit, shutting the pool down first if it wasn't
+	// If some are still going when the time is up, their ids come back as the error and they can
+	// be waited for again. That's every worker whose thread hasn't exited, busy with a job or not.
+	pub fn join(&mut self, timeout: Duration) -> Result<(), Vec<usize>> {
+		self.shutdown();
+
+		let deadline = Instant::now() + timeout;
+		while self.workers.iter().any(|w| w.is_running()) {
+			let left = deadline.saturating_duration_since(Instant::now());
+			match self.exited.recv_timeout(left) {
+				// it has returned from its loop, joining won't block
+				Ok(id) => self.workers[id].join(),
+				Err(_) => {
+					let running = self.workers.iter().filter(|w| w.is_running()).map(|w| w.id);
+					return Err(running.collect());
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+// `f` as a job that hands its result to the returned handle
+fn with_result<F, T>(f: F) -> (Runnable, JobHandle<T>)
+where
+	F: FnOnce() -> T + Send + 'static,
+	T: Send + 'static,
+{
+	let completion = Arc::new(Completion {
+		state: Mutex::new(State {
+			result: None,
+			waker: None,
+		}),
+		done: Condvar::new(),
+	});
+	let completer = Completer(Some(Arc::clone(&completion)));
+	let job = Box::new(move || {
+		let result = panic::catch_unwind(AssertUnwindSafe(f));
+		completer.complete(result);
+	});
+	(job, JobHandle { completion })
 }
 
 impl Drop for ThreadPool {
 	fn drop(&mut self) {
-		for _ in 0..self.workers.len() {
-			self.sender.send(Job::Task).unwrap(); // sending Stop to all threads in the pool
-		}
+		self.shutdown();
 
 		for worker in &mut self.workers {
-			if let Some(thread) = worker.thread.take() {
-				// take the memory, but why?
-				thread.join().unwrap(); // let's wait for worker to be defined
+			// a thread that died was replaced by a new one, which has to be waited for as well
+			loop {
+				let thread = worker.thread.lock().unwrap().take();
+				match thread {
+					Some(thread) => {
+						let _ = thread.join();
+					}
+					None => break,
+				}
 			}
 		}
 	}
 }
 
 struct Worker {
+	id: usize,
+	// set while the worker runs a job
+	busy: Arc<AtomicBool>,
 	// a uniquely owned permission to join a thread, there is no other way to join the thread for which this thread is
-	thread: Option<JoinHandle<()>>,
+	// It's swapped for the replacement's if the thread dies.
+	thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+	// the worker's deque while no thread is working on it
+	parked: Arc<Mutex<Option<Deque<Runnable>>>>,
 }
 
 impl Worker {
-	fn new(receiver: Arc<Mutex<Receiver<Job>>>) -> Worker {
-		// spawn a worker thread
-		let thread = std::thread::spawn(move || {
-			loop {
-				let job = receiver.lock().unwrap().recv();
-				match job {
-					Ok(Job::Task(f)) => f(),
-					Ok(Job::Stop) => break,
-					Err(_) => break,
+	fn new(id: usize, local: Deque<Runnable>) -> Worker {
+		Worker {
+			id,
+			busy: Arc::new(AtomicBool::new(false)),
+			thread: Arc::new(Mutex::new(None)),
+			parked: Arc::new(Mutex::new(Some(local))),
+		}
+	}
+
+	// start a thread working on the worker's deque
+	fn start(&self, shared: &Arc<Shared>, exited: &Sender<usize>) {
+		// a thread on its way out parks the deque before it says it's exiting
+		let local = self.parked.lock().unwrap().take().unwrap();
+		let context = Context {
+			id: self.id,
+			local,
+			shared: Arc::clone(shared),
+			busy: Arc::clone(&self.busy),
+			exited: exited.clone(),
+			thread: Arc::clone(&self.thread),
+			parked: Arc::clone(&self.parked),
+		};
+		// held while spawning so the thread can't be replaced before it's stored
+		let mut slot = self.thread.lock().unwrap();
+		*slot = Some(context.spawn());
+	}
+
+	// wait for a thread that has left its loop
+	fn join(&self) {
+		let thread = self.thread.lock().unwrap().take();
+		if let Some(thread) = thread {
+			let _ = thread.join();
+		}
+	}
+
+	fn is_busy(&self) -> bool {
+		self.busy.load(Ordering::SeqCst)
+	}
+
+	fn is_running(&self) -> bool {
+		self.thread.lock().unwrap().is_some()
+	}
+}
+
+// Everything a worker thread works with, handed over to a new thread if it dies
+struct Context {
+	id: usize,
+	local: Deque<Runnable>,
+	shared: Arc<Shared>,
+	busy: Arc<AtomicBool>,
+	exited: Sender<usize>, // the pool may be gone already, sends can fail
+	thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+	parked: Arc<Mutex<Option<Deque<Runnable>>>>,
+}
+
+impl Context {
+	// spawn a worker thread
+	fn spawn(self) -> JoinHandle<()> {
+		let mut builder = thread::Builder::new();
+		if let Some(name) = &self.shared.name {
+			builder = builder.name(format!("{}-{}", name, self.id));
+		}
+		builder
+			.spawn(move || {
+				let guard = Respawn(Some(self));
+				guard.0.as_ref().unwrap().run();
+			})
+			.unwrap()
+	}
+
+	fn run(&self) {
+		let shared = &self.shared;
+		let mut idle_since = None;
+		loop {
+			let job = shared.find_job(&self.local);
+			if job.is_some() {
+				idle_since = None;
+				shared.dequeued();
+				// a batch from the injector landed in this deque, a sleeping worker can steal from it
+				if !self.local.is_empty() {
+					shared.notify(false);
+				}
+			}
+			match job {
+				// queued jobs are dropped after shutdown_now
+				Some(_) if shared.discard.load(Ordering::SeqCst) => {}
+				Some(f) => {
+					self.busy.store(true, Ordering::SeqCst);
+					// the job's state is dropped with it, nothing half-updated is seen again
+					let result = panic::catch_unwind(AssertUnwindSafe(f));
+					self.busy.store(false, Ordering::SeqCst);
+					if let Err(payload) = result {
+						shared.report_panic(self.id, &*payload);
+					}
+				}
+				// jobs can't be submitted after shutdown, nothing queued means nothing left
+				None if shared.shutdown.load(Ordering::SeqCst) && shared.is_idle() => break,
+				None => {
+					let idle = idle_since.get_or_insert_with(Instant::now).elapsed();
+					// its deque is empty, nothing is left behind
+					if idle >= shared.keep_alive && shared.retire() {
+						break;
+					}
+					// a worker over the minimum wakes up again when its keep-alive is over
+					let timeout = if shared.live.load(Ordering::SeqCst) > shared.min_workers {
+						Some(shared.keep_alive.saturating_sub(idle))
+					} else {
+						None
+					};
+					shared.sleep(timeout);
 				}
 			}
+		}
+	}
+}
+
+// Replaces the worker thread if it dies anyway (say the panic hook itself panicked)
+// The new thread takes over the same deque, so the jobs in it aren't lost and size() stays true.
+// A thread that exits normally leaves its deque for the next one started in its slot.
+struct Respawn(Option<Context>);
+
+impl Drop for Respawn {
+	fn drop(&mut self) {
+		let context = match self.0.take() {
+			Some(context) => context,
+			None => return,
+		};
+		if thread::panicking() {
+			context.busy.store(false, Ordering::SeqCst);
+			let slot = Arc::clone(&context.thread);
+			// nothing panics while holding this lock, but a panic in here would abort
+			let mut thread = slot.lock().unwrap_or_else(|e| e.into_inner());
+			*thread = Some(context.spawn());
+		} else {
+			let Context {
+				id,
+				local,
+				exited,
+				parked,
+				..
+			} = context;
+			*parked.lock().unwrap() = Some(local);
+			let _ = exited.send(id);
+		}
+	}
+}
+
+// What a job submitted with submit_with_result ended with: Ok with its return value, or Err with
+// its panic payload. A job thrown away by shutdown_now ends with a `Cancelled` payload.
+pub type JobResult<T> = thread::Result<T>;
+
+// the payload of a job that was dropped without running
+#[derive(Debug)]
+pub struct Cancelled;
+
+struct State<T> {
+	result: Option<JobResult<T>>,
+	waker: Option<Waker>, // the task awaiting the handle, if it's awaited
+}
+
+struct Completion<T> {
+	state: Mutex<State<T>>,
+	done: Condvar, // for threads blocked in wait
+}
+
+impl<T> Completion<T> {
+	fn set(&self, result: JobResult<T>) {
+		let mut state = self.state.lock().unwrap();
+		state.result = Some(result);
+		let waker = state.waker.take();
+		drop(state);
+
+		self.done.notify_all();
+		if let Some(waker) = waker {
+			waker.wake();
+		}
+	}
+}
+
+// Moves into the job and fills in the result, or Cancelled if the job is dropped unrun
+struct Completer<T>(Option<Arc<Completion<T>>>);
+
+impl<T> Completer<T> {
+	fn complete(mut self, result: JobResult<T>) {
+		if let Some(completion) = self.0.take() {
+			completion.set(result);
+		}
+	}
+}
+
+impl<T> Drop for Completer<T> {
+	fn drop(&mut self) {
+		if let Some(completion) = self.0.take() {
+			completion.set(Err(Box::new(Cancelled)));
+		}
+	}
+}
+
+// The result of a job, once it has run
+pub struct JobHandle<T> {
+	completion: Arc<Completion<T>>,
+}
+
+impl<T> JobHandle<T> {
+	pub fn is_done(&self) -> bool {
+		self.completion.state.lock().unwrap().result.is_some()
+	}
+
+	// Block until the job has run
+	pub fn wait(self) -> JobResult<T> {
+		let mut state = self.completion.state.lock().unwrap();
+		loop {
+			if let Some(result) = state.result.take() {
+				return result;
+			}
+			state = self.completion.done.wait(state).unwrap();
+		}
+	}
+
+	// Block for at most `timeout`, the handle comes back if the job hasn't finished by then
+	pub fn wait_timeout(self, timeout: Duration) -> Result<JobResult<T>, JobHandle<T>> {
+		let deadline = Instant::now() + timeout;
+		let mut state = self.completion.state.lock().unwrap();
+		loop {
+			if let Some(result) = state.result.take() {
+				return Ok(result);
+			}
+			let left = deadline.saturating_duration_since(Instant::now());
+			if left == Duration::from_secs(0) {
+				drop(state);
+				return Err(self);
+			}
+			state = self.completion.done.wait_timeout(state, left).unwrap().0;
+		}
+	}
+}
+
+impl<T> Future for JobHandle<T> {
+	type Output = JobResult<T>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<JobResult<T>> {
+		let mut state = self.completion.state.lock().unwrap();
+		match state.result.take() {
+			Some(result) => Poll::Ready(result),
+			None => {
+				// only the most recent task polling is woken
+				state.waker = Some(cx.waker().clone());
+				Poll::Pending
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{sync::mpsc::sync_channel, task::Wake, thread::Thread};
+
+	fn counter() -> Arc<AtomicUsize> {
+		Arc::new(AtomicUsize::new(0))
+	}
+
+	// a job that counts itself once it has slept for `ms`
+	fn slow(n: &Arc<AtomicUsize>, ms: u64) -> impl FnOnce() + Send + 'static {
+		let n = Arc::clone(n);
+		move || {
+			thread::sleep(Duration::from_millis(ms));
+			n.fetch_add(1, Ordering::SeqCst);
+		}
+	}
+
+	// just enough of an executor to drive one future on this thread
+	struct Unpark(Thread);
+
+	impl Wake for Unpark {
+		fn wake(self: Arc<Self>) {
+			self.0.unpark();
+		}
+	}
+
+	fn block_on<F: Future>(future: F) -> F::Output {
+		let waker = Waker::from(Arc::new(Unpark(thread::current())));
+		let mut cx = TaskContext::from_waker(&waker);
+		let mut future = Box::pin(future);
+		loop {
+			match future.as_mut().poll(&mut cx) {
+				Poll::Ready(output) => return output,
+				Poll::Pending => thread::park(),
+			}
+		}
+	}
+
+	#[test]
+	fn shutdown_drains_the_queue() {
+		let mut pool = ThreadPool::new(2);
+		let n = counter();
+		for _ in 0..10 {
+			pool.submit(slow(&n, 10));
+		}
+		pool.shutdown();
+		assert_eq!(pool.try_submit(slow(&n, 0)), Err(SubmitError::ShutDown));
+		assert_eq!(pool.join(Duration::from_secs(5)), Ok(()));
+		assert_eq!(n.load(Ordering::SeqCst), 10);
+
+		// nor after joining, jobs submitted anyway are dropped
+		assert_eq!(pool.try_submit(slow(&n, 0)), Err(SubmitError::ShutDown));
+		pool.submit(slow(&n, 0));
+		assert!(pool
+			.submit_with_result(|| ())
+			.wait()
+			.unwrap_err()
+			.is::<Cancelled>());
+		assert!(pool.try_submit_with_result(|| ()).is_err());
+		assert_eq!(n.load(Ordering::SeqCst), 10);
+	}
+
+	#[test]
+	fn shutdown_now_drops_the_queue() {
+		let mut pool = ThreadPool::new(1);
+		let n = counter();
+		let (started, running) = channel();
+		let (release, wait) = channel::<()>();
+		let first = pool.submit_with_result(move || {
+			started.send(()).unwrap();
+			wait.recv().unwrap();
 		});
+		running.recv().unwrap();
+		for _ in 0..5 {
+			pool.submit(slow(&n, 0));
+		}
+		let queued = pool.submit_with_result(|| 1);
 
-		Worker {
-			thread: Some(thread),
+		pool.shutdown_now();
+		assert_eq!(pool.try_submit(|| ()), Err(SubmitError::ShutDown));
+		release.send(()).unwrap();
+		assert_eq!(pool.join(Duration::from_secs(5)), Ok(()));
+		// the running job finished, the queued ones never ran
+		assert!(first.wait().is_ok());
+		assert_eq!(n.load(Ordering::SeqCst), 0);
+		let payload = queued.wait().unwrap_err();
+		assert!(payload.is::<Cancelled>());
+	}
+
+	#[test]
+	fn join_times_out_on_a_stuck_job() {
+		let mut pool = ThreadPool::new(2);
+		let (release, wait) = channel::<()>();
+		let (started, running) = channel();
+		pool.submit(move || {
+			started.send(()).unwrap();
+			wait.recv().unwrap();
+		});
+		running.recv().unwrap();
+
+		let start = Instant::now();
+		let stuck = pool.join(Duration::from_millis(100)).unwrap_err();
+		assert!(start.elapsed() >= Duration::from_millis(100));
+		assert_eq!(stuck.len(), 1);
+
+		// the workers can be waited for again
+		release.send(()).unwrap();
+		assert_eq!(pool.join(Duration::from_secs(5)), Ok(()));
+	}
+
+	#[test]
+	fn join_reports_workers_between_jobs() {
+		let mut pool = ThreadPool::new(1);
+		let n = counter();
+		for _ in 0..1000 {
+			pool.submit(slow(&n, 1));
 		}
+		// the worker is still draining the queue, whether or not it's in a job right now
+		assert_eq!(pool.join(Duration::from_millis(20)), Err(vec![0]));
+		pool.shutdown_now();
+		assert_eq!(pool.join(Duration::from_secs(5)), Ok(()));
+	}
+
+	#[test]
+	fn panicking_jobs_are_reported() {
+		let mut pool = ThreadPool::new(1);
+		let (reports, reported) = channel();
+		let reports = Mutex::new(reports);
+		pool.on_panic(move |id, payload| {
+			let message = panic_message(payload).to_string();
+			reports.lock().unwrap().send((id, message)).unwrap();
+		});
+		pool.submit(|| panic!("job failed"));
+		assert_eq!(
+			reported.recv_timeout(Duration::from_secs(5)),
+			Ok((0, "job failed".to_string()))
+		);
+
+		// the worker goes on with the next job
+		let handle = pool.submit_with_result(|| 5);
+		assert_eq!(handle.wait().unwrap(), 5);
+		assert_eq!(pool.size(), 1);
+		assert_eq!(pool.join(Duration::from_secs(5)), Ok(()));
+	}
+
+	#[test]
+	fn dead_workers_are_respawned() {
+		let mut pool = ThreadPool::builder()
+			.min_workers(1)
+			.max_workers(1)
+			.name("respawn")
+			.build();
+		// a hook that panics takes the worker thread down with it
+		pool.on_panic(|_, _| panic!("hook failed"));
+		pool.submit(|| panic!("job failed"));
+
+		let handle = pool.submit_with_result(|| thread::current().name().map(str::to_string));
+		assert_eq!(handle.wait().unwrap(), Some("respawn-0".to_string()));
+		assert_eq!(pool.size(), 1);
+		assert_eq!(pool.join(Duration::from_secs(5)), Ok(()));
+	}
+
+	#[test]
+	fn handles_can_be_waited_for() {
+		let mut pool = ThreadPool::new(1);
+		assert_eq!(pool.submit_with_result(|| 6 * 7).wait().unwrap(), 42);
+
+		let (release, wait) = sync_channel::<()>(0);
+		let handle = pool.submit_with_result(move || wait.recv().map(|_| "done"));
+		assert!(!handle.is_done());
+		let handle = handle.wait_timeout(Duration::from_millis(50)).unwrap_err();
+		release.send(()).unwrap();
+		let result = handle.wait_timeout(Duration::from_secs(5)).ok().unwrap();
+		assert_eq!(result.unwrap(), Ok("done"));
+
+		// a panic comes back through the handle, not the hook
+		let handle = pool.submit_with_result(|| -> () { panic!("oops") });
+		let payload = handle.wait().unwrap_err();
+		assert_eq!(panic_message(&*payload), "oops");
+	}
+
+	#[test]
+	fn handles_can_be_awaited() {
+		let mut pool = ThreadPool::new(2);
+		let handle = pool.submit_with_result(|| {
+			thread::sleep(Duration::from_millis(50));
+			"slow"
+		});
+		assert_eq!(block_on(handle).unwrap(), "slow");
+
+		let handles: Vec<_> = (0..10u64)
+			.map(|i| pool.submit_with_result(move || i * i))
+			.collect();
+		let squares: Vec<u64> = handles
+			.into_iter()
+			.map(|handle| block_on(handle).unwrap())
+			.collect();
+		assert_eq!(squares, (0..10).map(|i| i * i).collect::<Vec<_>>());
+
+		let handle = pool.submit_with_result(|| -> u8 { panic!("async oops") });
+		let payload = block_on(handle).unwrap_err();
+		assert_eq!(panic_message(&*payload), "async oops");
+	}
+
+	#[test]
+	fn grows_when_jobs_back_up_and_shrinks_when_idle() {
+		let mut pool = ThreadPool::builder()
+			.min_workers(1)
+			.max_workers(4)
+			.keep_alive(Duration::from_millis(100))
+			.build();
+		assert_eq!(pool.size(), 1);
+
+		let (release, wait) = channel::<()>();
+		let wait = Arc::new(Mutex::new(wait));
+		let (started, running) = channel();
+		let handles: Vec<_> = (0..4)
+			.map(|_| {
+				let (wait, started) = (Arc::clone(&wait), started.clone());
+				pool.submit_with_result(move || {
+					started.send(()).unwrap();
+					wait.lock().unwrap().recv().unwrap();
+				})
+			})
+			.collect();
+		// all four run at once, so there are four workers
+		for _ in 0..4 {
+			running.recv_timeout(Duration::from_secs(5)).unwrap();
+		}
+		assert_eq!(pool.size(), 4);
+		for _ in 0..4 {
+			release.send(()).unwrap();
+		}
+		for handle in handles {
+			handle.wait().unwrap();
+		}
+
+		// the ones over the minimum leave after their keep-alive
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while pool.size() > 1 && Instant::now() < deadline {
+			thread::sleep(Duration::from_millis(10));
+		}
+		assert_eq!(pool.size(), 1);
+		assert_eq!(pool.submit_with_result(|| 1).wait().unwrap(), 1);
+		assert_eq!(pool.join(Duration::from_secs(5)), Ok(()));
+	}
+
+	#[test]
+	fn sleeping_workers_share_the_queue() {
+		let mut pool = ThreadPool::new(4);
+		// let every worker go to sleep first
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while pool.shared.sleeping.load(Ordering::SeqCst) < 4 {
+			assert!(Instant::now() < deadline, "the workers never went to sleep");
+			thread::sleep(Duration::from_millis(1));
+		}
+
+		let (release, wait) = channel::<()>();
+		let wait = Arc::new(Mutex::new(wait));
+		let (started, running) = channel();
+		let handles: Vec<_> = (0..4)
+			.map(|_| {
+				let (wait, started) = (Arc::clone(&wait), started.clone());
+				pool.submit_with_result(move || {
+					started.send(()).unwrap();
+					wait.lock().unwrap().recv().unwrap();
+				})
+			})
+			.collect();
+		// all four are running at the same moment, not one worker doing them in turn
+		for _ in 0..4 {
+			running.recv_timeout(Duration::from_secs(5)).unwrap();
+		}
+		for _ in 0..4 {
+			release.send(()).unwrap();
+		}
+		for handle in handles {
+			handle.wait().unwrap();
+		}
+		assert_eq!(pool.join(Duration::from_secs(5)), Ok(()));
+	}
+
+	#[test]
+	fn full_queues_fail_try_submit() {
+		let mut pool = ThreadPool::builder()
+			.min_workers(1)
+			.max_workers(1)
+			.queue_limit(2)
+			.build();
+		let (release, wait) = channel::<()>();
+		let (started, running) = channel();
+		let first = pool.submit_with_result(move || {
+			started.send(()).unwrap();
+			wait.recv().unwrap();
+		});
+		running.recv().unwrap();
+
+		let n = counter();
+		assert_eq!(pool.try_submit(slow(&n, 0)), Ok(()));
+		assert!(pool.try_submit_with_result(|| ()).is_ok());
+		assert_eq!(pool.try_submit(slow(&n, 0)), Err(SubmitError::QueueFull));
+		assert!(pool.try_submit_with_result(|| ()).is_err());
+
+		// there's room again once the worker gets to the queue
+		release.send(()).unwrap();
+		first.wait().unwrap();
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while pool.try_submit(slow(&n, 0)).is_err() {
+			assert!(Instant::now() < deadline, "the queue never drained");
+			thread::sleep(Duration::from_millis(1));
+		}
+		assert_eq!(pool.join(Duration::from_secs(5)), Ok(()));
+		assert_eq!(n.load(Ordering::SeqCst), 2);
+	}
+
+	#[test]
+	fn full_queues_block_submit() {
+		let mut pool = ThreadPool::builder()
+			.min_workers(1)
+			.max_workers(1)
+			.queue_limit(1)
+			.build();
+		let (release, wait) = channel::<()>();
+		let (started, running) = channel();
+		pool.submit(move || {
+			started.send(()).unwrap();
+			wait.recv().unwrap();
+		});
+		running.recv().unwrap();
+
+		// one running and one queued, the next submit has to wait for room
+		let n = counter();
+		pool.submit(slow(&n, 0));
+		let (submitted, done) = channel();
+		let submitter = {
+			let n = Arc::clone(&n);
+			thread::spawn(move || {
+				pool.submit(slow(&n, 0));
+				submitted.send(()).unwrap();
+				pool
+			})
+		};
+		assert!(done.recv_timeout(Duration::from_millis(50)).is_err());
+		assert_eq!(n.load(Ordering::SeqCst), 0);
+
+		release.send(()).unwrap();
+		done.recv_timeout(Duration::from_secs(5)).unwrap();
+		let mut pool = submitter.join().unwrap();
+		assert_eq!(pool.join(Duration::from_secs(5)), Ok(()));
+		assert_eq!(n.load(Ordering::SeqCst), 2);
+	}
+
+	#[test]
+	#[should_panic(expected = "at least one worker")]
+	fn pools_need_a_worker() {
+		ThreadPool::new(0);
+	}
+
+	#[test]
+	#[should_panic(expected = "queue limit")]
+	fn queue_limits_need_room() {
+		ThreadPool::builder().queue_limit(0).build();
+	}
+
+	#[test]
+	fn workers_are_named() {
+		let mut pool = ThreadPool::builder()
+			.min_workers(2)
+			.max_workers(2)
+			.name("named")
+			.build();
+		let (release, wait) = channel::<()>();
+		let wait = Arc::new(Mutex::new(wait));
+		let (started, running) = channel();
+		// both running at once, so each worker has one
+		let handles: Vec<_> = (0..2)
+			.map(|_| {
+				let (wait, started) = (Arc::clone(&wait), started.clone());
+				pool.submit_with_result(move || {
+					started.send(()).unwrap();
+					wait.lock().unwrap().recv().unwrap();
+					thread::current().name().map(str::to_string)
+				})
+			})
+			.collect();
+		for _ in 0..2 {
+			running.recv_timeout(Duration::from_secs(5)).unwrap();
+		}
+		for _ in 0..2 {
+			release.send(()).unwrap();
+		}
+		let mut names: Vec<_> = handles.into_iter().map(|h| h.wait().unwrap()).collect();
+		names.sort();
+		assert_eq!(
+			names,
+			vec![Some("named-0".to_string()), Some("named-1".to_string())]
+		);
+
+		// without a name they're left unnamed
+		let mut pool = ThreadPool::new(1);
+		let name = pool.submit_with_result(|| thread::current().name().map(str::to_string));
+		assert_eq!(name.wait().unwrap(), None);
 	}
 }
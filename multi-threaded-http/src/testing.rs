@@ -0,0 +1,264 @@
+// What the end-to-end tests share: a server running in the background (one for the whole test
+// run) and a scripted websocket client to talk to it
+// The client masks what it sends and checks every frame it gets back, so a test only has to say
+// what it sends and what it expects.
+
+use crate::deflate::{Deflater, Params};
+use crate::frame::{self, Decoder, Frame, Message, Opcode};
+use crate::{run, upgrade_checks};
+use mio::net::TcpListener;
+use parser_combinators::stream::ByteStream;
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::OnceLock,
+    thread,
+    time::Duration,
+};
+
+pub const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+fn server() -> SocketAddr {
+    static ADDR: OnceLock<SocketAddr> = OnceLock::new();
+    *ADDR.get_or_init(|| {
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || run(listener, upgrade_checks(addr, &[])));
+        addr
+    })
+}
+
+pub struct Client {
+    pub socket: TcpStream,
+    pub recv: ByteStream,
+    pub decoder: Decoder,
+    // compresses what's sent once permessage-deflate has been agreed on
+    pub deflater: Option<Deflater>,
+}
+
+impl Client {
+    // an upgraded connection to /echo
+    pub fn connect() -> Client {
+        let (client, head) = Client::handshake("");
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+        client
+    }
+
+    // A connection to /echo offering permessage-deflate, with the server's answer if it took it
+    // The client plays its part of what was agreed on, the server's parameters mirrored.
+    pub fn compressed(offer: &str) -> (Client, Option<String>) {
+        let (mut client, head) =
+            Client::handshake(&format!("Sec-WebSocket-Extensions: {}\r\n", offer));
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+        let answer = header(&head, "Sec-WebSocket-Extensions");
+        if let Some(params) = answer.as_deref().and_then(Params::parse) {
+            let mirrored = Params {
+                server_no_context_takeover: params.client_no_context_takeover,
+                client_no_context_takeover: params.server_no_context_takeover,
+                server_max_window_bits: 15,
+            };
+            client.deflater = Some(mirrored.deflater());
+            client.decoder.set_inflater(mirrored.inflater());
+        }
+        (client, answer)
+    }
+
+    // the opening handshake with more request `headers`, and the head of the server's answer
+    pub fn handshake(headers: &str) -> (Client, String) {
+        Client::open(&format!(
+            "GET /echo HTTP/1.1\r\n\
+             Host: 127.0.0.1\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             {}\r\n",
+            headers
+        ))
+    }
+
+    // send `request` as it is, and read the head of the answer
+    pub fn open(request: &str) -> (Client, String) {
+        Client::open_at(server(), request)
+    }
+
+    // the same with a server of the test's own
+    pub fn open_at(addr: SocketAddr, request: &str) -> (Client, String) {
+        let mut socket = TcpStream::connect(addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket.write_all(request.as_bytes()).unwrap();
+
+        let mut client = Client {
+            socket,
+            recv: ByteStream::with_capacity(1024),
+            decoder: Decoder::client(),
+            deflater: None,
+        };
+        let end = loop {
+            let buf = client.recv.as_ref();
+            if let Some(at) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break at + 4;
+            }
+            assert!(client.fill(), "connection closed during the handshake");
+        };
+        let head = String::from_utf8_lossy(&client.recv.as_ref()[..end]).into_owned();
+        frame::consume(&mut client.recv, end);
+        (client, head)
+    }
+
+    // read whatever has arrived, false once the server has closed the connection
+    pub fn fill(&mut self) -> bool {
+        let mut buffer = [0_u8; 64 * 1024];
+        match self.socket.read(&mut buffer) {
+            Ok(0) => false,
+            Ok(n) => {
+                self.recv.put(&buffer[..n]);
+                true
+            }
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => false,
+            Err(e) => panic!("reading from the server: {}", e),
+        }
+    }
+
+    pub fn send_raw(&mut self, bytes: &[u8]) {
+        // the server may already have closed the connection over an earlier frame
+        let _ = self.socket.write_all(bytes);
+    }
+
+    pub fn send(&mut self, frame: Frame) {
+        let mut out = ByteStream::with_capacity(frame.payload.len() + 14);
+        frame.encode(&mut out, Some(MASK));
+        self.send_raw(out.as_ref());
+    }
+
+    pub fn send_fragment(&mut self, opcode: Opcode, payload: &[u8], fin: bool) {
+        self.send(Frame {
+            fin,
+            rsv: 0,
+            opcode,
+            payload: payload.to_vec(),
+        });
+    }
+
+    pub fn send_text(&mut self, text: &[u8]) {
+        self.send_fragment(Opcode::Text, text, true);
+    }
+
+    // `text` compressed with the negotiated extension, sent in `fragments` frames
+    pub fn send_compressed(&mut self, text: &[u8], fragments: usize) {
+        let payload = self
+            .deflater
+            .as_mut()
+            .expect("permessage-deflate wasn't agreed on")
+            .deflate(text)
+            .expect("too short to compress");
+        self.send_split(&payload, fragments);
+    }
+
+    // a compressed payload as a text message in `fragments` frames, RSV1 set on the first one
+    pub fn send_split(&mut self, payload: &[u8], fragments: usize) {
+        let size = payload.len().div_ceil(fragments);
+        let mut chunks: Vec<&[u8]> = payload.chunks(size.max(1)).collect();
+        chunks.resize(fragments.max(chunks.len()), &[]);
+        let last = chunks.len() - 1;
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            self.send(Frame {
+                fin: i == last,
+                rsv: if i == 0 { frame::RSV1 } else { 0 },
+                opcode: if i == 0 {
+                    Opcode::Text
+                } else {
+                    Opcode::Continuation
+                },
+                payload: chunk.to_vec(),
+            });
+        }
+    }
+
+    // the reserved bits and payload length of the next frame, without taking it off the stream
+    pub fn peek(&mut self) -> (u8, usize) {
+        loop {
+            let mut copy = ByteStream::with_capacity(self.recv.len());
+            copy.put(self.recv.as_ref());
+            match frame::decode(&mut copy, false, frame::MAX_MESSAGE_SIZE) {
+                Ok(Some(frame)) => return (frame.rsv, frame.payload.len()),
+                Ok(None) => assert!(self.fill(), "connection closed, expected a frame"),
+                Err(e) => panic!("invalid frame from the server: {:?}", e),
+            }
+        }
+    }
+
+    pub fn recv(&mut self) -> Message {
+        loop {
+            match self.decoder.next(&mut self.recv) {
+                Ok(Some(message)) => return message,
+                Ok(None) => assert!(self.fill(), "connection closed, expected a message"),
+                Err(e) => panic!("invalid frame from the server: {:?}", e),
+            }
+        }
+    }
+
+    // the server sends a close frame with `code`, then ends the connection
+    pub fn expect_close(&mut self, code: u16) {
+        match self.recv() {
+            Message::Close(Some((got, _))) => assert_eq!(got, code, "close code"),
+            message => panic!("expected a close with {}, got {:?}", code, message),
+        }
+        while self.fill() {}
+        assert!(self.recv.as_ref().is_empty(), "data after the close frame");
+    }
+
+    pub fn expect_echo(&mut self, text: &[u8]) {
+        assert_eq!(
+            self.recv(),
+            Message::Text(String::from_utf8(text.to_vec()).unwrap())
+        );
+    }
+
+    // returns once the server has handled everything sent before (a ping's answered in order)
+    pub fn sync(&mut self) {
+        self.send_fragment(Opcode::Ping, b"sync", true);
+        assert_eq!(self.recv(), Message::Pong(b"sync".to_vec()));
+    }
+}
+
+// a request for `target` ("GET /echo") with a list of header lines
+pub fn request(target: &str, headers: &[&str]) -> String {
+    let mut request = format!("{} HTTP/1.1\r\nHost: 127.0.0.1\r\n", target);
+    for header in headers {
+        request.push_str(header);
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    request
+}
+
+// the headers of a valid upgrade, with the ones named in `without` left out
+pub fn valid_upgrade<'a>(without: &[&str], with: &[&'a str]) -> Vec<&'a str> {
+    let valid = [
+        "Connection: Upgrade",
+        "Upgrade: websocket",
+        "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==",
+        "Sec-WebSocket-Version: 13",
+    ];
+    valid
+        .iter()
+        .filter(|h| !without.iter().any(|name| h.starts_with(name)))
+        .chain(with)
+        .copied()
+        .collect()
+}
+
+// the value of a header in a response head, None if it isn't there
+pub fn header(head: &str, name: &str) -> Option<String> {
+    head.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.eq_ignore_ascii_case(name) {
+            Some(value.trim().to_string())
+        } else {
+            None
+        }
+    })
+}
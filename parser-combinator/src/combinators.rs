@@ -0,0 +1,154 @@
+// Parser combinators over bytes
+// A parser takes the input and either succeeds with the rest of the input and what it parsed, or
+// fails with the input it couldn't make sense of. Bigger parsers are built by combining small ones.
+
+pub type ParseResult<'a, Output> = Result<(&'a [u8], Output), &'a [u8]>;
+
+pub trait Parser<'a, Output> {
+    fn parse(&self, input: &'a [u8]) -> ParseResult<'a, Output>;
+}
+
+// any function with the right signature is a parser
+impl<'a, F, Output> Parser<'a, Output> for F
+where
+    F: Fn(&'a [u8]) -> ParseResult<'a, Output>,
+{
+    fn parse(&self, input: &'a [u8]) -> ParseResult<'a, Output> {
+        self(input)
+    }
+}
+
+// exactly the bytes in `expected`
+pub fn literal<'a>(expected: &'static [u8]) -> impl Parser<'a, ()> {
+    move |input: &'a [u8]| match input.strip_prefix(expected) {
+        Some(rest) => Ok((rest, ())),
+        None => Err(input),
+    }
+}
+
+// the longest run of bytes matching `predicate`, possibly empty
+pub fn take_while<'a, P>(predicate: P) -> impl Parser<'a, &'a [u8]>
+where
+    P: Fn(u8) -> bool,
+{
+    move |input: &'a [u8]| {
+        let end = input
+            .iter()
+            .position(|b| !predicate(*b))
+            .unwrap_or(input.len());
+        Ok((&input[end..], &input[..end]))
+    }
+}
+
+// like take_while, but at least one byte has to match
+pub fn take_while1<'a, P>(predicate: P) -> impl Parser<'a, &'a [u8]>
+where
+    P: Fn(u8) -> bool,
+{
+    let parser = take_while(predicate);
+    move |input: &'a [u8]| match parser.parse(input) {
+        Ok((_, [])) => Err(input),
+        result => result,
+    }
+}
+
+// one parser after the other, keeping both results
+pub fn pair<'a, P1, P2, R1, R2>(parser1: P1, parser2: P2) -> impl Parser<'a, (R1, R2)>
+where
+    P1: Parser<'a, R1>,
+    P2: Parser<'a, R2>,
+{
+    move |input| {
+        parser1.parse(input).and_then(|(next_input, result1)| {
+            parser2
+                .parse(next_input)
+                .map(|(last_input, result2)| (last_input, (result1, result2)))
+        })
+    }
+}
+
+pub fn map<'a, P, F, A, B>(parser: P, map_fn: F) -> impl Parser<'a, B>
+where
+    P: Parser<'a, A>,
+    F: Fn(A) -> B,
+{
+    move |input| {
+        parser
+            .parse(input)
+            .map(|(next_input, result)| (next_input, map_fn(result)))
+    }
+}
+
+// both parsers, keeping what the first one parsed
+pub fn left<'a, P1, P2, R1, R2>(parser1: P1, parser2: P2) -> impl Parser<'a, R1>
+where
+    P1: Parser<'a, R1>,
+    P2: Parser<'a, R2>,
+{
+    map(pair(parser1, parser2), |(left, _right)| left)
+}
+
+// both parsers, keeping what the second one parsed
+pub fn right<'a, P1, P2, R1, R2>(parser1: P1, parser2: P2) -> impl Parser<'a, R2>
+where
+    P1: Parser<'a, R1>,
+    P2: Parser<'a, R2>,
+{
+    map(pair(parser1, parser2), |(_left, right)| right)
+}
+
+// the parser as many times as it succeeds, possibly none
+pub fn zero_or_more<'a, P, A>(parser: P) -> impl Parser<'a, Vec<A>>
+where
+    P: Parser<'a, A>,
+{
+    move |mut input| {
+        let mut result = Vec::new();
+        while let Ok((next_input, item)) = parser.parse(input) {
+            input = next_input;
+            result.push(item);
+        }
+        Ok((input, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_parser() {
+        let parse_joe = literal(b"Hello Joe!");
+        assert_eq!(Ok((&b""[..], ())), parse_joe.parse(b"Hello Joe!"));
+        assert_eq!(
+            Ok((&b" Hello Robert!"[..], ())),
+            parse_joe.parse(b"Hello Joe! Hello Robert!")
+        );
+        assert_eq!(Err(&b"Hello Mike!"[..]), parse_joe.parse(b"Hello Mike!"));
+    }
+
+    #[test]
+    fn take_while_parsers() {
+        let digits = take_while(|b| b.is_ascii_digit());
+        assert_eq!(Ok((&b"abc"[..], &b"123"[..])), digits.parse(b"123abc"));
+        assert_eq!(Ok((&b"abc"[..], &b""[..])), digits.parse(b"abc"));
+        let digits = take_while1(|b| b.is_ascii_digit());
+        assert_eq!(Err(&b"abc"[..]), digits.parse(b"abc"));
+    }
+
+    #[test]
+    fn pair_combinator() {
+        let tag_opener = right(literal(b"<"), take_while1(|b| b.is_ascii_alphabetic()));
+        assert_eq!(Ok((&b"/>"[..], &b"my"[..])), tag_opener.parse(b"<my/>"));
+        assert_eq!(Err(&b"oops"[..]), tag_opener.parse(b"oops"));
+        assert_eq!(Err(&b"!oops"[..]), tag_opener.parse(b"<!oops"));
+    }
+
+    #[test]
+    fn zero_or_more_combinator() {
+        let parser = zero_or_more(literal(b"ha"));
+        assert_eq!(Ok((&b""[..], vec![(), (), ()])), parser.parse(b"hahaha"));
+        assert_eq!(Ok((&b"ahah"[..], vec![])), parser.parse(b"ahah"));
+        assert_eq!(Ok((&b""[..], vec![])), parser.parse(b""));
+    }
+}
@@ -0,0 +1,428 @@
+// HTTP/1.1 requests and responses
+// `parse_http_request` takes a request off the front of a ByteStream once all of it has arrived,
+// so it can simply be called again whenever more bytes come in. Request bodies are read by
+// Content-Length, chunked ones aren't supported.
+// Headers are kept in a HeaderMap, which looks them up by name regardless of case.
+
+use crate::combinators::{
+    left, literal, map, pair, right, take_while, take_while1, zero_or_more, Parser,
+};
+use crate::stream::ByteStream;
+use std::{collections::HashMap, iter::FromIterator};
+
+// a head that still hasn't ended after this many bytes isn't going to
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+// bodies are buffered whole, a larger Content-Length is refused before any of it is read
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+// Header fields by name, names compare case-insensitively (RFC 7230 section 3.2)
+// A field may be repeated, its values are kept in the order they came in, and the fields are
+// written out in the order they were first added.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeaderMap {
+    fields: Vec<(String, Vec<String>)>, // the name as first given, and every value
+    index: HashMap<String, usize>,      // lowercase name to its place in `fields`
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap::default()
+    }
+
+    fn values(&self, name: &str) -> Option<&Vec<String>> {
+        let at = self.index.get(&name.to_ascii_lowercase())?;
+        Some(&self.fields[*at].1)
+    }
+
+    // the first value of the field
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values(name).map(|values| values[0].as_str())
+    }
+
+    // every value of the field, none if it isn't there
+    pub fn get_all(&self, name: &str) -> &[String] {
+        self.values(name).map_or(&[], |values| values.as_slice())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.values(name).is_some()
+    }
+
+    // add a value after the ones the field already has
+    pub fn append(&mut self, name: &str, value: &str) {
+        match self.index.get(&name.to_ascii_lowercase()) {
+            Some(at) => self.fields[*at].1.push(value.to_string()),
+            None => {
+                self.index
+                    .insert(name.to_ascii_lowercase(), self.fields.len());
+                self.fields
+                    .push((name.to_string(), vec![value.to_string()]));
+            }
+        }
+    }
+
+    // set the field to just this value
+    pub fn insert(&mut self, name: &str, value: &str) {
+        match self.index.get(&name.to_ascii_lowercase()) {
+            Some(at) => self.fields[*at].1 = vec![value.to_string()],
+            None => self.append(name, value),
+        }
+    }
+
+    // The comma separated elements of every value of the field, like the "keep-alive" and
+    // "Upgrade" of "Connection: keep-alive, Upgrade" (RFC 7230 section 7)
+    pub fn tokens<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> + 'a {
+        self.get_all(name)
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+    }
+
+    // whether `token` is one of the field's elements, ignoring case
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.tokens(name).any(|t| t.eq_ignore_ascii_case(token))
+    }
+
+    // every name and value, a repeated field once for each of its values
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().flat_map(|(name, values)| {
+            values
+                .iter()
+                .map(move |value| (name.as_str(), value.as_str()))
+        })
+    }
+
+    // the number of distinct fields
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+impl FromIterator<Header> for HeaderMap {
+    fn from_iter<I: IntoIterator<Item = Header>>(headers: I) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for header in headers {
+            map.append(&header.name, &header.value);
+        }
+        map
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub protocol: String,
+    pub headers: HeaderMap,
+    pub content: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub protocol: String,
+    pub code: u16,
+    pub message: String,
+    pub headers: HeaderMap,
+    pub content: Vec<u8>,
+}
+
+// The bytes at the front of the stream aren't a request, waiting for more won't help
+#[derive(Debug, PartialEq)]
+pub struct Invalid(pub &'static str);
+
+// what methods and header names are made of (RFC 7230 section 3.2.6)
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+// METHOD SP target SP HTTP/version CRLF
+fn request_line<'a>() -> impl Parser<'a, (String, String, String)> {
+    let method = left(take_while1(is_token), literal(b" "));
+    let target = left(
+        take_while1(|b| b != b' ' && !b.is_ascii_control()),
+        literal(b" "),
+    );
+    let version = left(
+        right(
+            literal(b"HTTP/"),
+            take_while1(|b| b.is_ascii_digit() || b == b'.'),
+        ),
+        literal(b"\r\n"),
+    );
+    map(
+        pair(pair(method, target), version),
+        |((method, target), version)| {
+            (
+                text(method),
+                text(target),
+                format!("HTTP/{}", text(version)),
+            )
+        },
+    )
+}
+
+// name ":" value CRLF, with optional whitespace around the value
+fn header<'a>() -> impl Parser<'a, Header> {
+    let name = left(take_while1(is_token), literal(b":"));
+    let value = left(
+        right(
+            take_while(|b| b == b' ' || b == b'\t'),
+            take_while(|b| b != b'\r' && b != b'\n'),
+        ),
+        literal(b"\r\n"),
+    );
+    map(pair(name, value), |(name, value)| Header {
+        name: text(name),
+        value: text(value).trim_end().to_string(),
+    })
+}
+
+// the request line and headers, up to the empty line ending them
+fn head<'a>() -> impl Parser<'a, ((String, String, String), HeaderMap)> {
+    left(
+        pair(
+            request_line(),
+            map(zero_or_more(header()), |headers| {
+                headers.into_iter().collect()
+            }),
+        ),
+        literal(b"\r\n"),
+    )
+}
+
+// The request at the start of `buf` and the number of bytes it takes up, None until all of it is there
+pub fn parse_request(buf: &[u8]) -> Result<Option<(Request, usize)>, Invalid> {
+    // the head is only parsed once all of it has arrived
+    let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(at) => at + 4,
+        None if buf.len() > MAX_HEAD_SIZE => return Err(Invalid("request head too large")),
+        None => return Ok(None),
+    };
+    let ((method, path, protocol), headers) = match head().parse(&buf[..end]) {
+        Ok(([], head)) => head,
+        _ => return Err(Invalid("malformed request head")),
+    };
+
+    if headers.contains("Transfer-Encoding") {
+        return Err(Invalid("chunked request bodies aren't supported"));
+    }
+    let length = match headers.get("Content-Length") {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| Invalid("invalid Content-Length"))?,
+        None => 0,
+    };
+    let total = end
+        .checked_add(length)
+        .ok_or(Invalid("Content-Length too large"))?;
+    if length > MAX_BODY_SIZE {
+        return Err(Invalid("request body too large"));
+    }
+    if buf.len() < total {
+        return Ok(None);
+    }
+
+    let request = Request {
+        method,
+        path,
+        protocol,
+        headers,
+        content: buf[end..total].to_vec(),
+    };
+    Ok(Some((request, total)))
+}
+
+// Take the next request off the stream, None if it hasn't all arrived yet (or isn't a request)
+// The bytes it came from are skipped, `pull` drops them.
+pub fn parse_http_request(stream: &mut ByteStream) -> Option<Request> {
+    match parse_request(stream.as_ref()) {
+        Ok(Some((request, len))) => {
+            stream.skip(len);
+            Some(request)
+        }
+        _ => None,
+    }
+}
+
+// The response as it goes on the wire, its content has to be text
+pub fn as_string(response: Response) -> String {
+    let mut out = format!(
+        "{} {} {}\r\n",
+        response.protocol, response.code, response.message
+    );
+    for (name, value) in response.headers.iter() {
+        out += &format!("{}: {}\r\n", name, value);
+    }
+    out += "\r\n";
+    out += &String::from_utf8_lossy(&response.content);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GET: &[u8] = b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nAccept:  */* \r\n\r\n";
+
+    fn header(name: &str, value: &str) -> Header {
+        Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn get_request() {
+        let (request, len) = parse_request(GET).unwrap().unwrap();
+        assert_eq!(len, GET.len());
+        assert_eq!(
+            request,
+            Request {
+                method: "GET".to_string(),
+                path: "/index.html".to_string(),
+                protocol: "HTTP/1.1".to_string(),
+                headers: vec![header("Host", "localhost"), header("Accept", "*/*")]
+                    .into_iter()
+                    .collect(),
+                content: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn waits_for_the_whole_request() {
+        for n in 0..GET.len() {
+            assert_eq!(parse_request(&GET[..n]), Ok(None));
+        }
+        let post = b"POST /form HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello";
+        assert_eq!(parse_request(&post[..post.len() - 1]), Ok(None));
+        let (request, _) = parse_request(post).unwrap().unwrap();
+        assert_eq!(request.content, b"hello");
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let mut stream = ByteStream::with_capacity(256);
+        stream.put(GET);
+        stream.put(b"GET /second HTTP/1.1\r\n\r\nGET /th");
+        assert_eq!(parse_http_request(&mut stream).unwrap().path, "/index.html");
+        assert_eq!(parse_http_request(&mut stream).unwrap().path, "/second");
+        assert_eq!(parse_http_request(&mut stream), None);
+        stream.pull();
+        stream.put(b"ird HTTP/1.1\r\n\r\n");
+        assert_eq!(parse_http_request(&mut stream).unwrap().path, "/third");
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let invalid: [&[u8]; 4] = [
+            b"GET /index.html\r\n\r\n",
+            b"GET /index.html HTTP/1.1\r\nno colon\r\n\r\n",
+            b"GET /index.html HTTP/1.1\r\nContent-Length: ten\r\n\r\n",
+            b"GET /index.html HTTP/1.1\r\nBad Name: x\r\n\r\n",
+        ];
+        for request in invalid.iter() {
+            assert!(parse_request(request).is_err());
+        }
+    }
+
+    #[test]
+    fn oversized_bodies() {
+        // a length that can't even be added to the head's
+        let request = format!(
+            "POST /form HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            usize::MAX
+        );
+        assert_eq!(
+            parse_request(request.as_bytes()),
+            Err(Invalid("Content-Length too large"))
+        );
+
+        // refused as soon as the head is in, however little of the body has arrived
+        let request = format!(
+            "POST /form HTTP/1.1\r\nContent-Length: {}\r\n\r\nhello",
+            MAX_BODY_SIZE + 1
+        );
+        assert_eq!(
+            parse_request(request.as_bytes()),
+            Err(Invalid("request body too large"))
+        );
+
+        let mut request = format!(
+            "POST /form HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE
+        )
+        .into_bytes();
+        request.resize(request.len() + MAX_BODY_SIZE, b'*');
+        let (parsed, len) = parse_request(&request).unwrap().unwrap();
+        assert_eq!(parsed.content.len(), MAX_BODY_SIZE);
+        assert_eq!(len, request.len());
+    }
+
+    #[test]
+    fn header_map() {
+        let request = b"GET / HTTP/1.1\r\nconnection: keep-alive\r\nAccept: text/html\r\n\
+                        CONNECTION: Upgrade,, h2c\r\n\r\n";
+        let (request, _) = parse_request(request).unwrap().unwrap();
+        let headers = &request.headers;
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers.get("Connection"), Some("keep-alive"));
+        assert_eq!(
+            headers.get_all("connection"),
+            ["keep-alive", "Upgrade,, h2c"]
+        );
+        assert_eq!(
+            headers.tokens("Connection").collect::<Vec<_>>(),
+            ["keep-alive", "Upgrade", "h2c"]
+        );
+        assert!(headers.has_token("connection", "upgrade"));
+        assert!(!headers.has_token("Accept", "text"));
+        assert_eq!(headers.get("Upgrade"), None);
+        assert!(headers.get_all("Upgrade").is_empty());
+
+        let mut headers = headers.clone();
+        headers.insert("CONNECTION", "close");
+        headers.append("Set-Cookie", "a=1");
+        headers.append("set-cookie", "b=2");
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [
+                ("connection", "close"),
+                ("Accept", "text/html"),
+                ("Set-Cookie", "a=1"),
+                ("Set-Cookie", "b=2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn response_string() {
+        let response = Response {
+            protocol: "HTTP/1.1".to_string(),
+            code: 200,
+            message: "OK".to_string(),
+            headers: vec![header("Content-Length", "5")].into_iter().collect(),
+            content: b"hello".to_vec(),
+        };
+        assert_eq!(
+            as_string(response),
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"
+        );
+    }
+}
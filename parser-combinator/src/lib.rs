@@ -1,13 +1,19 @@
+// Parser combinators (combinators.rs), and an incremental HTTP/1.1 request parser built on them
+pub mod combinators;
+pub mod http;
+pub mod stream;
+
 // XML parser
 
+// the XML parser isn't finished, these are its first pieces
 #[derive(Clone, Debug, PartialEq, Eq)]
-struct Element {
-    name: String,                      // identifier at the start of each tag
-    attributes: Vec<(String, String)>, // (identifier, value) for the attributes
-    children: Vec<Element>,            // list of child elements that look exactly the same
+pub struct Element {
+    pub name: String,                      // identifier at the start of each tag
+    pub attributes: Vec<(String, String)>, // (identifier, value) for the attributes
+    pub children: Vec<Element>,            // list of child elements that look exactly the same
 }
 
-fn the_letter_a(input: &str) -> Result<(&str, ()), &str> {
+pub fn the_letter_a(input: &str) -> Result<(&str, ()), &str> {
     match input.chars().next() {
         Some('a') => Ok((&input['a'.len_utf8()..], ())),
         _ => Err(input),
@@ -16,8 +22,17 @@ fn the_letter_a(input: &str) -> Result<(&str, ()), &str> {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn the_letter_a_parses_one_a() {
+        assert_eq!(the_letter_a("abc"), Ok(("bc", ())));
+        assert_eq!(the_letter_a("bca"), Err("bca"));
+        assert_eq!(the_letter_a(""), Err(""));
+    }
 }
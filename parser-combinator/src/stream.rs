@@ -0,0 +1,71 @@
+// A growable byte buffer for data arriving in pieces
+// Bytes are put at the end as they come in, and skipped from the front once they've been parsed.
+// Skipped bytes are only dropped on `pull`, so a parser can look at the same data more than once.
+
+pub struct ByteStream {
+    buf: Vec<u8>,
+    pos: usize, // where the unread bytes start
+}
+
+impl ByteStream {
+    pub fn with_capacity(capacity: usize) -> ByteStream {
+        ByteStream {
+            buf: Vec::with_capacity(capacity),
+            pos: 0,
+        }
+    }
+
+    pub fn put(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    // mark the first `n` unread bytes as read
+    pub fn skip(&mut self, n: usize) {
+        self.pos = (self.pos + n).min(self.buf.len());
+    }
+
+    // drop the bytes that have been read
+    pub fn pull(&mut self) {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.pos = 0;
+    }
+
+    // the number of unread bytes
+    pub fn len(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// the unread bytes
+impl AsRef<[u8]> for ByteStream {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_and_pull() {
+        let mut stream = ByteStream::with_capacity(8);
+        stream.put(b"hello");
+        stream.put(b" world");
+        stream.skip(6);
+        assert_eq!(stream.as_ref(), b"world");
+        stream.pull();
+        assert_eq!(stream.as_ref(), b"world");
+        stream.skip(10);
+        assert!(stream.is_empty());
+    }
+}